@@ -0,0 +1,152 @@
+//! Benchmarks over a large synthetic `perf script` dump.
+//!
+//! Run with `cargo bench`. This is a plain timing harness: after a
+//! few warm-up runs, each benchmark is timed `RUNS` times, and the
+//! median is reported along with the fastest and slowest run, so a
+//! regression in parsing, matching, or graph construction can be told
+//! apart from noise.
+
+extern crate perf_focus;
+extern crate regex;
+
+use perf_focus::demangle::{demangle, with_demangled};
+use perf_focus::fixture::synthetic_dump;
+use perf_focus::graph::CallGraph;
+use perf_focus::matcher::{parse_matcher, SearchDirection};
+use perf_focus::trace::each_trace_from;
+use perf_focus::AddFrames;
+use regex::Regex;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+const SAMPLES: usize = 20_000;
+const WARM_UP: usize = 3;
+const RUNS: usize = 15;
+const DEEP_FRAMES: usize = 1000;
+const DEEP_SAMPLES: usize = 100;
+const MANGLED: &[&str] = &[
+    "_ZN4core3ptr13drop_in_place17h0123456789abcdefE",
+    "_ZN5rustc2ty4fold9fold_with17hdeadbeefdeadbeefE",
+    "_ZN66_$LT$alloc..vec..Vec$LT$T$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop\
+     17h2222222222222222E",
+];
+
+fn main() {
+    let dump = synthetic_dump(SAMPLES);
+
+    let mut stacks = vec![];
+    each_trace_from(&mut Cursor::new(dump.as_bytes()), |args| {
+        stacks.push(args.stack);
+    }).unwrap();
+
+    bench("parse trace", SAMPLES, || {
+        let mut count = 0;
+        each_trace_from(&mut Cursor::new(dump.as_bytes()), |_| count += 1).unwrap();
+        assert_eq!(count, SAMPLES);
+    });
+
+    let matcher = parse_matcher("{^a::}..{^b::}").unwrap();
+    bench("match {^a::}..{^b::}", SAMPLES, || {
+        let matches = stacks
+            .iter()
+            .filter(|stack| matcher.search_trace(stack).is_some())
+            .count();
+        assert!(matches > 0);
+    });
+
+    // What `--leaf` does, against the same question put to the matcher.
+    let leaf = Regex::new("^c::").unwrap();
+    let leaf_matches = stacks
+        .iter()
+        .filter(|stack| stack.last().is_some_and(|frame| leaf.is_match(frame)))
+        .count();
+    bench("leaf regex ^c::", SAMPLES, || {
+        let matches = stacks
+            .iter()
+            .filter(|stack| stack.last().is_some_and(|frame| leaf.is_match(frame)))
+            .count();
+        assert_eq!(matches, leaf_matches);
+    });
+
+    let matcher = parse_matcher("..{^c::},!.").unwrap();
+    bench("match ..{^c::},!.", SAMPLES, || {
+        let matches = stacks
+            .iter()
+            .filter(|stack| matcher.search_trace(stack).is_some())
+            .count();
+        assert_eq!(matches, leaf_matches);
+    });
+
+    bench("graph + set_total", SAMPLES, || {
+        let mut graph = CallGraph::new();
+        for stack in &stacks {
+            graph.add_frames(stack.iter().cloned());
+        }
+        graph.set_total(stacks.len(), 22);
+    });
+
+    // Every frame of every sample is one of a handful of mangled
+    // symbols, as when perf could not demangle a hot crate.
+    let mangled: Vec<&str> = (0..SAMPLES * 10).map(|i| MANGLED[i % MANGLED.len()]).collect();
+    bench("demangle every frame", SAMPLES, || {
+        let found = mangled.iter().filter(|s| demangle(s).is_some()).count();
+        assert_eq!(found, mangled.len());
+    });
+    bench("demangle, memoized", SAMPLES, || {
+        let found = mangled.iter().filter(|s| with_demangled(s, |name| name.is_some())).count();
+        assert_eq!(found, mangled.len());
+    });
+
+    // A deep stack where `a`, `b` and `c` only turn up at the leaf end.
+    let mut deep: Vec<String> = (0..DEEP_FRAMES).map(|i| format!("f{}", i)).collect();
+    deep.extend(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+
+    let matcher = parse_matcher("..{^a$}..{^b$}..{^c$}").unwrap();
+    bench("deep ..{a}..{b}..{c}", DEEP_SAMPLES, || {
+        for _ in 0..DEEP_SAMPLES {
+            assert!(matcher.search_trace(&deep).is_some());
+        }
+    });
+
+    // With an alternation absorbing the cuts, this one is quadratic,
+    // which is what `--max-search-steps` guards against.
+    let matcher = parse_matcher("..(({^f}..{^b$}..{^x$})/{^y$})..{^c$}").unwrap();
+    bench("deep, step limit 100k", DEEP_SAMPLES, || {
+        for _ in 0..DEEP_SAMPLES {
+            let result = matcher.search_trace_limited(&deep, SearchDirection::RootFirst, 100_000);
+            assert!(result.is_err());
+        }
+    });
+}
+
+fn bench<F>(name: &str, samples: usize, mut f: F)
+where
+    F: FnMut(),
+{
+    for _ in 0..WARM_UP {
+        f();
+    }
+
+    let mut times: Vec<f64> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            millis(start.elapsed())
+        })
+        .collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = times[RUNS / 2];
+
+    println!(
+        "{:<24} {:>10.3} ms/iter (median; {:.3} to {:.3}) {:>12.0} samples/s",
+        name,
+        median,
+        times[0],
+        times[RUNS - 1],
+        samples as f64 / (median / 1000.0)
+    );
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
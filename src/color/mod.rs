@@ -0,0 +1,55 @@
+//! Optional ANSI coloring for the text outputs.
+
+use std::env;
+use std::io::{self, IsTerminal};
+
+#[cfg(test)]
+mod test;
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorChoice {
+    /// Color only if stdout is a terminal and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Decides whether output should actually be colored.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+                !no_color && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+/// Wraps `text` (the rendering of `percent`) in a color reflecting how
+/// hot it is: red above 10%, yellow above 5%, plain otherwise.
+pub fn percent(color: bool, percent: u32, text: &str) -> String {
+    if !color || percent <= 5 {
+        text.to_string()
+    } else if percent > 10 {
+        format!("{}{}{}", RED, text, RESET)
+    } else {
+        format!("{}{}{}", YELLOW, text, RESET)
+    }
+}
+
+/// Highlights `text`, e.g. the frames selected by a matcher.
+pub fn highlight(color: bool, text: &str) -> String {
+    if color {
+        format!("{}{}{}", BOLD, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,17 @@
+use super::*;
+
+#[test]
+fn never_is_plain() {
+    assert!(!ColorChoice::Never.enabled());
+    assert_eq!(percent(false, 50, " 50%"), " 50%");
+    assert_eq!(highlight(false, "| foo"), "| foo");
+}
+
+#[test]
+fn thresholds() {
+    assert!(ColorChoice::Always.enabled());
+    assert_eq!(percent(true, 3, "3%"), "3%");
+    assert_eq!(percent(true, 7, "7%"), "\x1b[33m7%\x1b[0m");
+    assert_eq!(percent(true, 42, "42%"), "\x1b[31m42%\x1b[0m");
+    assert_eq!(highlight(true, "x"), "\x1b[1mx\x1b[0m");
+}
@@ -0,0 +1,193 @@
+//! Reads `--config` files. These use a small subset of TOML: one
+//! `key = value` per line, where a value is a string (`"..."` with the
+//! usual escapes, or `'...'` taken literally), a number, `true` or
+//! `false`, or an array of values in `[...]`, which may span lines.
+//! `#` starts a comment. Tables (`[section]`) are not supported.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    String(String),
+    /// A number, as written (less any `_` separators).
+    Number(String),
+    Array(Vec<Value>),
+}
+
+/// Parses the text of a config file into its `(key, value)` entries,
+/// in the order they appear. Errors name the line they occur on.
+pub fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser { chars: text.chars().peekable(), line: 1 };
+    let mut entries: Vec<(String, Value)> = vec![];
+    loop {
+        parser.skip_blank(true);
+        if parser.chars.peek().is_none() {
+            return Ok(entries);
+        }
+
+        let line = parser.line;
+        let entry = parser.entry().map_err(|e| format!("line {}: {}", parser.line, e))?;
+        if entries.iter().any(|(key, _)| *key == entry.0) {
+            return Err(format!("line {}: `{}` is given twice", line, entry.0));
+        }
+        entries.push(entry);
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    /// Skips spaces and comments and, if `newlines`, line ends too.
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '#' => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.next();
+                    }
+                }
+                '\n' if !newlines => return,
+                c if c.is_whitespace() => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn entry(&mut self) -> Result<(String, Value), String> {
+        let key = match self.chars.peek() {
+            Some(&'[') => return Err("tables are not supported".to_string()),
+            Some(&'"') => self.basic_string()?,
+            Some(&'\'') => self.literal_string()?,
+            _ => self.bare_word(),
+        };
+        if key.is_empty() {
+            return Err("expected a key".to_string());
+        }
+
+        self.skip_blank(false);
+        if self.next() != Some('=') {
+            return Err(format!("expected `=` after `{}`", key));
+        }
+        self.skip_blank(false);
+        let value = self.value()?;
+
+        self.skip_blank(false);
+        match self.next() {
+            None | Some('\n') => Ok((key, value)),
+            Some(c) => Err(format!("unexpected `{}` after the value of `{}`", c, key)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.chars.peek() {
+            Some(&'"') => Ok(Value::String(self.basic_string()?)),
+            Some(&'\'') => Ok(Value::String(self.literal_string()?)),
+            Some(&'[') => self.array(),
+            _ => {
+                let word = self.bare_word();
+                match &word[..] {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => {
+                        let number = word.replace('_', "");
+                        if !number.is_empty() && number.parse::<f64>().is_ok() {
+                            Ok(Value::Number(number))
+                        } else if word.is_empty() {
+                            Err("expected a value".to_string())
+                        } else {
+                            Err(format!("invalid value `{}` (strings need quotes)", word))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.next(); // `[`
+        let mut items = vec![];
+        loop {
+            self.skip_blank(true);
+            if self.chars.peek() == Some(&']') {
+                self.next();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank(true);
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err("expected `,` or `]` in array".to_string()),
+            }
+        }
+    }
+
+    /// A key or keyword: letters, digits and `_-.+`.
+    fn bare_word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || "_-.+".contains(c)) {
+                break;
+            }
+            word.push(c);
+            self.next();
+        }
+        word
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.next(); // `"`
+        let mut s = String::new();
+        loop {
+            match self.string_char()? {
+                '"' => return Ok(s),
+                '\\' => s.push(match self.string_char()? {
+                    '\\' => '\\',
+                    '"' => '"',
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    c => return Err(format!("unknown escape `\\{}` (use '...' for regexes)", c)),
+                }),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.next(); // `'`
+        let mut s = String::new();
+        loop {
+            match self.string_char()? {
+                '\'' => return Ok(s),
+                c => s.push(c),
+            }
+        }
+    }
+
+    /// The next character of a string, which must not end the line.
+    fn string_char(&mut self) -> Result<char, String> {
+        match self.chars.peek() {
+            Some(&c) if c != '\n' => Ok(self.next().unwrap()),
+            _ => Err("unterminated string".to_string()),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use super::*;
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+#[test]
+fn values() {
+    let text = r#"
+# an analysis recipe
+matcher = '{^rustc::}..{^je_}'
+tree-callees = true
+color = "never"   # a trailing comment
+tree-max-depth = 3
+frequency = 1_000
+quiet = false
+rename = [
+    ['::\{\{closure\}\}', ''],   # regexes read best as literal strings
+    ["^core::", "std::"],
+]
+"key with spaces" = "a\tb\"c\\"
+"#;
+    assert_eq!(parse(text).unwrap(), vec![
+        ("matcher".to_string(), string("{^rustc::}..{^je_}")),
+        ("tree-callees".to_string(), Value::Bool(true)),
+        ("color".to_string(), string("never")),
+        ("tree-max-depth".to_string(), Value::Number("3".to_string())),
+        ("frequency".to_string(), Value::Number("1000".to_string())),
+        ("quiet".to_string(), Value::Bool(false)),
+        ("rename".to_string(), Value::Array(vec![
+            Value::Array(vec![string(r"::\{\{closure\}\}"), string("")]),
+            Value::Array(vec![string("^core::"), string("std::")]),
+        ])),
+        ("key with spaces".to_string(), string("a\tb\"c\\")),
+    ]);
+    assert_eq!(parse("").unwrap(), vec![]);
+}
+
+#[test]
+fn errors() {
+    let error = |text: &str| parse(text).unwrap_err();
+    assert_eq!(error("a = 1\n[section]\n"), "line 2: tables are not supported");
+    assert_eq!(error("a = 1\na = 2\n"), "line 2: `a` is given twice");
+    assert_eq!(error("a 1\n"), "line 1: expected `=` after `a`");
+    assert_eq!(error("a = never\n"), "line 1: invalid value `never` (strings need quotes)");
+    assert_eq!(error("a = \"x\n"), "line 1: unterminated string");
+    assert_eq!(error("a = 'x"), "line 1: unterminated string");
+    assert_eq!(error("a = \"\\d\"\n"), "line 1: unknown escape `\\d` (use '...' for regexes)");
+    assert_eq!(error("a = 1 2\n"), "line 1: unexpected `2` after the value of `a`");
+    assert_eq!(error("a = [1 2]\n"), "line 1: expected `,` or `]` in array");
+    assert_eq!(error("= 1\n"), "line 1: expected a key");
+}
@@ -0,0 +1,104 @@
+//! Demangling of legacy Rust symbols, such as
+//! `_ZN4core3ptr13drop_in_place17h0123456789abcdefE`, for the frames
+//! that perf could not demangle itself.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod test;
+
+thread_local! {
+    // The symbols `with_demangled` has seen, and what they demangle to.
+    static CACHE: RefCell<HashMap<String, Option<String>>> = RefCell::new(HashMap::new());
+}
+
+/// The demangled form of a legacy (`_ZN...E`) symbol, without the
+/// trailing hash: `core::ptr::drop_in_place` for the example above.
+/// Returns `None` if `symbol` is not mangled that way.
+pub fn demangle(symbol: &str) -> Option<String> {
+    let mut rest = symbol.strip_prefix("_ZN")
+                         .or_else(|| symbol.strip_prefix("__ZN"))?;
+    let mut path = vec![];
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().ok()?;
+        path.push(rest.get(digits..digits + len)?);
+        rest = &rest[digits + len..];
+    }
+
+    if path.len() > 1 && is_hash(path[path.len() - 1]) {
+        path.pop();
+    }
+    if path.is_empty() {
+        return None;
+    }
+
+    let path: Vec<String> = path.into_iter().map(unescape).collect::<Option<_>>()?;
+    Some(path.join("::"))
+}
+
+/// Calls `f` with `demangle(symbol)`, but demangles each distinct
+/// symbol only once: a dump mentions the same few functions over and
+/// over, and the matcher asks about every frame it looks at. Symbols
+/// that are not mangled at all are passed through without touching
+/// the cache.
+pub fn with_demangled<F, R>(symbol: &str, f: F) -> R
+where
+    F: FnOnce(Option<&str>) -> R,
+{
+    if !symbol.starts_with("_ZN") && !symbol.starts_with("__ZN") {
+        return f(None);
+    }
+
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if !cache.contains_key(symbol) {
+            cache.insert(symbol.to_string(), demangle(symbol));
+        }
+        f(cache[symbol].as_deref())
+    })
+}
+
+/// True for the `h0123456789abcdef` path component that ends most
+/// legacy symbols.
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17 && ident.starts_with('h')
+        && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Undoes the `$LT$`-style escapes of one path component; `..` stands
+/// for `::`, which shows up in the paths of trait impls.
+fn unescape(ident: &str) -> Option<String> {
+    let ident = if ident.starts_with("_$") { &ident[1..] } else { ident };
+    let mut out = String::with_capacity(ident.len());
+    let mut rest = ident;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('$') {
+            let end = after.find('$')?;
+            out.push(match &after[..end] {
+                "SP" => '@',
+                "BP" => '*',
+                "RF" => '&',
+                "LT" => '<',
+                "GT" => '>',
+                "LP" => '(',
+                "RP" => ')',
+                "C" => ',',
+                code => {
+                    let hex = code.strip_prefix('u')?;
+                    ::std::char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+                }
+            });
+            rest = &after[end + 1..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Some(out)
+}
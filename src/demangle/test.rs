@@ -0,0 +1,40 @@
+use super::*;
+
+#[test]
+fn legacy_symbols() {
+    let cases = [
+        ("_ZN4core3ptr13drop_in_place17h0123456789abcdefE", "core::ptr::drop_in_place"),
+        ("_ZN5rustc2ty4fold9fold_with17hdeadbeefdeadbeefE", "rustc::ty::fold::fold_with"),
+        ("_ZN3std2rt10lang_start28_$u7b$$u7b$closure$u7d$$u7d$17h1111111111111111E",
+         "std::rt::lang_start::{{closure}}"),
+        ("_ZN66_$LT$alloc..vec..Vec$LT$T$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop\
+          17h2222222222222222E",
+         "<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop"),
+        ("__ZN3foo3barE", "foo::bar"),
+        ("_ZN3foo3barE.llvm.1234", "foo::bar"),
+    ];
+    for &(symbol, expected) in &cases {
+        assert_eq!(demangle(symbol).as_ref().map(|s| &s[..]), Some(expected), "{}", symbol);
+    }
+}
+
+#[test]
+fn not_mangled() {
+    for symbol in &["core::ptr::drop_in_place", "_ZN", "_ZN9fooE", "main", ""] {
+        assert_eq!(demangle(symbol), None, "{}", symbol);
+    }
+}
+
+#[test]
+fn cached() {
+    let symbol = "_ZN4core3ptr13drop_in_place17h0123456789abcdefE";
+    for _ in 0..3 {
+        assert_eq!(with_demangled(symbol, |name| name.map(str::to_string)),
+                   Some("core::ptr::drop_in_place".to_string()));
+    }
+    assert!(with_demangled("_ZN9fooE", |name| name.is_none()));
+    assert!(with_demangled("main", |name| name.is_none()));
+
+    // one entry per mangled symbol, hit or miss; plain names stay out
+    CACHE.with(|cache| assert_eq!(cache.borrow().len(), 2));
+}
@@ -0,0 +1,67 @@
+//! Generates synthetic `perf script` output, for use in benchmarks
+//! and tests that need a large input without a real `perf.data`.
+
+use std::fmt::Write;
+
+/// Function names that synthetic stacks are drawn from. Stacks
+/// always begin with `main`; the `a::`/`b::` families are there so
+/// that queries like `{^a::}..{^b::}` match a reasonable fraction of
+/// the samples.
+const FUNCTIONS: &[&str] = &[
+    "a::outer",
+    "a::inner",
+    "b::compute",
+    "b::helper",
+    "c::alloc",
+    "c::free",
+    "d::hash",
+    "d::lookup",
+    "je_malloc",
+    "memcpy",
+];
+
+/// Produce a dump containing `samples` samples in the format emitted
+/// by `perf script`. The output is deterministic: the same number of
+/// samples always yields the same text.
+pub fn synthetic_dump(samples: usize) -> String {
+    let mut rng = Lcg(0x2545_f491_4f6c_dd1d);
+    let mut out = String::new();
+
+    for sample in 0..samples {
+        writeln!(
+            out,
+            "rustc 18883 {}.{:06}: cycles:",
+            2323302 + sample / 1000,
+            sample % 1000
+        ).unwrap();
+
+        // perf lists the innermost frame first, so generate the stack
+        // from the root and emit it in reverse.
+        let depth = 4 + rng.next() % 28;
+        let mut stack = vec!["main"];
+        for _ in 0..depth {
+            stack.push(FUNCTIONS[rng.next() % FUNCTIONS.len()]);
+        }
+
+        for (index, name) in stack.iter().enumerate().rev() {
+            writeln!(out, "\t    {:x} {} (/some/path.so)", 0x7f82e6dee178 + index, name).unwrap();
+        }
+
+        out.push('\n');
+    }
+
+    out
+}
+
+/// A tiny linear congruential generator; we only need cheap,
+/// reproducible variety, not quality randomness.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> usize {
+        self.0 = self.0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) as usize
+    }
+}
@@ -1,12 +1,15 @@
 //! A simple graph data structure for storing the call graph we observe.
 
+use regex::Regex;
 use std::collections::{HashMap, HashSet};
 use std::io::{Result, Write};
-use std::usize;
 use util::percent;
 
 use super::AddFrames;
 
+#[cfg(test)]
+mod test;
+
 pub struct CallGraph {
     nodes: HashMap<String, NodeId>,
     edges: HashMap<Edge, usize>,
@@ -26,6 +29,57 @@ pub struct CallGraph {
     frames: Vec<NodeId>,
 
     total: usize,
+
+    // The nodes that survived the top-N and minimum-percentage cuts
+    // in `set_total`, with their rank (1 for the most common).
+    top_nodes: HashMap<NodeId, usize>,
+
+    // Nodes below this percentage of `total` are dropped by `set_total`.
+    min_percent: usize,
+
+    // If true, `dump` also emits top-N nodes that have no edges.
+    keep_orphans: bool,
+
+    // If true (the default), a node is counted at most once per sample.
+    dedup: bool,
+
+    // If true, an edge is counted once per traversal rather than once
+    // per sample.
+    count_repeats: bool,
+
+    // If true, `dump` prefixes node labels with their rank.
+    rank: bool,
+
+    // If true, `a -> b` and `b -> a` are one edge, and `dump` writes
+    // an undirected graph.
+    undirected: bool,
+
+    // Nodes whose names match are drawn as red ellipses by `dump`.
+    highlight: Option<Regex>,
+
+    // Graph attributes for `dump`: the layout engine, and the maximum
+    // size of the drawing in inches.
+    layout: Option<Layout>,
+    size: Option<(f64, f64)>,
+}
+
+/// A Graphviz layout engine. `dot` suits call graphs best; `fdp` and
+/// `sfdp` can cope better with very large graphs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Layout {
+    Dot,
+    Fdp,
+    Sfdp,
+}
+
+impl Layout {
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Dot => "dot",
+            Layout::Fdp => "fdp",
+            Layout::Sfdp => "sfdp",
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -33,67 +87,186 @@ struct NodeId(usize);
 
 const MARKER: NodeId = NodeId(usize::MAX);
 
+const HIGHLIGHT_STYLE: &str = ", shape=ellipse, color=red, fontcolor=red";
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 struct Edge {
     caller: NodeId,
     callee: NodeId,
 }
 
+impl Default for CallGraph {
+    fn default() -> CallGraph {
+        CallGraph::new()
+    }
+}
+
 impl CallGraph {
     pub fn new() -> CallGraph {
         CallGraph { nodes: HashMap::new(), edges: HashMap::new(), node_counts: vec![], total: 0,
-                    frames: vec![] }
+                    frames: vec![], top_nodes: HashMap::new(), min_percent: 0, keep_orphans: false,
+                    dedup: true, count_repeats: false, rank: false, undirected: false,
+                    highlight: None, layout: None, size: None }
+    }
+
+    /// Makes `dump` ask for the given layout engine.
+    pub fn set_layout(&mut self, layout: Option<Layout>) {
+        self.layout = layout;
+    }
+
+    /// Makes `dump` ask for the drawing to be scaled down to fit in
+    /// `width` by `height` inches.
+    pub fn set_size(&mut self, size: Option<(f64, f64)>) {
+        self.size = size;
+    }
+
+    /// Normally `dump` only emits nodes that take part in some edge.
+    /// When `keep_orphans` is set, nodes that made the top-N cut but
+    /// lost all their edges (because their neighbors were pruned) are
+    /// emitted as standalone boxes too.
+    pub fn set_keep_orphans(&mut self, keep_orphans: bool) {
+        self.keep_orphans = keep_orphans;
+    }
+
+    /// Makes `dump` draw the nodes whose names match `highlight` as red
+    /// ellipses rather than plain boxes.
+    pub fn set_highlight(&mut self, highlight: Option<Regex>) {
+        self.highlight = highlight;
+    }
+
+    /// Controls whether a node that appears more than once in a sample
+    /// (say, a recursive fn) is counted once, or once per appearance.
+    /// Edges are counted once per sample unless `set_count_repeats`
+    /// says otherwise.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    /// Normally an edge that occurs several times within one sample
+    /// (say, `a -> b -> a -> b`) is counted once, so its percentage is
+    /// the share of samples that went through it. When `count_repeats`
+    /// is set, every traversal is counted, so the label measures how
+    /// often the call happens instead, and can exceed 100%.
+    pub fn set_count_repeats(&mut self, count_repeats: bool) {
+        self.count_repeats = count_repeats;
     }
 
+    /// Makes `dump` prefix each node label with the node's rank among
+    /// the nodes kept by `set_total`, as in `#1 main (100%)`.
+    pub fn set_rank(&mut self, rank: bool) {
+        self.rank = rank;
+    }
+
+    /// Makes `set_total` ignore the direction of calls, so that `a -> b`
+    /// and `b -> a` count as one edge `a -- b`, and `dump` write an
+    /// undirected `graph` rather than a `digraph`. As with directed
+    /// edges, an edge is counted once per sample (unless
+    /// `set_count_repeats`), so a sample where `a` and `b` call each
+    /// other adds 1. `degree_report` then has no meaning.
+    pub fn set_undirected(&mut self, undirected: bool) {
+        self.undirected = undirected;
+    }
+
+    /// Makes `set_total` drop nodes below `min_percent` percent of the
+    /// total, in addition to those outside the top N. Only nodes that
+    /// pass both cuts are kept, and edges only run between kept nodes.
+    pub fn set_min_percent(&mut self, min_percent: usize) {
+        self.min_percent = min_percent;
+    }
+
+    /// Keeps the `threshold` most common nodes (see also
+    /// `set_min_percent`) and builds the edges between them, with
+    /// percentages relative to `total`. Each call starts over from all
+    /// the samples added so far, so it can be called again to refresh
+    /// the graph after more samples, or to re-rank with a different
+    /// `threshold`.
     pub fn set_total(&mut self, total: usize, threshold: usize) {
         self.total = total;
 
-        let mut percents: Vec<(u32, NodeId)> =
+        // rank by the raw counts: the percentages shown in the labels
+        // are truncated, and nodes whose counts differ by less than a
+        // percent would otherwise tie
+        let mut counts: Vec<(usize, NodeId)> =
             self.node_counts.iter()
-                            .map(|&count| percent(count, total))
                             .enumerate()
-                            .map(|(index, percent)| (percent, NodeId(index)))
+                            .map(|(index, &count)| (count, NodeId(index)))
                             .collect();
 
-        percents.sort();
-
-        // a map of the top N node ids, and their percentages
-        let top_node_ids: HashSet<NodeId> =
-            percents.iter()
-                    .rev()
-                    .take(threshold)
-                    .map(|&(_, i)| i)
-                    .collect();
+        // most common first; among equals, the one seen first
+        counts.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
 
-        // drop all nodes from the list of frames data if they don't
-        // appear in the top-node-ids list
-        self.frames.retain(|&n| n == MARKER || top_node_ids.contains(&n));
+        // a map from the top N node ids that also meet the minimum
+        // percentage to their rank
+        let min_percent = self.min_percent;
+        let top_node_ids: HashMap<NodeId, usize> =
+            counts.iter()
+                  .take(threshold)
+                  .filter(|&&(count, _)| percent(count, total) as usize >= min_percent)
+                  .enumerate()
+                  .map(|(rank, &(_, i))| (i, rank + 1))
+                  .collect();
 
-        // construct the edges.
+        // construct the edges, skipping over nodes that aren't in the
+        // top-node-ids list. `frames` is left alone and `edges` is
+        // rebuilt from scratch, so `set_total` can be called again as
+        // more samples come in.
+        self.edges.clear();
         let mut edges = vec![];
-        for i in 0..self.frames.len() {
-            let caller = self.frames[i];
-
+        let mut caller: Option<NodeId> = None;
+        for &node in self.frames.iter().filter(|&&n| n == MARKER || top_node_ids.contains_key(&n)) {
             // when we reach the end of a sample, collect the edges,
             // remove duplicates, and insert them into the map. This
             // way, if an edge occurs multiple times within one
-            // sample, it only gets counted a single time in the map.
-            if caller == MARKER {
-                edges.sort();
-                edges.dedup();
+            // sample, it only gets counted a single time in the map
+            // (unless `count_repeats` is set).
+            if node == MARKER {
+                if !self.count_repeats {
+                    edges.sort();
+                    edges.dedup();
+                }
                 for &edge in &edges {
                     *self.edges.entry(edge).or_insert(0) += 1;
                 }
                 edges.truncate(0);
+                caller = None;
                 continue;
             }
 
-            // otherwise, record an edge between this frame and the next
-            let callee = self.frames[i+1];
-            if callee != MARKER {
-                edges.push(Edge { caller: caller, callee: callee });
+            // otherwise, record an edge between the previous frame and
+            // this one
+            if let Some(caller) = caller {
+                if self.undirected {
+                    edges.push(Edge { caller: caller.min(node), callee: caller.max(node) });
+                } else {
+                    edges.push(Edge { caller, callee: node });
+                }
             }
+            caller = Some(node);
+        }
+
+        self.top_nodes = top_node_ids;
+    }
+
+    /// For each node with at least one edge, the number of distinct
+    /// callees (out-degree) and callers (in-degree) it has, as
+    /// `(name, out_degree, in_degree)`. Sorted by out-degree, then
+    /// in-degree, highest first, and then by name. Only meaningful after
+    /// `set_total`, which builds the edges.
+    pub fn degree_report(&self) -> Vec<(&str, usize, usize)> {
+        let mut degrees: HashMap<NodeId, (usize, usize)> = HashMap::new();
+        for edge in self.edges.keys() {
+            degrees.entry(edge.caller).or_insert((0, 0)).0 += 1;
+            degrees.entry(edge.callee).or_insert((0, 0)).1 += 1;
         }
+
+        let mut report: Vec<(&str, usize, usize)> =
+            self.nodes.iter()
+                      .filter_map(|(name, id)| {
+                          degrees.get(id).map(|&(out, inn)| (&name[..], out, inn))
+                      })
+                      .collect();
+        report.sort_by(|a, b| (b.1, b.2, a.0).cmp(&(a.1, a.2, b.0)));
+        report
     }
 
     fn node_id(&mut self, name: String) -> NodeId {
@@ -105,15 +278,27 @@ impl CallGraph {
         })
     }
 
-    pub fn dump(&self, out: &mut Write) -> Result<()> {
-        try!(write!(out, "digraph G {{\n"));
-        try!(write!(out, "  node [ shape=box ];"));
+    pub fn dump(&self, out: &mut dyn Write) -> Result<()> {
+        let (kind, edge_op) = if self.undirected { ("graph", "--") } else { ("digraph", "->") };
+        writeln!(out, "{} G {{", kind)?;
+        let mut attributes = vec![];
+        if let Some(layout) = self.layout {
+            attributes.push(format!("layout={}", layout.name()));
+        }
+        if let Some((width, height)) = self.size {
+            attributes.push(format!("size=\"{},{}\"", width, height));
+            attributes.push("ratio=compress".to_string());
+        }
+        if !attributes.is_empty() {
+            writeln!(out, "  graph [ {} ];", attributes.join(", "))?;
+        }
+        writeln!(out, "  node [ shape=box ];")?;
 
         let mut node_ids = HashSet::new();
         for (edge, &count) in self.edges.iter() {
             let percentage = percent(count, self.total);
-            try!(write!(out, "  n{} -> n{} [label=\"{}%\"];\n",
-                        edge.caller.0, edge.callee.0, percentage));
+            writeln!(out, "  n{} {} n{} [label=\"{}%\"];",
+                     edge.caller.0, edge_op, edge.callee.0, percentage)?;
             node_ids.insert(edge.caller);
             node_ids.insert(edge.callee);
         }
@@ -121,13 +306,23 @@ impl CallGraph {
         for (name, &index) in self.nodes.iter() {
             let count = self.node_counts[index.0];
             let percentage = percent(count, self.total);
-            if node_ids.contains(&index) {
-                try!(write!(out, "  n{} [label=\"{} ({}%)\"];\n",
-                            index.0, name, percentage));
+            if node_ids.contains(&index)
+                || (self.keep_orphans && self.top_nodes.contains_key(&index))
+            {
+                let style = match self.highlight {
+                    Some(ref regex) if regex.is_match(name) => HIGHLIGHT_STYLE,
+                    _ => "",
+                };
+                let rank = match self.top_nodes.get(&index) {
+                    Some(rank) if self.rank => format!("#{} ", rank),
+                    _ => String::new(),
+                };
+                writeln!(out, "  n{} [label=\"{}{} ({}%)\"{}];",
+                         index.0, rank, name, percentage, style)?;
             }
         }
 
-        try!(write!(out, "}}\n"));
+        writeln!(out, "}}")?;
         Ok(())
     }
 }
@@ -139,7 +334,7 @@ impl AddFrames for CallGraph {
         let mut node_ids: Vec<_> = frames.map(|frame| self.node_id(frame)).collect();
 
         // just ignore empty samples
-        if node_ids.len() == 0 {
+        if node_ids.is_empty() {
             return;
         }
 
@@ -147,11 +342,46 @@ impl AddFrames for CallGraph {
         self.frames.extend(node_ids.iter().cloned());
         self.frames.push(MARKER);
 
-        node_ids.sort();
-        node_ids.dedup();
+        if self.dedup {
+            node_ids.sort();
+            node_ids.dedup();
+        }
 
         for id in node_ids {
             self.node_counts[id.0] += 1;
         }
     }
+
+    fn add_keyed_frames<I>(&mut self, frames: I)
+        where I: Iterator<Item=(String, String)>
+    {
+        let frames: Vec<_> = frames.map(|(key, frame)| (key, self.node_id(frame))).collect();
+
+        // just ignore empty samples
+        if frames.is_empty() {
+            return;
+        }
+
+        // the edges come from the whole sequence; only the node counts
+        // are deduplicated, by key
+        self.frames.reserve(frames.len() + 1);
+        self.frames.extend(frames.iter().map(|&(_, id)| id));
+        self.frames.push(MARKER);
+
+        let mut seen = HashSet::new();
+        for (key, id) in frames {
+            if seen.insert(key) {
+                self.node_counts[id.0] += 1;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+        self.edges.clear();
+        self.node_counts.clear();
+        self.frames.clear();
+        self.total = 0;
+        self.top_nodes.clear();
+    }
 }
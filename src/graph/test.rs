@@ -0,0 +1,310 @@
+use super::*;
+
+fn add(graph: &mut CallGraph, frames: &[&str]) {
+    graph.add_frames(frames.iter().map(|s| s.to_string()));
+}
+
+fn dump(graph: &CallGraph) -> String {
+    let mut out = vec![];
+    graph.dump(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+/// The lines of `dump`, sorted, since nodes and edges come out in hash
+/// order.
+fn sorted(graph: &CallGraph) -> Vec<String> {
+    let mut lines: Vec<String> = dump(graph).lines().map(|l| l.to_string()).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn undirected() {
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["a", "b"]);
+    add(&mut graph, &["b", "a"]);
+    add(&mut graph, &["b", "a"]);
+    add(&mut graph, &["a", "c"]);
+
+    graph.set_total(4, usize::MAX);
+    let directed = sorted(&graph);
+    assert!(directed.contains(&"digraph G {".to_string()));
+    assert_eq!(directed.iter().filter(|l| l.contains(" -> ")).count(), 3);
+
+    graph.set_undirected(true);
+    graph.set_total(4, usize::MAX);
+    assert_eq!(sorted(&graph), [
+        "  n0 -- n1 [label=\"75%\"];",
+        "  n0 -- n2 [label=\"25%\"];",
+        "  n0 [label=\"a (100%)\"];",
+        "  n1 [label=\"b (75%)\"];",
+        "  n2 [label=\"c (25%)\"];",
+        "  node [ shape=box ];",
+        "graph G {",
+        "}",
+    ]);
+
+    // a sample in which the two call each other still counts once
+    add(&mut graph, &["a", "b", "a"]);
+    graph.set_total(5, usize::MAX);
+    assert!(sorted(&graph).contains(&"  n0 -- n1 [label=\"80%\"];".to_string()));
+}
+
+/// `leaf` is hot, but each of its callers is cold, so with a top-1
+/// cut it survives without any edges.
+fn orphaned_leaf() -> CallGraph {
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["x1", "leaf"]);
+    add(&mut graph, &["x2", "leaf"]);
+    add(&mut graph, &["x3", "leaf"]);
+    add(&mut graph, &["y", "z"]);
+    graph
+}
+
+#[test]
+fn orphan_dropped_by_default() {
+    let mut graph = orphaned_leaf();
+    graph.set_total(4, 1);
+    let out = dump(&graph);
+    assert!(!out.contains("leaf"), "{}", out);
+    assert!(!out.contains("->"), "{}", out);
+}
+
+#[test]
+fn orphan_kept() {
+    let mut graph = orphaned_leaf();
+    graph.set_keep_orphans(true);
+    graph.set_total(4, 1);
+    let out = dump(&graph);
+    assert!(out.contains("[label=\"leaf (75%)\"]"), "{}", out);
+    assert!(!out.contains("x1"), "{}", out);
+    assert!(!out.contains("->"), "{}", out);
+}
+
+#[test]
+fn clear_is_like_new() {
+    let mut graph = orphaned_leaf();
+    graph.set_keep_orphans(true);
+    graph.set_total(4, 1);
+    graph.clear();
+
+    let mut fresh = CallGraph::new();
+    fresh.set_keep_orphans(true);
+    for g in [&mut graph, &mut fresh].iter_mut() {
+        add(g, &["main", "a"]);
+        add(g, &["main", "b"]);
+        g.set_total(2, 22);
+    }
+
+    let mut lines: Vec<String> = dump(&graph).lines().map(|l| l.to_string()).collect();
+    let mut fresh_lines: Vec<String> = dump(&fresh).lines().map(|l| l.to_string()).collect();
+    lines.sort();
+    fresh_lines.sort();
+    assert_eq!(lines, fresh_lines);
+}
+
+/// `main` is in every sample; `a` in 4 of 10, `b` in 3 and each `cN`
+/// in 1.
+fn spread() -> CallGraph {
+    let mut graph = CallGraph::new();
+    for _ in 0..4 {
+        add(&mut graph, &["main", "a"]);
+    }
+    for _ in 0..3 {
+        add(&mut graph, &["main", "b"]);
+    }
+    for c in &["c1", "c2", "c3"] {
+        add(&mut graph, &["main", c]);
+    }
+    graph
+}
+
+fn nodes(graph: &CallGraph) -> Vec<String> {
+    let mut names: Vec<String> = graph.nodes.iter()
+        .filter(|&(_, id)| graph.top_nodes.contains_key(id))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn min_percent_vs_top_n() {
+    // top 5 by count takes two of the `cN`s
+    let mut graph = spread();
+    graph.set_total(10, 5);
+    assert_eq!(nodes(&graph).len(), 5);
+    assert!(nodes(&graph).iter().any(|n| n.starts_with('c')));
+
+    // at least 20% keeps main, a and b, however large N is
+    let mut graph = spread();
+    graph.set_min_percent(20);
+    graph.set_total(10, 22);
+    assert_eq!(nodes(&graph), ["a", "b", "main"]);
+    let out = dump(&graph);
+    assert!(!out.contains("c1"), "{}", out);
+    assert!(out.contains("[label=\"40%\"]"), "{}", out);
+
+    // both cuts apply
+    let mut graph = spread();
+    graph.set_min_percent(20);
+    graph.set_total(10, 2);
+    assert_eq!(nodes(&graph), ["a", "main"]);
+}
+
+#[test]
+fn highlight() {
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["main", "je_malloc"]);
+    add(&mut graph, &["main", "compute"]);
+    graph.set_highlight(Some(Regex::new("^je_").unwrap()));
+    graph.set_total(2, 22);
+
+    let out = dump(&graph);
+    let line = |name: &str| {
+        out.lines().find(|l| l.contains(&format!("label=\"{} ", name))).unwrap().to_string()
+    };
+    assert!(line("je_malloc").ends_with("(50%)\", shape=ellipse, color=red, fontcolor=red];"),
+            "{}", out);
+    assert!(line("compute").ends_with("(50%)\"];"), "{}", out);
+    assert!(line("main").ends_with("(100%)\"];"), "{}", out);
+}
+
+#[test]
+fn degree_report_finds_hub() {
+    // `dispatch` calls three distinct fns, and is called from two
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["main", "dispatch", "a"]);
+    add(&mut graph, &["main", "dispatch", "b"]);
+    add(&mut graph, &["main", "dispatch", "c"]);
+    add(&mut graph, &["main", "other", "dispatch", "a"]);
+    graph.set_total(4, 22);
+
+    let report = graph.degree_report();
+    assert_eq!(report[0], ("dispatch", 3, 2));
+    assert_eq!(report[1..], [("main", 2, 0), ("other", 1, 1), ("a", 0, 1), ("b", 0, 1),
+                             ("c", 0, 1)]);
+}
+
+#[test]
+fn count_repeats() {
+    let edge_label = |count_repeats| {
+        let mut graph = CallGraph::new();
+        graph.set_count_repeats(count_repeats);
+        add(&mut graph, &["a", "b", "a", "b"]);
+        add(&mut graph, &["a", "c"]);
+        graph.set_total(2, 22);
+        let out = dump(&graph);
+        let a = out.lines().find(|l| l.contains("[label=\"a ")).unwrap();
+        let b = out.lines().find(|l| l.contains("[label=\"b ")).unwrap();
+        let id = |l: &str| l.trim().split(' ').next().unwrap().to_string();
+        let edge = format!("  {} -> {} [label=", id(a), id(b));
+        out.lines().find(|l| l.starts_with(&edge)).unwrap().to_string()
+    };
+    assert!(edge_label(false).ends_with("[label=\"50%\"];"), "{}", edge_label(false));
+    assert!(edge_label(true).ends_with("[label=\"100%\"];"), "{}", edge_label(true));
+}
+
+#[test]
+fn set_total_twice() {
+    let samples: &[&[&str]] = &[&["main", "a", "b"], &["main", "a", "c"], &["main", "c"],
+                                &["main", "b"], &["main", "b"]];
+
+    // `b` is pruned at first
+    let mut graph = CallGraph::new();
+    for sample in &samples[..3] {
+        add(&mut graph, sample);
+    }
+    graph.set_total(3, 3);
+    let once = sorted(&graph);
+    assert!(!dump(&graph).contains("\"b "), "{}", dump(&graph));
+    graph.set_total(3, 3);
+    assert_eq!(sorted(&graph), once);
+
+    // refreshing after more samples gives the same graph as building
+    // it in one go, and `b` comes back with its earlier edges
+    for sample in &samples[3..] {
+        add(&mut graph, sample);
+    }
+    graph.set_total(5, 3);
+    let mut fresh = CallGraph::new();
+    for sample in samples {
+        add(&mut fresh, sample);
+    }
+    fresh.set_total(5, 3);
+    assert_eq!(sorted(&graph), sorted(&fresh));
+    assert!(dump(&graph).contains("[label=\"b (60%)\""), "{}", dump(&graph));
+}
+
+#[test]
+fn set_total_rerank() {
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["main", "a", "b"]);
+    add(&mut graph, &["main", "a", "b"]);
+    add(&mut graph, &["main", "c", "b"]);
+
+    graph.set_total(3, 4);
+    let wide = sorted(&graph);
+    assert_eq!(graph.degree_report(), [("main", 2, 0), ("a", 1, 1), ("c", 1, 1), ("b", 0, 2)]);
+
+    // with a top-2 cut, only `main -> b` survives, seen in every sample
+    graph.set_total(3, 2);
+    assert_eq!(graph.degree_report(), [("main", 1, 0), ("b", 0, 1)]);
+    let narrow = dump(&graph);
+    assert_eq!(narrow.matches(" -> ").count(), 1, "{}", narrow);
+    assert!(narrow.contains("[label=\"100%\"]"), "{}", narrow);
+
+    // widening again brings back the pruned nodes, and counts are not
+    // doubled by the earlier calls
+    graph.set_total(3, 4);
+    assert_eq!(sorted(&graph), wide);
+    assert!(wide.iter().any(|l| l.ends_with("[label=\"66%\"];")), "{:?}", wide);
+}
+
+#[test]
+fn ranks_by_count() {
+    // out of 1000 samples, `hot` is in 5 and `cold` in 4: both show as
+    // 0%, but only `hot` should make a top-2 cut
+    let mut graph = CallGraph::new();
+    for _ in 0..4 {
+        add(&mut graph, &["main", "cold"]);
+    }
+    for _ in 0..5 {
+        add(&mut graph, &["main", "hot"]);
+    }
+    graph.set_total(1000, 2);
+    let out = dump(&graph);
+    assert!(out.contains("[label=\"hot (0%)\"]"), "{}", out);
+    assert!(!out.contains("cold"), "{}", out);
+}
+
+#[test]
+fn rank() {
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["main", "a", "b"]);
+    add(&mut graph, &["main", "a"]);
+    graph.set_total(2, 22);
+    assert!(!dump(&graph).contains('#'));
+
+    graph.set_rank(true);
+    let out = dump(&graph);
+    assert!(out.contains("[label=\"#1 main (100%)\"]"), "{}", out);
+    assert!(out.contains("[label=\"#2 a (100%)\"]"), "{}", out);
+    assert!(out.contains("[label=\"#3 b (50%)\"]"), "{}", out);
+}
+
+#[test]
+fn layout_and_size() {
+    let mut graph = CallGraph::new();
+    add(&mut graph, &["main", "a"]);
+    graph.set_total(1, 22);
+    assert!(!dump(&graph).contains("graph ["));
+
+    graph.set_layout(Some(Layout::Sfdp));
+    assert!(dump(&graph).starts_with("digraph G {\n  graph [ layout=sfdp ];\n  node"));
+
+    graph.set_size(Some((7.5, 10.0)));
+    let out = dump(&graph);
+    assert!(out.contains("  graph [ layout=sfdp, size=\"7.5,10\", ratio=compress ];\n"),
+            "{}", out);
+}
@@ -1,20 +1,91 @@
 //! A simple graph data structure for storing the call graph we observe.
 
-use std::collections::{HashMap};
-use util::percent;
+use color;
+use std::collections::{HashMap, HashSet};
+use std::io::{Result, Write};
+use util::{percent, truncate_name};
 
 use super::AddFrames;
 
+#[cfg(test)]
+mod test;
+
 pub struct Histogram {
     fns: HashMap<String, usize>,
+
+    // If true (the default), `add_frames` counts a name at most once
+    // per sample.
+    dedup: bool,
+
+    // `dump` skips names below this percentage of the total.
+    min_percent: usize,
+
+    // `dump` shortens longer names to this many characters.
+    max_name_width: Option<usize>,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
 }
 
 impl Histogram {
     pub fn new() -> Histogram {
-        Histogram { fns: HashMap::new(), }
+        Histogram { fns: HashMap::new(), dedup: true, min_percent: 0, max_name_width: None }
+    }
+
+    /// Controls whether `add_frames` counts a name that appears more
+    /// than once in a sample (say, a recursive fn) once, or once per
+    /// appearance.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    /// Makes `dump` skip names that appear in less than `min_percent`
+    /// percent of the total.
+    pub fn set_min_percent(&mut self, min_percent: usize) {
+        self.min_percent = min_percent;
+    }
+
+    /// Makes `dump` shorten names longer than `max_name_width`
+    /// characters, keeping their end (see `util::truncate_name`).
+    pub fn set_max_name_width(&mut self, max_name_width: Option<usize>) {
+        self.max_name_width = max_name_width;
     }
 
-    pub fn dump(&self, total: usize, threshold: usize) {
+    /// Builds a histogram directly from `(name, count)` pairs. Repeated
+    /// names have their counts summed.
+    pub fn from_counts<I>(counts: I) -> Histogram
+        where I: IntoIterator<Item=(String, usize)>
+    {
+        let mut hist = Histogram::new();
+        for (name, count) in counts {
+            hist.add(name, count);
+        }
+        hist
+    }
+
+    /// Adds `count` to the entry for `name`. Unlike `add_frames`, this
+    /// does no per-sample deduplication.
+    pub fn add(&mut self, name: String, count: usize) {
+        *self.fns.entry(name).or_insert(0) += count;
+    }
+
+    /// Iterates over `(name, count)` pairs in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item=(&str, usize)> {
+        self.fns.iter().map(|(key, &value)| (&key[..], value))
+    }
+
+    /// The most common name and its count, if any; ties go to the
+    /// name that sorts first.
+    pub fn top(&self) -> Option<(&str, usize)> {
+        self.iter().max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+    }
+
+    pub fn dump(&self, out: &mut dyn Write, total: usize, threshold: usize, color: bool)
+                -> Result<()>
+    {
         let mut fns: Vec<(usize, &str)> =
             self.fns.iter()
                     .map(|(key, &value)| (value, &key[..]))
@@ -25,8 +96,17 @@ impl Histogram {
         let skip = if fns.len() < threshold {0} else {fns.len() - threshold};
         for &(count, name) in fns.iter().skip(skip) {
             let percentage = percent(count, total);
-            println!("{:3}% {}", percentage, name);
+            if (percentage as usize) < self.min_percent {
+                continue;
+            }
+            let text = format!("{:3}%", percentage);
+            let name = match self.max_name_width {
+                Some(width) => truncate_name(name, width),
+                None => name.to_string(),
+            };
+            writeln!(out, "{} {}", color::percent(color, percentage, &text), name)?;
         }
+        Ok(())
     }
 }
 
@@ -35,10 +115,27 @@ impl AddFrames for Histogram {
         where I: Iterator<Item=String>
     {
         let mut frames: Vec<_> = frames.collect();
-        frames.sort();
-        frames.dedup();
+        if self.dedup {
+            frames.sort();
+            frames.dedup();
+        }
         for frame in frames {
             *self.fns.entry(frame).or_insert(0) += 1;
         }
     }
+
+    fn add_keyed_frames<I>(&mut self, frames: I)
+        where I: Iterator<Item=(String, String)>
+    {
+        let mut seen = HashSet::new();
+        for (key, frame) in frames {
+            if seen.insert(key) {
+                *self.fns.entry(frame).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.fns.clear();
+    }
 }
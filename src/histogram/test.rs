@@ -0,0 +1,85 @@
+use super::*;
+
+fn example() -> Histogram {
+    let mut hist = Histogram::new();
+    for _ in 0..8 {
+        hist.add_frames(vec!["main".to_string(), "hot".to_string()].into_iter());
+    }
+    hist.add_frames(vec!["main".to_string(), "cold".to_string()].into_iter());
+    hist
+}
+
+fn dump(hist: &Histogram, color: bool) -> String {
+    let mut out = vec![];
+    hist.dump(&mut out, 10, 22, color).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn dump_plain() {
+    assert_eq!(dump(&example(), false), " 10% cold\n 80% hot\n 90% main\n");
+}
+
+#[test]
+fn dump_color() {
+    let out = dump(&example(), true);
+    assert!(out.contains("\x1b[31m 80%\x1b[0m hot"), "{:?}", out);
+    assert!(out.contains("\x1b[33m 10%\x1b[0m cold"), "{:?}", out);
+}
+
+#[test]
+fn min_percent() {
+    let mut hist = example();
+    hist.set_min_percent(50);
+    assert_eq!(dump(&hist, false), " 80% hot\n 90% main\n");
+}
+
+#[test]
+fn max_name_width() {
+    let mut hist = Histogram::new();
+    hist.add("rustc::ty::fold::TypeFoldable::fold_with".to_string(), 1);
+    hist.add("main".to_string(), 1);
+    hist.set_max_name_width(Some(10));
+    assert_eq!(dump(&hist, false), " 10% main\n 10% …fold_with\n");
+
+    // the names themselves are kept whole
+    assert!(hist.iter().any(|(name, _)| name.len() > 10));
+}
+
+#[test]
+fn top() {
+    assert_eq!(example().top(), Some(("main", 9)));
+    assert_eq!(Histogram::new().top(), None);
+
+    let tied = Histogram::from_counts(vec![("b".to_string(), 2), ("a".to_string(), 2)]);
+    assert_eq!(tied.top(), Some(("a", 2)));
+}
+
+#[test]
+fn from_counts_round_trip() {
+    let counts = vec![
+        ("main".to_string(), 10),
+        ("hot".to_string(), 7),
+        ("cold".to_string(), 1),
+        ("hot".to_string(), 2),
+    ];
+    let mut hist = Histogram::from_counts(counts);
+    hist.add("cold".to_string(), 1);
+
+    let mut entries: Vec<_> = hist.iter().collect();
+    entries.sort();
+    assert_eq!(entries, vec![("cold", 2), ("hot", 9), ("main", 10)]);
+}
+
+#[test]
+fn clear_is_like_new() {
+    let mut hist = example();
+    hist.clear();
+    assert_eq!(hist.iter().count(), 0);
+
+    let mut fresh = Histogram::new();
+    for h in [&mut hist, &mut fresh].iter_mut() {
+        h.add_frames(vec!["main".to_string(), "x".to_string()].into_iter());
+    }
+    assert_eq!(dump(&hist, false), dump(&fresh, false));
+}
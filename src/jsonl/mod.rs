@@ -0,0 +1,75 @@
+//! Streams matched samples out as JSON lines, one object per sample:
+//!
+//! ```text
+//! {"process":"rustc","tid":18883,"timestamp":2323302.03915,"frames":["main","..."]}
+//! ```
+//!
+//! Frames are listed from the outermost (root) to the innermost.
+//! Fields that could not be parsed from the sample header are `null`.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{LineWriter, Result, Write};
+use trace::TraceArgs;
+
+#[cfg(test)]
+mod test;
+
+pub struct JsonlWriter<W: Write> {
+    // A `LineWriter` flushes after each complete line, so a consumer
+    // tailing the output never sees a partial object.
+    out: LineWriter<W>,
+    line: String,
+}
+
+impl<W: Write> JsonlWriter<W> {
+    pub fn new(out: W) -> JsonlWriter<W> {
+        JsonlWriter { out: LineWriter::new(out), line: String::new() }
+    }
+
+    pub fn write_sample(&mut self, args: &TraceArgs) -> Result<()> {
+        self.line.truncate(0);
+        self.line.push_str("{\"process\":");
+        push_str(&mut self.line, args.process_name);
+        self.line.push_str(",\"tid\":");
+        match args.tid {
+            Some(tid) => write!(self.line, "{}", tid).unwrap(),
+            None => self.line.push_str("null"),
+        }
+        self.line.push_str(",\"timestamp\":");
+        match args.timestamp {
+            Some(t) => write!(self.line, "{}", t).unwrap(),
+            None => self.line.push_str("null"),
+        }
+        self.line.push_str(",\"frames\":[");
+        for (index, frame) in args.stack.iter().enumerate() {
+            if index > 0 {
+                self.line.push(',');
+            }
+            push_str(&mut self.line, frame);
+        }
+        self.line.push_str("]}\n");
+
+        self.out.write_all(self.line.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Appends `s` to `out` as a quoted JSON string.
+fn push_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
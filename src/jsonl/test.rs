@@ -0,0 +1,42 @@
+use super::*;
+
+fn sample(tid: Option<u32>, stack: &[&str]) -> String {
+    let args = TraceArgs {
+        header: &[],
+        process_name: "rustc",
+        tid,
+        cpu: None,
+        timestamp: tid.map(|_| 2323302.5),
+        event: None,
+        cost: None,
+        stack: stack.iter().map(|s| s.to_string()).collect(),
+    };
+
+    let mut out = vec![];
+    {
+        let mut writer = JsonlWriter::new(&mut out);
+        writer.write_sample(&args).unwrap();
+        writer.write_sample(&args).unwrap();
+    }
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn one_line_per_sample() {
+    let out = sample(Some(18883), &["main", "foo::bar"]);
+    let line = r#"{"process":"rustc","tid":18883,"timestamp":2323302.5,"frames":["main","foo::bar"]}"#;
+    assert_eq!(out, format!("{}\n{}\n", line, line));
+}
+
+#[test]
+fn missing_fields_are_null() {
+    let out = sample(None, &[]);
+    assert_eq!(out.lines().next().unwrap(),
+               r#"{"process":"rustc","tid":null,"timestamp":null,"frames":[]}"#);
+}
+
+#[test]
+fn escaping() {
+    let out = sample(None, &["<impl \"Foo\\Bar\">", "a\tb\u{1}"]);
+    assert!(out.contains(r#""frames":["<impl \"Foo\\Bar\">","a\tb\u0001"]"#), "{}", out);
+}
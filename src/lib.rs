@@ -0,0 +1,45 @@
+extern crate itertools;
+extern crate regex;
+
+#[macro_use]
+extern crate rusty_peg;
+
+pub mod color;
+pub mod config;
+pub mod demangle;
+pub mod fixture;
+pub mod graph;
+pub mod histogram;
+pub mod jsonl;
+pub mod matcher;
+pub mod pairs;
+pub mod progress;
+pub mod rustc_query;
+pub mod stacks;
+pub mod trace;
+pub mod transitions;
+pub mod tree;
+pub mod util;
+
+pub trait AddFrames {
+    fn add_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = String>;
+
+    /// Like `add_frames`, but each frame comes as `(key, name)`, and
+    /// an accumulator that dedups its counts should count a frame once
+    /// per distinct key in the sample rather than once per distinct
+    /// name; used for `--dedup pre-rename`. The sequence of names must
+    /// still be kept whole. By default the keys are ignored.
+    fn add_keyed_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = (String, String)>,
+    {
+        self.add_frames(frames.map(|(_, name)| name));
+    }
+
+    /// Resets to the state of a freshly created accumulator, keeping
+    /// allocated storage where possible. Configuration (such as
+    /// `CallGraph::set_keep_orphans`) is kept.
+    fn clear(&mut self);
+}
@@ -1,89 +1,322 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::env;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{self, BufWriter};
+use std::io::{self, BufWriter, Read};
+use std::ops::ControlFlow;
 use std::process::exit;
 use std::str::FromStr;
 
-extern crate itertools;
+extern crate perf_focus;
 extern crate regex;
 
-#[macro_use]
-extern crate rusty_peg;
-
-mod histogram;
-mod graph;
-mod matcher;
-mod rustc_query;
-mod trace;
-mod tree;
-mod util;
-
-use histogram::Histogram;
-use graph::CallGraph;
-use matcher::{parse_matcher, Matcher, SearchResult};
-use regex::Regex;
-use tree::Tree;
-use util::percent;
-
-trait AddFrames {
-    fn add_frames<I>(&mut self, frames: I)
-    where
-        I: Iterator<Item = String>;
-}
+use perf_focus::color::{self, ColorChoice};
+use perf_focus::config::{self, Value};
+use perf_focus::histogram::Histogram;
+use perf_focus::jsonl::JsonlWriter;
+use perf_focus::graph::{CallGraph, Layout};
+use perf_focus::matcher::{self, parse_matcher, MatchIndex, Matcher, SearchDirection,
+                          SearchResult, StepLimitExceeded};
+use perf_focus::pairs::Pairs;
+use perf_focus::transitions::Transitions;
+use perf_focus::stacks::Stacks;
+use perf_focus::rustc_query;
+use perf_focus::trace::{self, Input, OwnedTrace, ParseStats, TraceArgs};
+use perf_focus::tree::Tree;
+use perf_focus::util::{percent, seconds_str, terminal_width, truncate_name};
+use perf_focus::AddFrames;
+use regex::{Captures, Regex};
+
+#[cfg(test)]
+mod test;
 
 struct Options {
     process_name_filter: Option<regex::Regex>,
-    from_stdin: bool,
+    process_name_exact: Option<String>,
+    /// Process names for samples whose process has no name, which
+    /// perf shows as `:<pid>`.
+    pid_names: HashMap<u32, String>,
+    cpu: Option<u32>,
+    root_filter: Option<regex::Regex>,
+    leaf_filter: Option<regex::Regex>,
+    input: Input,
     rustc_query: bool,
+    rustc_query_context: bool,
+    include_idle: bool,
+    keep_address: bool,
+    normalize_addresses: bool,
     matcher: Option<Matcher>,
+    exact: bool,
     print_match: bool,
     script_match: bool,
     script_miss: bool,
     graph_file: Option<String>,
+    jsonl_file: Option<String>,
     graph_mode: Option<GraphMode>,
+    graph_keep_orphans: bool,
+    graph_count_repeats: bool,
+    graph_rank: bool,
+    graph_synthetic_root: bool,
+    graph_undirected: bool,
+    graph_degrees: bool,
+    graph_min_percent: usize,
+    graph_highlight: Option<Regex>,
+    graph_stride: usize,
+    graph_flush_every: Option<usize>,
+    graph_layout: Option<Layout>,
+    graph_size: Option<(f64, f64)>,
+    graph_relative: Denominator,
+    tree_relative: Option<Denominator>,
     hist_mode: Option<GraphMode>,
+    unique_stacks: bool,
+    hist_pairs: bool,
+    pairs_context: usize,
+    transitions: bool,
+    callers_of: bool,
+    miss_hist: bool,
+    hist_regex_group: Option<String>,
+    list_functions: bool,
+    top_adjacent: bool,
+    match_depth_stats: bool,
+    coverage: bool,
+    gaps: bool,
+    anchor: Option<Anchor>,
+    search: SearchDirection,
+    match_index: MatchIndex,
+    max_search_steps: usize,
     top_n: usize,
+    /// Whether `top_n` came from `--top-n` rather than the default.
+    top_n_given: bool,
+    /// The floor set by `--min-percent`; `graph_min_percent` and
+    /// `tree_min_percent` default to it.
+    min_percent: usize,
+    /// Names in histograms and trees are shortened to this width.
+    max_name_width: Option<usize>,
     tree_mode: Option<GraphMode>,
     tree_max_depth: usize,
     tree_min_percent: usize,
+    tree_other: bool,
     tree_leaf: bool,
+    /// Weight the tree by the period of each sample rather than
+    /// counting samples.
+    tree_cost: bool,
+    /// Build a separate tree for each thread.
+    tree_by_tid: bool,
+    /// Print only the hottest path of the tree.
+    hot_path: bool,
     rename: Vec<(regex::Regex, String)>,
+    dedup: Dedup,
+    trim_generics: bool,
     relative: bool,
+    color: ColorChoice,
+    summary_only: bool,
+    oneline: bool,
+    frames_only: bool,
+    dump_parsed: bool,
+    frequency: Option<u64>,
+    max_samples: Option<usize>,
+    /// The flag that set `max_samples` (`--max-samples` or `--head`),
+    /// for messages.
+    max_samples_flag: &'static str,
+    /// Only handle the last this many samples that pass the process
+    /// and CPU filters.
+    tail: Option<usize>,
+    progress: bool,
+    quiet: bool,
+    verbose: bool,
 }
 
 fn usage(msg: &str) -> ! {
     println!("Usage: perf-focus [options] [matcher]");
-    println!("");
+    println!();
     println!("Options:");
     println!(" --process-name <regex>   filter samples by process name");
+    println!(" --process-name-exact <name>");
+    println!("                          filter samples by exact process name");
+    println!(" --pid-name <pid> <name>  name the process of samples shown as `:<pid>`");
+    println!("                          (no process name) <name>, for the filters");
+    println!("                          and reports; may be given more than once");
+    println!(" --cpu <n>                only consider samples taken on CPU <n>; needs the");
+    println!("                          CPU column in the dump (`perf script -F +cpu`)");
+    println!(" --root <regex>           only match samples whose outermost fn matches");
+    println!(" --leaf <regex>           only match samples whose innermost fn matches");
+    println!(" --leaf-regex <regex>     same as --leaf");
     println!(" --rustc-query            convert from raw stacks to rustc query stacks");
+    println!(" --rustc-query-context    like --rustc-query, but label each query with the");
+    println!("                          non-query fn that forced it, as `query <- fn`");
+    println!(" --include-idle           treat samples with no stack, or only `[unknown]`");
+    println!("                          frames, as a single `[idle]` frame instead of");
+    println!("                          leaving them unmatched");
+    println!(" --keep-address           name frames that have no symbol by their address,");
+    println!("                          e.g. `0x7f82e6dee179`, rather than `[unknown]`");
+    println!(" --normalize-addresses    like --keep-address, but keep only the file and");
+    println!("                          the offset within the page, e.g. `libfoo.so+0x179`,");
+    println!("                          so addresses match across runs despite ASLR");
     println!(" --print-match            dump samples that match and show why they matched");
     println!(" --print-miss             dump samples that do not match");
     println!(" --script-match           dump samples that match in `perf script` format");
     println!(" --script-miss            dump samples that do not match in `perf script` format");
     println!(" --relative               make percentages relative to number of matches");
+    println!(" --anchor <needle|start|end>");
+    println!("                          where to split the stack for caller/callee modes:");
+    println!("                          the match start, the match end, or the frame");
+    println!("                          found by the last `..` in the matcher");
+    println!(" --exact                  the matcher must cover the whole stack, from the");
+    println!("                          outermost fn to the innermost, with no `..`");
+    println!(" --search <root-first|leaf-first>");
+    println!("                          when the matcher matches more than once in a");
+    println!("                          sample, use the outermost (default) or innermost match");
+    println!(" --match-index <n|last>   use the <n>th match in the --search order, or the");
+    println!("                          last; samples with fewer matches do not match");
+    println!(" --max-search-steps <n>   give up on matching a sample after <n> steps and");
+    println!("                          count it as not matching (guards against queries");
+    println!("                          that are very slow on deep stacks)");
+    println!(" --match-budget <n>       same as --max-search-steps");
+    println!(" --frequency <hz>         sampling frequency (as in `perf record -F`), used");
+    println!("                          to estimate matched and total time in the summary");
     println!(" --top-n <n>              limit graph or histograms to the top <n> fns");
+    println!("                          (default: $PERF_FOCUS_TOP_N, or else 22)");
+    println!(" --max-name-width <n>     shorten fn names in histograms and trees to <n>");
+    println!("                          characters, keeping the end (default: the");
+    println!("                          terminal's $COLUMNS, if stdout is one; 0: never)");
+    println!(" --jsonl-out <file>       stream matching samples to <file> as JSON lines");
     println!(" --graph <file>           dumps a callgraph of matching samples into <file>");
     println!(" --graph-callers <file>   as above, but only dumps callers of the matcher");
     println!(" --graph-callees <file>   as above, but only dumps callees of the matcher");
+    println!(" --graph-relative <matched|all>");
+    println!("                          make graph percentages relative to the matching");
+    println!("                          samples (the default) or to all samples");
+    println!(" --graph-highlight <regex>");
+    println!("                          draw graph nodes whose names match as red ellipses");
+    println!(" --min-percent <n>        hide histogram rows, tree nodes and graph fns below");
+    println!("                          N% (--graph-min-percent or --tree-min-percent win)");
+    println!(" --graph-min-percent <n>  limit graph to fns with at least N% of samples;");
+    println!("                          combined with --top-n, fns must pass both");
+    println!(" --graph-layout <dot|fdp|sfdp>");
+    println!("                          ask Graphviz for this layout engine");
+    println!(" --graph-size <w,h>       ask Graphviz to shrink the graph to fit in");
+    println!("                          <w> by <h> inches");
+    println!(" --graph-stride <n>       keep only every <n>th frame fed to the graph, so");
+    println!("                          edges span <n> calls (a coarse approximation)");
+    println!(" --graph-degrees          also print the fns in the graph with the most");
+    println!("                          distinct callees (out) and callers (in)");
+    println!(" --graph-keep-orphans     keep top-N fns in the graph even if all their");
+    println!("                          edges were pruned");
+    println!(" --graph-count-repeats    count an edge each time it occurs in a sample,");
+    println!("                          rather than once per sample");
+    println!(" --graph-rank             prefix each fn in the graph with its rank (#1 is");
+    println!("                          the most common)");
+    println!(" --graph-synthetic-root   start every sample in the graph at a shared");
+    println!("                          `<root>` node");
+    println!(" --graph-undirected       merge `a -> b` and `b -> a` into one edge `a -- b`");
+    println!("                          and write an undirected graph");
+    println!(" --graph-flush-every <n>  rewrite the graph file every <n> matches, so an");
+    println!("                          interrupted run still leaves a graph behind");
     println!(" --hist                   prints out the most common fns");
     println!(" --hist-callers           prints out the most common fns amongst the callers");
     println!(" --hist-callees           prints out the most common fns amongst the callees");
+    println!(" --hist-pairs             prints the most common (caller, leaf fn) pairs");
+    println!(" --pairs-context <n>      with --hist-pairs, keep <n> callers of each");
+    println!("                          leaf fn rather than 1");
+    println!(" --transitions            prints the most common (caller, callee) pairs");
+    println!("                          anywhere in the stack");
+    println!(" --callers-of             prints the most common direct callers of the");
+    println!("                          first frame of the match");
+    println!(" --miss-hist              prints the most common leaf fns of the samples");
+    println!("                          that did not match");
+    println!(" --hist-regex-group <name>");
+    println!("                          prints the most common values of the capture");
+    println!("                          group <name> of the matcher's regexes");
+    println!(" --top-adjacent           prints the most common direct caller and callee");
+    println!("                          of the match");
+    println!(" --list-functions         prints every distinct fn in the matching samples,");
+    println!("                          sorted, after --rename");
+    println!(" --gaps                   prints the min/max/mean time between one matching");
+    println!("                          sample and the next, and a histogram of these gaps");
+    println!(" --coverage               adds to the summary the share of all the frames");
+    println!("                          of all the samples that lie within a match");
+    println!(" --match-depth-stats      prints the min/max/mean/median depth (frames from");
+    println!("                          the outermost) at which matches start");
+    println!(" --unique-stacks          counts distinct stacks and prints the most common,");
+    println!("                          and how many cover 50% and 90% of the matches");
+    println!(" --stacks-equal           same as --unique-stacks");
     println!(" --tree                   prints out a tree of the samples");
     println!(" --tree-callers           prints out an (inverted) tree of the callers");
     println!(" --tree-callees           prints out a tree of the callees");
-    println!(" --tree-max-depth <n>     limit tree to the outermost N functions");
+    println!(" --tree-butterfly         prints out one tree with the match at the top, an");
+    println!("                          (inverted) tree of its callers and a tree of its");
+    println!("                          callees below it; each side adds up to the match");
+    println!(" --tree-max-depth <n>     limit tree to fns at most N calls below the top");
+    println!("                          level (0 shows only the top level)");
     println!(" --tree-min-percent <n>   limit tree to fns whose total time exceeds N%");
+    println!(" --tree-other             collect the time of fns hidden by --tree-max-depth");
+    println!("                          or --tree-min-percent in a final `(other)` node");
+    println!(" --tree-relative <matched|all>");
+    println!("                          make tree percentages relative to the matching");
+    println!("                          samples or to all samples (by default, as for");
+    println!("                          --relative)");
     println!(" --tree-leaf              only print nodes that have 'self hits'");
+    println!(" --tree-by-tid            print one tree per thread id, with percentages");
+    println!("                          of that thread's samples");
+    println!(" --hot-path               print only the hottest path of the tree, taking");
+    println!("                          the child with the highest total at each level");
+    println!(" --tree-cost              weight the tree by the period of each sample");
+    println!("                          (`perf script -F +period`), e.g. cache misses");
+    println!("                          or bytes, rather than counting samples");
+    println!(" --trim-generics          strip generic arguments (`<...>`) from fn names");
+    println!("                          in graphs/histograms, before any --rename");
+    println!(" --dedup <pre-rename|post-rename|none>");
+    println!("                          count a fn that appears more than once in a sample");
+    println!("                          once per distinct name before renaming, once per");
+    println!("                          name after renaming (the default), or every time,");
+    println!("                          in graphs/histograms");
     println!(" --rename <match> <repl>  post-process names for graphs/histograms;");
     println!("                          see `replace_all` in Regex doc [1] for instructions.");
     println!("                          May be specified more than once.");
     println!("                          [1]: http://doc.rust-lang.org/regex/regex/index.html");
+    println!(" --rename-from-file <file>");
+    println!("                          read rename rules from <file>, one");
+    println!("                          `<match><TAB><repl>` per line; rules from all");
+    println!("                          --rename flags and files apply in command-line order");
+    println!(" --max-samples <n>        stop reading after <n> samples (matching or not)");
+    println!("                          have passed the process and CPU filters");
+    println!(" --head <n>               same as --max-samples");
+    println!(" --tail <n>               only use the last <n> samples that pass the");
+    println!("                          process and CPU filters (they are kept in memory");
+    println!("                          until the input ends); not with --head");
+    println!(" --frames-only            print each (matching) sample as one line of");
+    println!("                          `;`-separated fns, outermost first, after");
+    println!("                          --rename, and nothing else; the matcher is optional");
+    println!(" --dump-parsed            print how each sample was parsed (process, tid,");
+    println!("                          cpu, timestamp, event, period and stack), then exit;");
+    println!("                          no matching is done");
+    println!(" --summary-only           only count matches; rejects any option that");
+    println!("                          needs per-sample output or aggregation");
+    println!(" --oneline                print only `<input>: matches=N total=M pct=P%`");
+    println!("                          (for scripts); implies --quiet, and rejects the");
+    println!("                          same options as --summary-only");
+    println!(" --quiet                  don't print warnings (such as the number of");
+    println!("                          lines that were not valid UTF-8) to stderr");
+    println!(" --verbose                at the end, report on stderr how many samples");
+    println!("                          each filter dropped, how many matched, and what");
+    println!("                          was odd about the input");
+    println!(" --progress               show progress on stderr: the percentage read when");
+    println!("                          stdin is a file, else a running sample count");
+    println!(" --color <auto|always|never>");
+    println!("                          colorize output; `auto` (the default) colors only");
+    println!("                          when stdout is a terminal and NO_COLOR is unset");
+    println!(" --config <file>          read options from a TOML file, one `flag = value`");
+    println!("                          per line (e.g. `tree-max-depth = 3`, `hist = true`,");
+    println!("                          `matcher = '{{a}}'`); flags on the command line win");
+    println!(" @<file>                  read more arguments from <file>, separated by");
+    println!("                          whitespace; quote (with ' or \") to keep spaces");
     println!(" --from-stdin             read samples from stdin;");
     println!("                          when not using this option,");
     println!("                          we will execute `perf script`");
-    println!("");
+    println!(" --input <file>           read samples from <file>, saved `perf script`");
+    println!("                          output, rather than from stdin or `perf script`;");
+    println!("                          repeat to combine several files into one report");
+    println!();
     println!("{}", msg);
     exit(1)
 }
@@ -100,35 +333,350 @@ enum GraphMode {
     All,
     Caller,
     Callee,
+    /// Callers and callees together, around the match; trees only.
+    Butterfly,
+}
+
+/// The denominator for the percentages in the call graph or tree
+/// (`--graph-relative`, `--tree-relative`). These only ever contain
+/// matching samples, so `Matched` makes their percentages add up.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Denominator {
+    Matched,
+    All,
+}
+
+/// When the histogram and graph deduplicate the fns in a sample, so
+/// that a fn that appears more than once is counted once. This matters
+/// when `--rename` maps distinct fns to the same name: `PreRename`
+/// counts each of them, `PostRename` counts the shared name once.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Dedup {
+    PreRename,
+    PostRename,
+    Off,
+}
+
+/// Selects the boundary used to split a matching stack into callers
+/// and callees. Without an explicit anchor, callers are the frames
+/// before the match and callees the frames after it, so the matched
+/// frames themselves appear in neither.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Anchor {
+    /// Split at the frame found by the last `..` skip in the matcher.
+    Needle,
+
+    /// Split at the first matching frame.
+    Start,
+
+    /// Split just past the last matching frame.
+    End,
+}
+
+const DEFAULT_TOP_N: usize = 22;
+
+/// Environment variable that overrides `DEFAULT_TOP_N`.
+const TOP_N_VAR: &str = "PERF_FOCUS_TOP_N";
+
+/// The `--top-n` to use when none is given: `PERF_FOCUS_TOP_N` if it
+/// is set, else 22. A value that isn't a number is ignored, with a
+/// warning unless `quiet`.
+fn default_top_n(quiet: bool) -> usize {
+    let value = match env::var(TOP_N_VAR) {
+        Ok(value) => value,
+        Err(_) => return DEFAULT_TOP_N,
+    };
+    match usize::from_str(value.trim()) {
+        Ok(n) => n,
+        Err(_) => {
+            if !quiet {
+                eprintln!(
+                    "Warning: ignoring {}={:?}, which is not a number; using {}",
+                    TOP_N_VAR, value, DEFAULT_TOP_N
+                );
+            }
+            DEFAULT_TOP_N
+        }
+    }
 }
 
 fn parse_options() -> Options {
-    let mut args = env::args().skip(1);
+    let options = parse_args(env::args().skip(1));
+    match validate(&options) {
+        Ok(warnings) => {
+            if !options.quiet {
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+            }
+        }
+        Err(msg) => usage(&format!("Error: {}", msg)),
+    }
+    options
+}
+
+/// Checks that the options, each of which parsed fine on its own,
+/// make sense together. Combinations that can't work are errors; flags
+/// that merely have no effect, because the output they tune was not
+/// asked for, are returned as warnings.
+fn validate(options: &Options) -> Result<Vec<String>, String> {
+    if options.print_match && options.script_match {
+        return Err("--print-match and --script-match cannot be combined".to_string());
+    }
+
+    if options.graph_degrees && options.graph_mode.is_none() {
+        return Err("--graph-degrees needs one of the --graph options".to_string());
+    }
+
+    if options.graph_degrees && options.graph_undirected {
+        return Err("--graph-degrees cannot be combined with --graph-undirected".to_string());
+    }
+
+    if options.summary_only && !counts_only(options) {
+        return Err(
+            "--summary-only cannot be combined with output or aggregation options".to_string(),
+        );
+    }
+
+    if options.hot_path && (options.tree_leaf || options.tree_other) {
+        return Err("--hot-path cannot be combined with --tree-leaf or --tree-other".to_string());
+    }
+
+    if options.tail.is_some() && options.max_samples.is_some() {
+        return Err(format!("--tail cannot be combined with {}", options.max_samples_flag));
+    }
+
+    if options.oneline && !counts_only(options) {
+        return Err(
+            "--oneline cannot be combined with output or aggregation options".to_string(),
+        );
+    }
+
+    if options.frames_only && !only_counts_reported(options) {
+        return Err(
+            "--frames-only cannot be combined with output or aggregation options".to_string(),
+        );
+    }
+
+    if options.max_search_steps == 0 {
+        return Err("--max-search-steps must be at least 1".to_string());
+    }
+
+    if let Some(ref group) = options.hist_regex_group {
+        if !options.matcher.as_ref().is_some_and(|m| m.has_group(group)) {
+            return Err(format!(
+                "--hist-regex-group: no regex in the matcher has a group named `{}`",
+                group
+            ));
+        }
+    }
+
+    Ok(ignored_flags(options)
+        .into_iter()
+        .map(|(flags, why)| format!("ignoring {}: {}", flags, why))
+        .collect())
+}
+
+/// The tuning flags that were given even though the output they tune
+/// was not asked for, grouped by what is missing.
+fn ignored_flags(options: &Options) -> Vec<(String, &'static str)> {
+    let mut ignored = vec![];
+    let mut check = |active: bool, why: &'static str, flags: &[(bool, &str)]| {
+        let given: Vec<&str> =
+            flags.iter().filter(|&&(set, _)| set).map(|&(_, flag)| flag).collect();
+        if !active && !given.is_empty() {
+            ignored.push((given.join(", "), why));
+        }
+    };
+
+    check(options.graph_mode.is_some(), "no --graph option given", &[
+        (options.graph_keep_orphans, "--graph-keep-orphans"),
+        (options.graph_count_repeats, "--graph-count-repeats"),
+        (options.graph_rank, "--graph-rank"),
+        (options.graph_synthetic_root, "--graph-synthetic-root"),
+        (options.graph_undirected, "--graph-undirected"),
+        (options.graph_min_percent != options.min_percent, "--graph-min-percent"),
+        (options.graph_highlight.is_some(), "--graph-highlight"),
+        (options.graph_stride != 1, "--graph-stride"),
+        (options.graph_flush_every.is_some(), "--graph-flush-every"),
+        (options.graph_layout.is_some(), "--graph-layout"),
+        (options.graph_size.is_some(), "--graph-size"),
+        (options.graph_relative != Denominator::Matched, "--graph-relative"),
+    ]);
+
+    check(options.tree_mode.is_some(), "no --tree option given", &[
+        (options.tree_max_depth != usize::MAX, "--tree-max-depth"),
+        (options.tree_min_percent != options.min_percent, "--tree-min-percent"),
+        (options.tree_other, "--tree-other"),
+        (options.tree_leaf, "--tree-leaf"),
+        (options.tree_relative.is_some(), "--tree-relative"),
+        (options.tree_cost, "--tree-cost"),
+        (options.tree_by_tid, "--tree-by-tid"),
+        (options.hot_path, "--hot-path"),
+    ]);
+
+    check(options.hist_pairs, "no --hist-pairs option given", &[
+        (options.pairs_context != 1, "--pairs-context"),
+    ]);
+
+    let top_n_output = options.graph_mode.is_some() || options.hist_mode.is_some()
+        || options.hist_pairs || options.transitions || options.callers_of
+        || options.miss_hist || options.hist_regex_group.is_some() || options.unique_stacks;
+    check(top_n_output, "no graph, histogram or list output asked for", &[
+        (options.top_n_given, "--top-n"),
+    ]);
+
+    let floored_output = options.graph_mode.is_some() || options.hist_mode.is_some()
+        || options.tree_mode.is_some() || options.callers_of || options.miss_hist
+        || options.hist_regex_group.is_some();
+    check(floored_output, "no graph, histogram or tree output asked for", &[
+        (options.min_percent != 0, "--min-percent"),
+    ]);
+
+    let modes = [options.graph_mode, options.hist_mode, options.tree_mode];
+    let split_mode = modes.iter().any(|mode| {
+        matches!(*mode, Some(GraphMode::Caller | GraphMode::Callee | GraphMode::Butterfly))
+    });
+    check(split_mode, "no caller or callee output asked for", &[
+        (options.anchor.is_some(), "--anchor"),
+    ]);
+
+    check(options.graph_mode.is_some() || options.hist_mode.is_some(),
+          "no --graph or --hist option given", &[
+        (options.dedup != Dedup::PostRename, "--dedup"),
+    ]);
+
+    ignored
+}
+
+fn parse_args<I>(args: I) -> Options
+where
+    I: Iterator<Item = String>,
+{
+    let args = expand_response_files(args);
+    let args = match take_config(args) {
+        (Some(path), args) => {
+            let entries = check_err("Error reading config file", read_config(&path));
+            let mut merged = check_err(
+                &format!("Error in config file `{}`", path),
+                config_args(entries, &args),
+            );
+            merged.extend(args);
+            merged
+        }
+        (None, args) => args,
+    };
+    let mut options = parse_expanded(args);
+
+    // only now that the config file has had its say is the matcher
+    // final
+    if options.exact {
+        let matcher = match options.matcher.take() {
+            Some(matcher) => matcher,
+            None => usage("Error: --exact needs a matcher"),
+        };
+        match matcher.exact() {
+            Ok(matcher) => options.matcher = Some(matcher),
+            Err(err) => usage(&format!("Error: --exact: {}", err)),
+        }
+    }
+    options
+}
 
+/// Parses arguments after `@file` and `--config` expansion.
+fn parse_expanded(args: Vec<String>) -> Options {
+    let mut args = args.into_iter();
+    let mut top_n = None;
+    let mut min_percent = None;
+    let mut max_name_width = None;
+    let mut graph_min_percent = None;
+    let mut tree_min_percent = None;
     let mut options = Options {
         process_name_filter: None,
+        process_name_exact: None,
+        pid_names: HashMap::new(),
+        cpu: None,
+        root_filter: None,
+        leaf_filter: None,
         relative: false,
-        from_stdin: false,
+        input: Input::PerfScript,
         rustc_query: false,
+        rustc_query_context: false,
+        include_idle: false,
+        keep_address: false,
+        normalize_addresses: false,
         matcher: None,
+        exact: false,
         script_match: false,
         print_match: false,
         script_miss: false,
         graph_file: None,
+        jsonl_file: None,
         graph_mode: None,
+        graph_keep_orphans: false,
+        graph_count_repeats: false,
+        graph_rank: false,
+        graph_synthetic_root: false,
+        graph_undirected: false,
+        graph_degrees: false,
+        graph_min_percent: 0,
+        graph_highlight: None,
+        graph_stride: 1,
+        graph_flush_every: None,
+        graph_layout: None,
+        graph_size: None,
+        graph_relative: Denominator::Matched,
+        tree_relative: None,
         hist_mode: None,
+        unique_stacks: false,
+        hist_pairs: false,
+        pairs_context: 1,
+        transitions: false,
+        callers_of: false,
+        miss_hist: false,
+        hist_regex_group: None,
+        list_functions: false,
+        top_adjacent: false,
+        match_depth_stats: false,
+        coverage: false,
+        gaps: false,
+        anchor: None,
+        search: SearchDirection::RootFirst,
+        match_index: MatchIndex::Nth(1),
+        max_search_steps: usize::MAX,
         tree_mode: None,
-        top_n: 22,
-        tree_max_depth: ::std::usize::MAX,
+        top_n: DEFAULT_TOP_N,
+        top_n_given: false,
+        min_percent: 0,
+        max_name_width: None,
+        tree_max_depth: usize::MAX,
         tree_min_percent: 0,
+        tree_other: false,
         tree_leaf: false,
+        tree_cost: false,
+        tree_by_tid: false,
+        hot_path: false,
         rename: vec![],
+        dedup: Dedup::PostRename,
+        trim_generics: false,
+        color: ColorChoice::Auto,
+        summary_only: false,
+        oneline: false,
+        frames_only: false,
+        dump_parsed: false,
+        frequency: None,
+        max_samples: None,
+        max_samples_flag: "--max-samples",
+        tail: None,
+        progress: false,
+        quiet: false,
+        verbose: false,
     };
 
     while let Some(arg) = args.next() {
         if arg == "--process-name" {
             if options.process_name_filter.is_some() {
-                usage(&format!("Error: process-name already specified"));
+                usage("Error: process-name already specified");
             }
 
             let process_name_arg = expect(args.next());
@@ -143,153 +691,568 @@ fn parse_options() -> Options {
                     ));
                 }
             }
+        } else if arg == "--process-name-exact" {
+            if options.process_name_exact.is_some() {
+                usage("Error: process-name-exact already specified");
+            }
+
+            options.process_name_exact = Some(expect(args.next()));
+        } else if arg == "--pid-name" {
+            let pid = expect(u32::from_str(&expect(args.next())).ok());
+            options.pid_names.insert(pid, expect(args.next()));
+        } else if arg == "--cpu" {
+            let n = expect(u32::from_str(&expect(args.next())).ok());
+            options.cpu = Some(n);
+        } else if arg == "--root" {
+            let r = check_err(
+                "invalid regular expression",
+                Regex::new(&expect(args.next())),
+            );
+            options.root_filter = Some(r);
+        } else if arg == "--leaf" || arg == "--leaf-regex" {
+            let r = check_err(
+                "invalid regular expression",
+                Regex::new(&expect(args.next())),
+            );
+            options.leaf_filter = Some(r);
         } else if arg == "--print-match" {
             options.print_match = true;
         } else if arg == "--script-match" {
             options.script_match = true;
         } else if arg == "--rustc-query" {
             options.rustc_query = true;
+        } else if arg == "--rustc-query-context" {
+            options.rustc_query_context = true;
+        } else if arg == "--include-idle" {
+            options.include_idle = true;
+        } else if arg == "--keep-address" {
+            options.keep_address = true;
+        } else if arg == "--normalize-addresses" {
+            options.normalize_addresses = true;
         } else if arg == "--relative" {
             options.relative = true;
         } else if arg == "--from-stdin" {
-            options.from_stdin = true;
+            set_input(&mut options, Input::Stdin);
+        } else if arg == "--input" {
+            let path = expect(args.next());
+            match options.input {
+                Input::Files(ref mut paths) => paths.push(path),
+                _ => set_input(&mut options, Input::Files(vec![path])),
+            }
         } else if arg == "--print-miss" || arg == "--script-miss" {
             options.script_miss = true;
+        } else if arg == "--jsonl-out" {
+            options.jsonl_file = Some(expect(args.next()));
         } else if arg == "--graph" {
             set_graph(&mut options, args.next(), GraphMode::All);
         } else if arg == "--graph-callers" {
             set_graph(&mut options, args.next(), GraphMode::Caller);
         } else if arg == "--graph-callees" {
             set_graph(&mut options, args.next(), GraphMode::Callee);
+        } else if arg == "--graph-relative" {
+            let relative = expect(args.next());
+            options.graph_relative = match &relative[..] {
+                "matched" => Denominator::Matched,
+                "all" => Denominator::All,
+                _ => usage(&format!("Error: unknown graph denominator: {}", relative)),
+            };
+        } else if arg == "--tree-relative" {
+            let relative = expect(args.next());
+            options.tree_relative = Some(match &relative[..] {
+                "matched" => Denominator::Matched,
+                "all" => Denominator::All,
+                _ => usage(&format!("Error: unknown tree denominator: {}", relative)),
+            });
+        } else if arg == "--graph-layout" {
+            let layout = expect(args.next());
+            options.graph_layout = Some(match &layout[..] {
+                "dot" => Layout::Dot,
+                "fdp" => Layout::Fdp,
+                "sfdp" => Layout::Sfdp,
+                _ => usage(&format!("Error: unknown graph layout: {}", layout)),
+            });
+        } else if arg == "--graph-size" {
+            let size = expect(args.next());
+            match parse_graph_size(&size) {
+                Some(size) => options.graph_size = Some(size),
+                None => usage(&format!("Error: invalid graph size: {}", size)),
+            }
+        } else if arg == "--graph-degrees" {
+            options.graph_degrees = true;
+        } else if arg == "--graph-keep-orphans" {
+            options.graph_keep_orphans = true;
+        } else if arg == "--graph-count-repeats" {
+            options.graph_count_repeats = true;
+        } else if arg == "--graph-rank" {
+            options.graph_rank = true;
+        } else if arg == "--graph-synthetic-root" {
+            options.graph_synthetic_root = true;
+        } else if arg == "--graph-undirected" {
+            options.graph_undirected = true;
+        } else if arg == "--graph-highlight" {
+            let r = check_err(
+                "invalid regular expression",
+                Regex::new(&expect(args.next())),
+            );
+            options.graph_highlight = Some(r);
+        } else if arg == "--graph-stride" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            if n == 0 {
+                usage("Error: --graph-stride must be at least 1");
+            }
+            options.graph_stride = n;
+        } else if arg == "--graph-flush-every" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            if n == 0 {
+                usage("Error: --graph-flush-every must be at least 1");
+            }
+            options.graph_flush_every = Some(n);
+        } else if arg == "--graph-min-percent" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            graph_min_percent = Some(n);
         } else if arg == "--hist" {
             set_hist(&mut options, GraphMode::All);
         } else if arg == "--hist-callers" {
             set_hist(&mut options, GraphMode::Caller);
         } else if arg == "--hist-callees" {
             set_hist(&mut options, GraphMode::Callee);
+        } else if arg == "--hist-pairs" {
+            options.hist_pairs = true;
+        } else if arg == "--pairs-context" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            if n == 0 {
+                usage("Error: pairs-context must be positive");
+            }
+            options.pairs_context = n;
+        } else if arg == "--transitions" {
+            options.transitions = true;
+        } else if arg == "--hist-regex-group" {
+            options.hist_regex_group = Some(expect(args.next()));
+        } else if arg == "--callers-of" {
+            options.callers_of = true;
+        } else if arg == "--miss-hist" {
+            options.miss_hist = true;
+        } else if arg == "--list-functions" {
+            options.list_functions = true;
+        } else if arg == "--top-adjacent" {
+            options.top_adjacent = true;
+        } else if arg == "--match-depth-stats" {
+            options.match_depth_stats = true;
+        } else if arg == "--coverage" {
+            options.coverage = true;
+        } else if arg == "--gaps" {
+            options.gaps = true;
+        } else if arg == "--unique-stacks" || arg == "--stacks-equal" {
+            options.unique_stacks = true;
         } else if arg == "--tree" {
             set_tree(&mut options, GraphMode::All);
         } else if arg == "--tree-callers" {
             set_tree(&mut options, GraphMode::Caller);
         } else if arg == "--tree-callees" {
             set_tree(&mut options, GraphMode::Callee);
+        } else if arg == "--tree-butterfly" {
+            set_tree(&mut options, GraphMode::Butterfly);
+        } else if arg == "--anchor" {
+            let anchor = expect(args.next());
+            options.anchor = Some(match &anchor[..] {
+                "needle" => Anchor::Needle,
+                "start" => Anchor::Start,
+                "end" => Anchor::End,
+                _ => usage(&format!("Error: unknown anchor: {}", anchor)),
+            });
+        } else if arg == "--exact" {
+            options.exact = true;
+        } else if arg == "--search" {
+            let search = expect(args.next());
+            options.search = match &search[..] {
+                "root-first" => SearchDirection::RootFirst,
+                "leaf-first" => SearchDirection::LeafFirst,
+                _ => usage(&format!("Error: unknown search direction: {}", search)),
+            };
+        } else if arg == "--match-index" {
+            let index = expect(args.next());
+            options.match_index = match &index[..] {
+                "last" => MatchIndex::Last,
+                _ => match usize::from_str(&index) {
+                    Ok(n) if n > 0 => MatchIndex::Nth(n),
+                    _ => usage("Error: --match-index must be `last` or at least 1"),
+                },
+            };
+        } else if arg == "--max-search-steps" || arg == "--match-budget" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            options.max_search_steps = n;
         } else if arg == "--top-n" {
-            let n = expect(usize::from_str(&*expect(args.next())).ok());
-            options.top_n = n;
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            top_n = Some(n);
+        } else if arg == "--min-percent" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            min_percent = Some(n);
+        } else if arg == "--max-name-width" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            max_name_width = Some(n);
         } else if arg == "--tree-max-depth" {
-            let n = expect(usize::from_str(&*expect(args.next())).ok());
+            let n = expect(usize::from_str(&expect(args.next())).ok());
             options.tree_max_depth = n;
         } else if arg == "--tree-min-percent" {
-            let n = expect(usize::from_str(&*expect(args.next())).ok());
-            options.tree_min_percent = n;
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            tree_min_percent = Some(n);
+        } else if arg == "--tree-other" {
+            options.tree_other = true;
+        } else if arg == "--tree-by-tid" {
+            options.tree_by_tid = true;
+        } else if arg == "--hot-path" {
+            options.hot_path = true;
+        } else if arg == "--tree-cost" {
+            options.tree_cost = true;
         } else if arg == "--tree-leaf" {
             options.tree_leaf = true;
+        } else if arg == "--frequency" {
+            let hz = expect(u64::from_str(&expect(args.next())).ok());
+            if hz == 0 {
+                usage("Error: frequency must be positive");
+            }
+            options.frequency = Some(hz);
+        } else if arg == "--max-samples" || arg == "--head" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            if n == 0 {
+                usage("Error: max-samples must be positive");
+            }
+            options.max_samples = Some(n);
+            options.max_samples_flag = if arg == "--head" { "--head" } else { "--max-samples" };
+        } else if arg == "--tail" {
+            let n = expect(usize::from_str(&expect(args.next())).ok());
+            if n == 0 {
+                usage("Error: tail must be positive");
+            }
+            options.tail = Some(n);
+        } else if arg == "--summary-only" {
+            options.summary_only = true;
+        } else if arg == "--oneline" {
+            options.oneline = true;
+            options.quiet = true;
+        } else if arg == "--frames-only" {
+            options.frames_only = true;
+        } else if arg == "--dump-parsed" {
+            options.dump_parsed = true;
+        } else if arg == "--quiet" {
+            options.quiet = true;
+        } else if arg == "--verbose" {
+            options.verbose = true;
+        } else if arg == "--progress" {
+            options.progress = true;
+        } else if arg == "--color" {
+            let choice = expect(args.next());
+            options.color = match &choice[..] {
+                "auto" => ColorChoice::Auto,
+                "always" => ColorChoice::Always,
+                "never" => ColorChoice::Never,
+                _ => usage(&format!("Error: unknown color choice: {}", choice)),
+            };
         } else if arg == "--rename" {
             let m = check_err(
                 "invalid regular expression",
-                Regex::new(&*expect(args.next())),
+                Regex::new(&expect(args.next())),
             );
             let r = expect(args.next());
             options.rename.push((m, r));
+        } else if arg == "--dedup" {
+            let dedup = expect(args.next());
+            options.dedup = match &dedup[..] {
+                "pre-rename" => Dedup::PreRename,
+                "post-rename" => Dedup::PostRename,
+                "none" => Dedup::Off,
+                _ => usage(&format!("Error: unknown dedup mode: {}", dedup)),
+            };
+        } else if arg == "--trim-generics" {
+            options.trim_generics = true;
+        } else if arg == "--rename-from-file" {
+            let path = expect(args.next());
+            let rules = check_err("Error reading rename rules", read_rename_file(&path));
+            options.rename.extend(rules);
         } else if arg.starts_with("-") {
             usage(&format!("Error: unknown argument: {}", arg));
         } else if options.matcher.is_some() {
-            usage(&format!("Error: matcher already specified"));
+            usage("Error: matcher already specified");
         } else {
             match parse_matcher(&arg) {
                 Ok(r) => {
                     options.matcher = Some(r);
                 }
                 Err(err) => {
-                    usage(&format!(
-                        "Error: invalid matcher: {} (*) {}",
-                        &arg[..err.offset],
-                        &arg[err.offset..]
-                    ));
+                    usage(&format!("Error: {}", err));
                 }
             }
         }
     }
 
+    options.top_n_given = top_n.is_some();
+    options.top_n = top_n.unwrap_or_else(|| default_top_n(options.quiet));
+    options.min_percent = min_percent.unwrap_or(0);
+    options.max_name_width = match max_name_width {
+        Some(0) => None,
+        Some(n) => Some(n),
+        None => terminal_width(),
+    };
+    options.graph_min_percent = graph_min_percent.unwrap_or(options.min_percent);
+    options.tree_min_percent = tree_min_percent.unwrap_or(options.min_percent);
+
     return options;
 
+    // Different outputs (a graph, a histogram, a tree, ...) can be
+    // combined, but only one mode of each may be given.
+
+    fn set_input(options: &mut Options, input: Input) {
+        if options.input != Input::PerfScript && options.input != input {
+            usage("Error: input already specified");
+        }
+        options.input = input;
+    }
+
     fn set_graph(options: &mut Options, file_name: Option<String>, mode: GraphMode) {
-        check_graph_hist_etc(options);
+        if options.graph_mode.is_some() {
+            usage("Error: graph already specified");
+        }
         options.graph_file = Some(expect(file_name));
         options.graph_mode = Some(mode);
     }
 
     fn set_hist(options: &mut Options, mode: GraphMode) {
-        check_graph_hist_etc(options);
+        if options.hist_mode.is_some() {
+            usage("Error: histogram already specified");
+        }
         options.hist_mode = Some(mode);
     }
 
     fn set_tree(options: &mut Options, mode: GraphMode) {
-        check_graph_hist_etc(options);
-        options.tree_mode = Some(mode);
-    }
-
-    fn check_graph_hist_etc(options: &Options) {
-        if options.graph_mode.is_some() || options.hist_mode.is_some()
-            || options.tree_mode.is_some()
-        {
-            usage("Error: graph, histogram, or tree already specified");
+        if options.tree_mode.is_some() {
+            usage("Error: tree already specified");
         }
+        options.tree_mode = Some(mode);
     }
 }
 
 fn main() {
     let options = parse_options();
+    if options.dump_parsed {
+        dump_parsed(&options);
+        return;
+    }
+
+    let color = options.color.enabled();
+    let counts_only = counts_only(&options);
     let empty_matcher = &matcher::empty_matcher();
     let matcher = options.matcher.as_ref().unwrap_or(empty_matcher);
 
-    let mut graph = CallGraph::new();
-    let mut hist = Histogram::new();
-    let mut tree = Tree::new();
+    let mut graph = call_graph(&options);
+    let mut hist = histogram(&options);
+    hist.set_dedup(options.dedup == Dedup::PostRename);
+    let mut tree = TreeOutput::new(&options);
+    let mut thread_trees = HashMap::new();
+    let mut stacks = Stacks::new();
+    let mut pairs = Pairs::new();
+    pairs.set_context(options.pairs_context);
+    let mut transitions = Transitions::new();
+    let mut callers = histogram(&options);
+    let mut groups = histogram(&options);
+    let mut misses = histogram(&options);
+    let mut depths = DepthStats::default();
+    let mut coverage = Coverage::default();
+    let mut gaps = GapStats::default();
+    let mut functions = BTreeSet::new();
+    let mut adjacent_callers = Histogram::new();
+    let mut adjacent_callees = Histogram::new();
+    let mut jsonl = options.jsonl_file.as_ref().map(|jsonl_file| {
+        let file = check_err(
+            &format!("Error creating `{}`", jsonl_file),
+            File::create(jsonl_file),
+        );
+        JsonlWriter::new(file)
+    });
     let mut matches = 0;
     let mut not_matches = 0;
-    let result = trace::each_trace(options.from_stdin, |mut args| {
-        if let Some(ref regex) = options.process_name_filter {
-            if !regex.is_match(args.process_name) {
-                return;
-            }
+    let mut gave_up = 0;
+    // Handles a sample that passed the process and CPU filters.
+    let mut process = |mut args: TraceArgs, filtered: &mut Filtered| {
+        if options.keep_address || options.normalize_addresses {
+            trace::keep_addresses(&mut args, options.normalize_addresses);
         }
 
-        if options.rustc_query {
+        if options.rustc_query_context {
+            rustc_query::to_query_context_stack(&mut args);
+        } else if options.rustc_query {
             rustc_query::to_query_stack(&mut args);
         }
 
-        if let Some(result) = matcher.search_trace(&args.stack) {
+        if options.include_idle {
+            bucket_idle(&mut args.stack);
+        }
+
+        // samples without a period count once
+        let tree_weight = match args.cost {
+            Some(cost) if options.tree_cost => cost as usize,
+            _ => 1,
+        };
+
+        let result = if filtered.root_and_leaf(&options, &args.stack) {
+            search_sample(&options, matcher, &args.stack).unwrap_or_else(|StepLimitExceeded| {
+                gave_up += 1;
+                None
+            })
+        } else {
+            None
+        };
+
+        if options.coverage {
+            coverage.add(args.stack.len(), result);
+        }
+
+        if let Some(result) = result {
             matches += 1;
 
+            if counts_only {
+                return;
+            }
+
+            if options.frames_only {
+                println!("{}", folded(&options, &args.stack));
+                return;
+            }
+
+            if let Some(ref mut jsonl) = jsonl {
+                check_err("Error writing JSON lines", jsonl.write_sample(&args));
+            }
+
             if options.print_match {
-                print_trace(&args.header, Some(result));
+                print_trace(args.header, selected_lines(&args, result), color);
             } else if options.script_match {
-                print_trace(&args.header, None);
+                print_trace(args.header, None, color);
             }
 
+            let stack = &args.stack;
+            let dedup_first = options.dedup == Dedup::PreRename;
             if let Some(mode) = options.hist_mode {
-                add_frames(&matcher, mode, args.stack, result, &options, &mut hist);
-            } else if let Some(mode) = options.graph_mode {
-                add_frames(&matcher, mode, args.stack, result, &options, &mut graph);
-            } else if let Some(mode) = options.tree_mode {
-                add_frames(&matcher, mode, args.stack, result, &options, &mut tree);
+                add_frames(matcher, mode, stack, result, &options, &mut hist, dedup_first);
+            }
+            if let Some(mode) = options.graph_mode {
+                let mut rooted = Rooted { acc: &mut graph, enabled: options.graph_synthetic_root };
+                let mut strided = Strided { acc: &mut rooted, stride: options.graph_stride };
+                add_frames(matcher, mode, stack, result, &options, &mut strided, dedup_first);
+                if options.graph_flush_every.is_some_and(|n| matches % n == 0) {
+                    flush_graph(&options, &mut graph, matches, not_matches);
+                }
+            }
+            if let Some(mode) = options.tree_mode {
+                let output = tree_output(&options, &mut tree, &mut thread_trees, args.tid);
+                output.matched += tree_weight;
+                let tree = &mut output.tree;
+                match mode {
+                    GraphMode::Butterfly => {
+                        add_butterfly(matcher, stack, result, &options, tree, tree_weight);
+                    }
+                    _ => {
+                        let mut weighted = Weighted { tree, weight: tree_weight };
+                        add_frames(matcher, mode, stack, result, &options, &mut weighted, false)
+                    }
+                }
+            }
+            if options.unique_stacks {
+                add_frames(matcher, GraphMode::All, stack, result, &options, &mut stacks, false);
+            }
+            if options.hist_pairs {
+                add_frames(matcher, GraphMode::All, stack, result, &options, &mut pairs, false);
+            }
+            if options.transitions {
+                let acc = &mut transitions;
+                add_frames(matcher, GraphMode::All, stack, result, &options, acc, false);
+            }
+            if options.match_depth_stats {
+                depths.add(result.first_matching_frame);
+            }
+            if options.gaps {
+                gaps.add(args.timestamp);
+            }
+            if options.callers_of {
+                let caller = caller_of(&args.stack, result).to_string();
+                callers.add(rename_frame(&options, caller), 1);
+            }
+            if let Some(ref group) = options.hist_regex_group {
+                groups.add(group_value(matcher, &args.stack, result, group), 1);
+            }
+            if options.list_functions {
+                add_functions(&options, &args.stack, &mut functions);
+            }
+            if options.top_adjacent {
+                let caller = caller_of(&args.stack, result).to_string();
+                adjacent_callers.add(rename_frame(&options, caller), 1);
+                let callee = callee_of(&args.stack, result).to_string();
+                adjacent_callees.add(rename_frame(&options, callee), 1);
             }
         } else {
             not_matches += 1;
 
+            if options.tree_mode.is_some() {
+                tree_output(&options, &mut tree, &mut thread_trees, args.tid).missed += tree_weight;
+            }
+
             if options.script_miss {
-                print_trace(&args.header, None);
+                print_trace(args.header, None, color);
+            }
+
+            if options.miss_hist {
+                misses.add(rename_frame(&options, leaf_of(&args.stack).to_string()), 1);
             }
         }
+    };
+    let mut feed = Feed::new(&options);
+    let result = trace::each_trace(&options.input, options.progress, |args| {
+        feed.sample(&options, args, &mut process)
     });
+    if result.is_ok() {
+        feed.finish(&mut process);
+    }
 
-    match result {
-        Ok(()) => {}
+    let stats = match result {
+        Ok(stats) => stats,
         Err(err) => {
             eprintln!("I/O error encountered: {:?}", err);
             exit(1);
         }
+    };
+
+    if let Some(ref mut jsonl) = jsonl {
+        check_err("Error writing JSON lines", jsonl.flush());
+    }
+
+    if gave_up > 0 && !options.quiet {
+        eprintln!(
+            "Warning: {} samples exceeded --max-search-steps and were counted as not matching",
+            gave_up
+        );
+    }
+
+    if feed.stopped_early && !options.quiet {
+        eprintln!(
+            "Warning: stopped after {} samples ({}); the output may be partial",
+            matches + not_matches,
+            options.max_samples_flag
+        );
+    }
+
+    if stats.lossy_lines > 0 && !options.quiet {
+        eprintln!(
+            "Warning: {} lines were not valid UTF-8; bad bytes were replaced with U+FFFD",
+            stats.lossy_lines
+        );
+    }
+
+    if options.verbose {
+        for (label, count) in feed.filtered.report(matches, not_matches, gave_up, &stats) {
+            eprintln!("{:<26} : {}", label, count);
+        }
+    }
+
+    if options.frames_only {
+        return;
     }
 
     let total = if options.relative {
@@ -298,124 +1261,1528 @@ fn main() {
         matches + not_matches
     };
 
-    graph.set_total(total, options.top_n);
-
-    if let Some(ref graph_file) = options.graph_file {
-        check_err(
-            &format!("Error printing graph to `{}`", graph_file),
-            dump_graph(&graph, graph_file),
-        );
+    if options.oneline {
+        println!("{}", oneline(&options, matches, total));
+        return;
     }
 
+    flush_graph(&options, &mut graph, matches, not_matches);
+
     println!("Matcher    : {:?}", matcher);
     println!("Matches    : {}", matches);
     println!("Not Matches: {}", not_matches);
+    if options.max_search_steps != usize::MAX {
+        println!("Over Budget: {}", gave_up);
+    }
     println!("Percentage : {}%", percent(matches, total));
+    if options.coverage {
+        match coverage.fraction() {
+            Some(fraction) => println!("Coverage   : {:.1}% of frames", fraction * 100.0),
+            None => println!("Coverage   : (no frames)"),
+        }
+    }
+    if let Some(hz) = options.frequency {
+        println!("Match Time : {}", seconds_str(matches, hz));
+        println!("Total Time : {}", seconds_str(matches + not_matches, hz));
+    }
+
+    if options.graph_degrees {
+        println!();
+        println!("Graph Degrees");
+        println!("{:>5} {:>5}  fn", "out", "in");
+        for (name, out, inn) in graph.degree_report().into_iter().take(options.top_n) {
+            println!("{:>5} {:>5}  {}", out, inn, name);
+        }
+    }
 
     if options.hist_mode.is_some() {
-        println!("");
+        println!();
         println!("Histogram");
-        hist.dump(total, options.top_n);
+        check_err(
+            "Error printing histogram",
+            hist.dump(&mut io::stdout(), total, options.top_n, color),
+        );
+    }
+
+    if options.hist_pairs {
+        println!();
+        println!("Caller -> Leaf");
+        check_err(
+            "Error printing pairs",
+            pairs.dump(&mut io::stdout(), total, options.top_n),
+        );
+    }
+
+    if options.transitions {
+        println!();
+        println!("Caller -> Callee");
+        check_err(
+            "Error printing transitions",
+            transitions.dump(&mut io::stdout(), total, options.top_n),
+        );
+    }
+
+    if options.callers_of {
+        println!();
+        println!("Callers");
+        check_err(
+            "Error printing callers",
+            callers.dump(&mut io::stdout(), matches, options.top_n, color),
+        );
+    }
+
+    if options.miss_hist {
+        println!();
+        println!("Missed Leaf Fns");
+        check_err(
+            "Error printing missed leaf fns",
+            misses.dump(&mut io::stdout(), not_matches, options.top_n, color),
+        );
+    }
+
+    if let Some(ref group) = options.hist_regex_group {
+        println!();
+        println!("Group `{}`", group);
+        check_err(
+            "Error printing groups",
+            groups.dump(&mut io::stdout(), matches, options.top_n, color),
+        );
+    }
+
+    if options.top_adjacent {
+        println!();
+        println!("Adjacent");
+        for (label, hist) in &[("Caller", &adjacent_callers), ("Callee", &adjacent_callees)] {
+            match hist.top() {
+                Some((name, count)) => {
+                    println!("{:<11}: {}% {}", label, percent(count, matches), name)
+                }
+                None => println!("{:<11}: (no matches)", label),
+            }
+        }
+    }
+
+    if options.list_functions {
+        println!();
+        println!("Functions");
+        for function in &functions {
+            println!("{}", function);
+        }
+    }
+
+    if options.gaps {
+        println!();
+        println!("Gaps");
+        gaps.dump();
+    }
+
+    if options.match_depth_stats {
+        println!();
+        println!("Match Depth");
+        depths.dump();
+    }
+
+    if options.unique_stacks {
+        println!();
+        println!("Unique Stacks");
+        stacks.dump(total, options.top_n);
+    }
+
+    let tree_name = if options.hot_path { "Hot Path" } else { "Tree" };
+    if options.tree_mode.is_some() && options.tree_by_tid {
+        let written =
+            write_thread_trees(&mut io::stdout(), &options, &mut thread_trees, tree_name, color);
+        check_err("Error printing tree", written);
+    } else if options.tree_mode.is_some() {
+        let written = tree.write(&mut io::stdout(), &options, tree_name, color);
+        check_err("Error printing tree", written);
+    }
+}
+
+/// A tree being built, with the total weight (1 per sample, or its
+/// period with `--tree-cost`) of the samples that matched and that did
+/// not, which `--tree-relative` chooses between.
+struct TreeOutput {
+    tree: Tree,
+    matched: usize,
+    missed: usize,
+}
+
+impl TreeOutput {
+    fn new(options: &Options) -> TreeOutput {
+        let mut tree = Tree::new();
+        tree.set_max_name_width(options.max_name_width);
+        TreeOutput { tree, matched: 0, missed: 0 }
     }
 
-    if options.tree_mode.is_some() {
-        println!("");
-        println!("Tree");
+    /// Prints the tree under the header `label`, after applying the
+    /// `--tree-*` options. With `--hot-path`, prints only its hottest
+    /// path, as far as `--tree-max-depth` and `--tree-min-percent` allow.
+    fn write(
+        &mut self,
+        out: &mut dyn io::Write,
+        options: &Options,
+        label: &str,
+        color: bool,
+    ) -> io::Result<()> {
+        let total = tree_total(options, self.matched, self.missed);
+        writeln!(out)?;
+        if options.tree_cost {
+            writeln!(out, "{} (by sample period, of {})", label, total)?;
+        } else {
+            writeln!(out, "{}", label)?;
+        }
+        let tree = &mut self.tree;
+        if options.hot_path {
+            for (depth, (label, hits)) in tree.hottest_path().into_iter().enumerate() {
+                let total_percent = percent(hits, total);
+                if depth > options.tree_max_depth
+                    || (total_percent as usize) < options.tree_min_percent
+                {
+                    break;
+                }
+                let label = match options.max_name_width {
+                    Some(width) => truncate_name(&label, width),
+                    None => label,
+                };
+                let total_text = format!("{}% total", total_percent);
+                writeln!(out, "{}| {} ({})", ": ".repeat(depth), label,
+                         color::percent(color, total_percent, &total_text))?;
+            }
+            return Ok(());
+        }
         tree.sort();
-        if options.tree_leaf {
+        if options.tree_leaf || options.tree_other {
+            tree.set_other_bucket(options.tree_other);
             tree.rollup(total, options.tree_max_depth, options.tree_min_percent);
+        }
+        if options.tree_leaf {
             tree.only_leaves();
         }
-        tree.dump(total, options.tree_max_depth, options.tree_min_percent);
+        tree.dump(out, total, options.tree_max_depth, options.tree_min_percent, color)
     }
 }
 
-fn add_frames<F>(
-    matcher: &Matcher,
-    mode: GraphMode,
-    frames: Vec<String>,
-    result: SearchResult,
+/// Writes the tree of each thread (for `--tree-by-tid`), in order of
+/// thread id. Threads none of whose samples matched are left out.
+fn write_thread_trees(
+    out: &mut dyn io::Write,
     options: &Options,
-    acc: &mut F,
-) where
-    F: AddFrames,
-{
-    match mode {
-        GraphMode::All => {
-            acc.add_frames(frames.into_iter().map(|s| rename_frame(options, s)));
-        }
-        GraphMode::Caller => {
-            let caller_frames: Vec<_> = frames
-                .into_iter()
-                .take(result.first_matching_frame)
-                .map(|s| rename_frame(options, s))
-                .chain(vec![format!("matched `{:?}`", matcher)].into_iter())
-                .collect();
-            acc.add_frames(caller_frames.into_iter().rev());
-        }
-        GraphMode::Callee => {
-            acc.add_frames(
-                vec![format!("matched `{:?}`", matcher)].into_iter().chain(
-                    frames
-                        .into_iter()
-                        .skip(result.first_callee_frame)
-                        .map(|s| rename_frame(options, s)),
-                ),
-            );
+    thread_trees: &mut HashMap<Option<u32>, TreeOutput>,
+    tree_name: &str,
+    color: bool,
+) -> io::Result<()> {
+    let mut tids: Vec<Option<u32>> = thread_trees.keys().cloned().collect();
+    tids.sort();
+    for tid in tids {
+        let output = thread_trees.get_mut(&tid).unwrap();
+        if output.matched == 0 {
+            continue;
         }
+        let label = match tid {
+            Some(tid) => format!("{} (tid {})", tree_name, tid),
+            None => format!("{} (no tid)", tree_name),
+        };
+        output.write(out, options, &label, color)?;
     }
+    Ok(())
 }
 
-fn rename_frame(options: &Options, frame: String) -> String {
-    let mut frame = frame;
-    for &(ref regex, ref repl) in &options.rename {
-        let tmp = regex.replace_all(&frame, &repl[..]);
-        frame = tmp;
+/// The tree that a sample of thread `tid` goes to: with `--tree-by-tid`
+/// the thread's own, otherwise the single `tree`.
+fn tree_output<'a>(
+    options: &Options,
+    tree: &'a mut TreeOutput,
+    thread_trees: &'a mut HashMap<Option<u32>, TreeOutput>,
+    tid: Option<u32>,
+) -> &'a mut TreeOutput {
+    if options.tree_by_tid {
+        thread_trees.entry(tid).or_insert_with(|| TreeOutput::new(options))
+    } else {
+        tree
     }
-    frame
 }
 
-fn print_trace(header: &[String], selected: Option<SearchResult>) {
-    if let Some(SearchResult {
-        first_matching_frame,
-        first_callee_frame,
-    }) = selected
-    {
-        // The search result is expressed counting backwards from
-        // **top** element in the stack, which is last in this list.
-        //
-        // Matcher: {a}..{b}
-        // against Frames:
-        //     z
-        //     b
-        //     y
-        //     a
-        //     x
-        // yields `{ first_callee_frame: 4, first_matching_frame: 1 }`
-        // we want to select from index `1..5`.
-        let selection_start = header.len() - first_callee_frame;
-        let selection_end = header.len() - first_matching_frame;
+/// The `--dump-parsed` mode: prints every sample of the input as the
+/// parser saw it, before any filter or rewrite.
+fn dump_parsed(options: &Options) {
+    let mut index = 0;
+    let result = trace::each_trace(&options.input, options.progress, |args| {
+        index += 1;
+        print!("{}", parsed_sample(index, &args));
+    });
+    if let Err(err) = result {
+        eprintln!("I/O error encountered: {:?}", err);
+        exit(1);
+    }
+}
 
-        println!("{}", header[0]);
-        for string in &header[1..selection_start] {
-            println!("  {}", string);
-        }
-        for string in &header[selection_start..selection_end] {
-            println!("| {}", string);
-        }
-        for string in &header[selection_end..] {
-            println!("  {}", string);
+/// The `--dump-parsed` view of sample number `index`. The process name
+/// is quoted so that an empty one shows.
+fn parsed_sample(index: usize, args: &TraceArgs) -> String {
+    fn field<T: Display>(value: Option<T>) -> String {
+        value.map_or("(none)".to_string(), |v| v.to_string())
+    }
+    let mut text = format!("Sample {}\n", index);
+    text += &format!("  process  : `{}`\n", args.process_name);
+    text += &format!("  tid      : {}\n", field(args.tid));
+    text += &format!("  cpu      : {}\n", field(args.cpu));
+    text += &format!("  timestamp: {}\n", field(args.timestamp.map(|t| format!("{:.6}", t))));
+    text += &format!("  event    : {}\n", field(args.event));
+    text += &format!("  period   : {}\n", field(args.cost));
+    text += &format!("  stack    : {} frames, outermost first\n", args.stack.len());
+    for (depth, frame) in args.stack.iter().enumerate() {
+        text += &format!("    {:>3} {}\n", depth, frame);
+    }
+    text
+}
+
+/// Whether to keep reading samples after `samples` of them passed the
+/// process and CPU filters.
+fn keep_going(options: &Options, samples: usize) -> ControlFlow<()> {
+    match options.max_samples {
+        Some(max) if samples >= max => ControlFlow::Break(()),
+        _ => ControlFlow::Continue(()),
+    }
+}
+
+fn graph_total(options: &Options, matches: usize, not_matches: usize) -> usize {
+    match options.graph_relative {
+        Denominator::Matched => matches,
+        Denominator::All => matches + not_matches,
+    }
+}
+
+/// Like `graph_total`, but without `--tree-relative` the tree follows
+/// `--relative`, as it always did.
+fn tree_total(options: &Options, matches: usize, not_matches: usize) -> usize {
+    match options.tree_relative {
+        Some(Denominator::Matched) => matches,
+        Some(Denominator::All) => matches + not_matches,
+        None if options.relative => matches,
+        None => matches + not_matches,
+    }
+}
+
+/// The `--oneline` summary: the input, the match count and the
+/// percentage of `total` samples that matched.
+fn oneline(options: &Options, matches: usize, total: usize) -> String {
+    format!(
+        "{}: matches={} total={} pct={}%",
+        options.input.name(),
+        matches,
+        total,
+        percent(matches, total)
+    )
+}
+
+/// True if nothing beyond the match counts is needed, i.e. no option
+/// asks for samples to be printed, exported, or fed to an accumulator.
+/// In that case matched samples are counted and immediately dropped.
+fn counts_only(options: &Options) -> bool {
+    !options.frames_only && only_counts_reported(options)
+}
+
+/// Like `counts_only`, but ignoring `--frames-only`, whose output
+/// replaces the report rather than adding to it.
+fn only_counts_reported(options: &Options) -> bool {
+    !options.print_match && !options.script_match && !options.script_miss
+        && options.jsonl_file.is_none() && options.graph_mode.is_none()
+        && options.hist_mode.is_none() && options.tree_mode.is_none()
+        && !options.unique_stacks && !options.hist_pairs && !options.transitions
+        && !options.callers_of && !options.miss_hist && options.hist_regex_group.is_none()
+        && !options.list_functions && !options.top_adjacent
+        && !options.match_depth_stats && !options.gaps
+}
+
+const IDLE_FRAME: &str = "[idle]";
+
+/// The last `len` samples seen, for `--tail`.
+struct Tail {
+    samples: VecDeque<OwnedTrace>,
+    len: usize,
+}
+
+impl Tail {
+    fn new(len: usize) -> Tail {
+        Tail { samples: VecDeque::with_capacity(len), len }
+    }
+
+    /// Adds a sample, dropping the oldest one if the window is full.
+    /// Returns whether a sample was dropped.
+    fn push(&mut self, args: &TraceArgs) -> bool {
+        let full = self.samples.len() == self.len;
+        if full {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(args.to_owned_trace());
+        full
+    }
+}
+
+/// Takes each sample of the input through `--pid-name`, the process
+/// name and CPU filters, `--max-samples` and `--tail` on its way to
+/// `process`.
+struct Feed {
+    filtered: Filtered,
+    tail: Option<Tail>,
+    /// Samples that passed the filters so far.
+    kept: usize,
+    /// Whether `--max-samples` stopped the reading with samples left.
+    stopped_early: bool,
+}
+
+impl Feed {
+    fn new(options: &Options) -> Feed {
+        Feed {
+            filtered: Filtered::default(),
+            tail: options.tail.map(Tail::new),
+            kept: 0,
+            stopped_early: false,
+        }
+    }
+
+    /// Handles the next sample of the input. If it passes the filters,
+    /// it goes to `process` right away or, with `--tail`, once the
+    /// input has been read (see `finish`). Stops the reading when a
+    /// sample comes in after `--max-samples` of them have passed.
+    fn sample<F>(&mut self, options: &Options, args: TraceArgs, process: &mut F) -> ControlFlow<()>
+        where F: FnMut(TraceArgs, &mut Filtered)
+    {
+        if keep_going(options, self.kept).is_break() {
+            self.stopped_early = true;
+            return ControlFlow::Break(());
+        }
+
+        let process_name = process_name(options, args.process_name);
+        let args = TraceArgs { process_name, ..args };
+
+        if !check_err("Error filtering by CPU", self.filtered.keep(options, &args)) {
+            return ControlFlow::Continue(());
+        }
+        self.kept += 1;
+
+        match self.tail {
+            Some(ref mut tail) => {
+                if tail.push(&args) {
+                    self.filtered.tail += 1;
+                }
+            }
+            None => process(args, &mut self.filtered),
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Passes the samples held back by `--tail` to `process`, once the
+    /// whole input has been read.
+    fn finish<F>(&mut self, process: &mut F)
+        where F: FnMut(TraceArgs, &mut Filtered)
+    {
+        if let Some(tail) = self.tail.take() {
+            for sample in &tail.samples {
+                process(sample.args(), &mut self.filtered);
+            }
+        }
+    }
+}
+
+/// How many frames the matches span (from `first_matching_frame` up to
+/// `first_callee_frame`), out of all the frames of all the samples, for
+/// `--coverage`. A matcher that hits half the samples but only one
+/// frame of each covers much less than one that spans whole stacks.
+#[derive(Default)]
+struct Coverage {
+    covered: usize,
+    frames: usize,
+}
+
+impl Coverage {
+    fn add(&mut self, frames: usize, result: Option<SearchResult>) {
+        self.frames += frames;
+        if let Some(result) = result {
+            self.covered += result.first_callee_frame - result.first_matching_frame;
+        }
+    }
+
+    /// The covered share of the frames, or `None` if there were none.
+    fn fraction(&self) -> Option<f64> {
+        if self.frames == 0 {
+            return None;
+        }
+        Some(self.covered as f64 / self.frames as f64)
+    }
+}
+
+/// The depths (`first_matching_frame`) at which the matcher fired, for
+/// `--match-depth-stats`.
+#[derive(Default)]
+struct DepthStats {
+    depths: Vec<usize>,
+}
+
+impl DepthStats {
+    fn add(&mut self, depth: usize) {
+        self.depths.push(depth);
+    }
+
+    /// Returns `(min, max, mean, median)`, or `None` if nothing matched.
+    fn summary(&self) -> Option<(usize, usize, f64, f64)> {
+        if self.depths.is_empty() {
+            return None;
+        }
+
+        let mut depths = self.depths.clone();
+        depths.sort();
+        let n = depths.len();
+        let mean = depths.iter().sum::<usize>() as f64 / n as f64;
+        let median = if n % 2 == 1 {
+            depths[n / 2] as f64
+        } else {
+            (depths[n / 2 - 1] + depths[n / 2]) as f64 / 2.0
+        };
+        Some((depths[0], depths[n - 1], mean, median))
+    }
+
+    fn dump(&self) {
+        match self.summary() {
+            Some((min, max, mean, median)) => {
+                println!("Min        : {}", min);
+                println!("Max        : {}", max);
+                println!("Mean       : {:.1}", mean);
+                println!("Median     : {:.1}", median);
+            }
+            None => println!("(no matches)"),
+        }
+    }
+}
+
+/// The times between consecutive matching samples, for `--gaps`. This
+/// relies on seeing the samples in the order they were taken, which is
+/// how `perf script` prints them; it would not survive splitting the
+/// input across threads.
+#[derive(Default)]
+struct GapStats {
+    last: Option<f64>,
+    gaps: Vec<f64>,
+}
+
+/// Upper bounds (in seconds) and labels of the `--gaps` histogram
+/// buckets; longer gaps go into a final `>= 1s` bucket.
+const GAP_BUCKETS: [(f64, &str); 6] = [
+    (1e-5, "< 10us"),
+    (1e-4, "< 100us"),
+    (1e-3, "< 1ms"),
+    (1e-2, "< 10ms"),
+    (1e-1, "< 100ms"),
+    (1.0, "< 1s"),
+];
+
+impl GapStats {
+    /// Samples without a timestamp are skipped.
+    fn add(&mut self, timestamp: Option<f64>) {
+        if let Some(timestamp) = timestamp {
+            if let Some(last) = self.last {
+                self.gaps.push(timestamp - last);
+            }
+            self.last = Some(timestamp);
+        }
+    }
+
+    /// Returns `(min, max, mean)`, or `None` if there are no gaps.
+    fn summary(&self) -> Option<(f64, f64, f64)> {
+        if self.gaps.is_empty() {
+            return None;
+        }
+
+        let min = self.gaps.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = self.gaps.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let mean = self.gaps.iter().sum::<f64>() / self.gaps.len() as f64;
+        Some((min, max, mean))
+    }
+
+    /// The number of gaps in each of `GAP_BUCKETS`, then `>= 1s`.
+    fn histogram(&self) -> Vec<(&'static str, usize)> {
+        let mut counts = vec![0; GAP_BUCKETS.len() + 1];
+        for &gap in &self.gaps {
+            let bucket = GAP_BUCKETS.iter().position(|&(limit, _)| gap < limit);
+            counts[bucket.unwrap_or(GAP_BUCKETS.len())] += 1;
+        }
+        GAP_BUCKETS.iter().map(|&(_, label)| label).chain(Some(">= 1s")).zip(counts).collect()
+    }
+
+    fn dump(&self) {
+        match self.summary() {
+            Some((min, max, mean)) => {
+                println!("Gaps       : {}", self.gaps.len());
+                println!("Min        : {:.6}s", min);
+                println!("Max        : {:.6}s", max);
+                println!("Mean       : {:.6}s", mean);
+                for (label, count) in self.histogram() {
+                    println!("{:>8} {:3}% ({})", label, percent(count, self.gaps.len()), count);
+                }
+            }
+            None => println!("(fewer than two matches with timestamps)"),
+        }
+    }
+}
+
+/// The (renamed) frames of `stack`, outermost first, joined with `;`
+/// as in the "folded" format that flame graph tools read.
+fn folded(options: &Options, stack: &[String]) -> String {
+    let frames: Vec<String> = stack.iter().map(|f| rename_frame(options, f.clone())).collect();
+    frames.join(";")
+}
+
+/// Adds the (renamed) name of every frame of `stack` to `functions`.
+fn add_functions(options: &Options, stack: &[String], functions: &mut BTreeSet<String>) {
+    for frame in stack {
+        functions.insert(rename_frame(options, frame.clone()));
+    }
+}
+
+const NO_CALLER: &str = "[no caller]";
+
+/// The frame that directly called the first frame of the match, or
+/// `[no caller]` if the match starts at the outermost frame.
+fn caller_of(stack: &[String], result: SearchResult) -> &str {
+    match result.first_matching_frame.checked_sub(1) {
+        Some(index) => &stack[index],
+        None => NO_CALLER,
+    }
+}
+
+const NO_CALLEE: &str = "[no callee]";
+
+const NO_LEAF: &str = "[no frames]";
+
+/// The innermost frame of `stack`, or `[no frames]` if it is empty.
+fn leaf_of(stack: &[String]) -> &str {
+    stack.last().map_or(NO_LEAF, |frame| frame)
+}
+
+const NO_GROUP: &str = "[no group]";
+
+/// The value of the capture group `group` in the match (see
+/// `Matcher::capture`), or `[no group]` if the group took no part in it.
+fn group_value(matcher: &Matcher, stack: &[String], result: SearchResult, group: &str) -> String {
+    matcher.capture(stack, result, group).unwrap_or_else(|| NO_GROUP.to_string())
+}
+
+/// The frame directly called by the last frame of the match, or
+/// `[no callee]` if the match ends at the innermost frame.
+fn callee_of(stack: &[String], result: SearchResult) -> &str {
+    match stack.get(result.first_callee_frame) {
+        Some(frame) => frame,
+        None => NO_CALLEE,
+    }
+}
+
+/// Replaces a stack that carries no information (it is empty, or every
+/// frame is `[unknown]`) with the single frame `[idle]`, so that such
+/// samples show up as one bucket in the outputs.
+fn bucket_idle(stack: &mut Vec<String>) {
+    if stack.iter().all(|frame| frame == "[unknown]") {
+        stack.clear();
+        stack.push(IDLE_FRAME.to_string());
+    }
+}
+
+/// True unless `--cpu` selects a different CPU than `cpu`, the one the
+/// sample was taken on. It is an error to use `--cpu` on samples that
+/// do not say which CPU they ran on.
+fn cpu_matches(options: &Options, cpu: Option<u32>) -> Result<bool, &'static str> {
+    match (options.cpu, cpu) {
+        (None, _) => Ok(true),
+        (Some(wanted), Some(cpu)) => Ok(wanted == cpu),
+        (Some(_), None) => {
+            Err("--cpu was given, but a sample has no CPU column (try `perf script -F +cpu`)")
+        }
+    }
+}
+
+/// Counts the samples dropped by each filter, for `--verbose`.
+#[derive(Debug, Default, PartialEq)]
+struct Filtered {
+    read: usize,
+    process: usize,
+    cpu: usize,
+    /// Samples that passed the filters but fell out of the `--tail`
+    /// window.
+    tail: usize,
+    root_leaf: usize,
+}
+
+impl Filtered {
+    /// Applies the process name and CPU filters to a sample. Samples
+    /// they drop are not counted at all, matching or not.
+    fn keep(&mut self, options: &Options, args: &TraceArgs) -> Result<bool, &'static str> {
+        self.read += 1;
+        if !process_name_matches(options, args.process_name) {
+            self.process += 1;
+            return Ok(false);
+        }
+
+        if !cpu_matches(options, args.cpu)? {
+            self.cpu += 1;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Applies `--root` and `--leaf`; samples they reject still count
+    /// as not matching.
+    fn root_and_leaf(&mut self, options: &Options, stack: &[String]) -> bool {
+        let matches = root_and_leaf_match(options, stack);
+        if !matches {
+            self.root_leaf += 1;
+        }
+        matches
+    }
+
+    /// The lines of the `--verbose` report, as `(label, count)`.
+    fn report(
+        &self,
+        matches: usize,
+        not_matches: usize,
+        gave_up: usize,
+        stats: &ParseStats,
+    ) -> Vec<(&'static str, usize)> {
+        vec![
+            ("Samples read", self.read),
+            ("Dropped by process name", self.process),
+            ("Dropped by --cpu", self.cpu),
+            ("Before the --tail window", self.tail),
+            ("Not matching --root/--leaf", self.root_leaf),
+            ("Over --max-search-steps", gave_up),
+            ("Matches", matches),
+            ("Not matches", not_matches),
+            ("Lines not valid UTF-8", stats.lossy_lines),
+            ("Frames without a symbol", stats.unknown_frames),
+            ("`---` marker lines", stats.marker_lines),
+            ("Frames before any header", stats.stray_frames),
+        ]
+    }
+}
+
+/// The name to use for a sample's process: the `--pid-name` given for
+/// a nameless process shown as `:<pid>`, otherwise `name` itself.
+fn process_name<'a>(options: &'a Options, name: &'a str) -> &'a str {
+    name.strip_prefix(':')
+        .and_then(|pid| u32::from_str(pid).ok())
+        .and_then(|pid| options.pid_names.get(&pid))
+        .map_or(name, |renamed| renamed)
+}
+
+fn process_name_matches(options: &Options, process_name: &str) -> bool {
+    if let Some(ref regex) = options.process_name_filter {
+        if !regex.is_match(process_name) {
+            return false;
+        }
+    }
+
+    if let Some(ref name) = options.process_name_exact {
+        if process_name != name {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Searches `stack` for `matcher`, as `--search` and
+/// `--max-search-steps` say. Without a matcher on the command line, a
+/// sample with no frames stays unmatched (see `--include-idle`); an
+/// explicit `()` is needed to select such samples.
+fn search_sample(
+    options: &Options,
+    matcher: &Matcher,
+    stack: &[String],
+) -> Result<Option<SearchResult>, StepLimitExceeded> {
+    if stack.is_empty() && options.matcher.is_none() {
+        return Ok(None);
+    }
+
+    matcher.search_trace_nth_limited(
+        stack,
+        options.search,
+        options.match_index,
+        options.max_search_steps,
+    )
+}
+
+/// Checks the `--root` and `--leaf` filters against the outermost and
+/// innermost frames of `stack`. An empty stack passes only if neither
+/// filter is given.
+fn root_and_leaf_match(options: &Options, stack: &[String]) -> bool {
+    let check = |filter: &Option<Regex>, frame: Option<&String>| match *filter {
+        Some(ref regex) => frame.is_some_and(|f| regex.is_match(f)),
+        None => true,
+    };
+
+    check(&options.root_filter, stack.first()) && check(&options.leaf_filter, stack.last())
+}
+
+/// Feeds the part of `frames` that `mode` selects to `acc`, renamed.
+/// If `dedup_first` is set, each frame is passed along with its name
+/// before renaming, so that `acc` counts it once per original name
+/// (this is how `--dedup pre-rename` works for the histogram and
+/// graph); the sequence itself is never shortened.
+fn add_frames<F>(
+    matcher: &Matcher,
+    mode: GraphMode,
+    frames: &[String],
+    result: SearchResult,
+    options: &Options,
+    acc: &mut F,
+    dedup_first: bool,
+) where
+    F: AddFrames,
+{
+    let (callers_end, callees_start) = split_at_match(matcher, frames, result, options);
+
+    // `None` stands for the label of the match itself, which is not renamed
+    let selected: Vec<Option<&String>> = match mode {
+        GraphMode::All => frames.iter().map(Some).collect(),
+        GraphMode::Caller => {
+            let mut caller_frames: Vec<_> = frames.iter().take(callers_end).map(Some).collect();
+            caller_frames.push(None);
+            caller_frames.reverse();
+            caller_frames
+        }
+        GraphMode::Callee => {
+            Some(None).into_iter().chain(frames.iter().skip(callees_start).map(Some)).collect()
+        }
+        GraphMode::Butterfly => panic!("butterfly mode is only for trees"),
+    };
+
+    let matched = format!("matched `{:?}`", matcher);
+    let name = |frame: Option<&String>| match frame {
+        Some(s) => rename_frame(options, s.clone()),
+        None => matched.clone(),
+    };
+    if dedup_first {
+        acc.add_keyed_frames(
+            selected.into_iter().map(|frame| (frame.unwrap_or(&matched).clone(), name(frame))),
+        );
+    } else {
+        acc.add_frames(selected.into_iter().map(name));
+    }
+}
+
+/// Returns `(callers_end, callees_start)`: the number of leading
+/// frames treated as callers of the match, and the index of the first
+/// callee, as adjusted by `--anchor`.
+fn split_at_match(
+    matcher: &Matcher,
+    frames: &[String],
+    result: SearchResult,
+    options: &Options,
+) -> (usize, usize) {
+    match options.anchor {
+        None => (result.first_matching_frame, result.first_callee_frame),
+        Some(Anchor::Start) => (result.first_matching_frame, result.first_matching_frame),
+        Some(Anchor::End) => (result.first_callee_frame, result.first_callee_frame),
+        Some(Anchor::Needle) => {
+            // an empty stack matched by `()` has no needle frame
+            let needle = matcher.needle_frame(frames, result);
+            (needle, (needle + 1).min(frames.len()))
+        }
+    }
+}
+
+/// Adds a sample to the `--tree-butterfly` tree, split into callers
+/// and callees just as for `--tree-callers` and `--tree-callees`.
+fn add_butterfly(
+    matcher: &Matcher,
+    frames: &[String],
+    result: SearchResult,
+    options: &Options,
+    tree: &mut Tree,
+    weight: usize,
+) {
+    let (callers_end, callees_start) = split_at_match(matcher, frames, result, options);
+    let rename = |s: &String| rename_frame(options, s.clone());
+    tree.add_butterfly(
+        format!("matched `{:?}`", matcher),
+        frames[..callers_end].iter().rev().map(rename),
+        frames[callees_start..].iter().map(rename),
+        weight,
+    );
+}
+
+/// Adds each sample to `tree` `weight` times; used for `--tree-cost`.
+struct Weighted<'a> {
+    tree: &'a mut Tree,
+    weight: usize,
+}
+
+impl<'a> AddFrames for Weighted<'a> {
+    fn add_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = String>,
+    {
+        self.tree.add_weighted_frames(frames, self.weight);
+    }
+
+    fn clear(&mut self) {
+        self.tree.clear();
+    }
+}
+
+/// Passes every `stride`th frame (starting with the first) on to
+/// `acc`; used for `--graph-stride`.
+struct Strided<'a, F: 'a> {
+    acc: &'a mut F,
+    stride: usize,
+}
+
+impl<'a, F: AddFrames> AddFrames for Strided<'a, F> {
+    fn add_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = String>,
+    {
+        self.acc.add_frames(frames.step_by(self.stride));
+    }
+
+    fn add_keyed_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = (String, String)>,
+    {
+        self.acc.add_keyed_frames(frames.step_by(self.stride));
+    }
+
+    fn clear(&mut self) {
+        self.acc.clear();
+    }
+}
+
+const SYNTHETIC_ROOT: &str = "<root>";
+
+/// Passes each sample on to `acc` with `<root>` in front, if
+/// `enabled`; used for `--graph-synthetic-root`.
+struct Rooted<'a, F: 'a> {
+    acc: &'a mut F,
+    enabled: bool,
+}
+
+impl<'a, F: AddFrames> AddFrames for Rooted<'a, F> {
+    fn add_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = String>,
+    {
+        if self.enabled {
+            self.acc.add_frames(Some(SYNTHETIC_ROOT.to_string()).into_iter().chain(frames));
+        } else {
+            self.acc.add_frames(frames);
+        }
+    }
+
+    fn add_keyed_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = (String, String)>,
+    {
+        if self.enabled {
+            let root = (SYNTHETIC_ROOT.to_string(), SYNTHETIC_ROOT.to_string());
+            self.acc.add_keyed_frames(Some(root).into_iter().chain(frames));
+        } else {
+            self.acc.add_keyed_frames(frames);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.acc.clear();
+    }
+}
+
+/// Parses a `--graph-size` argument like `7.5,10` into a width and
+/// height, both of which must be positive.
+fn parse_graph_size(s: &str) -> Option<(f64, f64)> {
+    let mut parts = s.split(',');
+    let width = f64::from_str(parts.next()?.trim()).ok()?;
+    let height = f64::from_str(parts.next()?.trim()).ok()?;
+    if parts.next().is_some() || !(width > 0.0 && height > 0.0) {
+        return None;
+    }
+    Some((width, height))
+}
+
+/// Reads rename rules from `path`. Each line holds a regular
+/// expression and its replacement, separated by a tab; blank lines
+/// and lines starting with `#` are ignored.
+fn read_rename_file(path: &str) -> Result<Vec<(Regex, String)>, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("`{}`: {}", path, e))?;
+    parse_rename_rules(&text).map_err(|e| format!("`{}` {}", path, e))
+}
+
+/// Removes `--config <file>` from `args`, returning the file, if any.
+fn take_config(mut args: Vec<String>) -> (Option<String>, Vec<String>) {
+    let i = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => i,
+        None => return (None, args),
+    };
+    if i + 1 == args.len() {
+        usage("Error: --config needs a file");
+    }
+    let path = args.remove(i + 1);
+    args.remove(i);
+    if args.iter().any(|arg| arg == "--config") {
+        usage("Error: config already specified");
+    }
+    (Some(path), args)
+}
+
+fn read_config(path: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("`{}`: {}", path, e))?;
+    config::parse(&text).map_err(|e| format!("`{}`: {}", path, e))
+}
+
+/// The type of value a config file key takes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ConfigType {
+    /// `true` gives the flag, `false` leaves it out.
+    Bool,
+    /// An integer of at least 0.
+    Count,
+    String,
+    /// An integer or `"last"`; only for `match-index`.
+    Index,
+    /// An array with an element per use of a flag that may be given
+    /// more than once; an element is an array of the flag's arguments,
+    /// unless it only takes one.
+    Repeated(&'static [ConfigType]),
+}
+
+/// Each config file key, named after its flag (`--` left off), with
+/// the type of its value. `matcher` gives the matcher.
+const CONFIG_KEYS: &[(&str, ConfigType)] = &[
+    ("matcher", ConfigType::String),
+    ("process-name", ConfigType::String),
+    ("process-name-exact", ConfigType::String),
+    ("pid-name", ConfigType::Repeated(&[ConfigType::Count, ConfigType::String])),
+    ("cpu", ConfigType::Count),
+    ("root", ConfigType::String),
+    ("leaf", ConfigType::String),
+    ("leaf-regex", ConfigType::String),
+    ("print-match", ConfigType::Bool),
+    ("script-match", ConfigType::Bool),
+    ("rustc-query", ConfigType::Bool),
+    ("rustc-query-context", ConfigType::Bool),
+    ("include-idle", ConfigType::Bool),
+    ("keep-address", ConfigType::Bool),
+    ("normalize-addresses", ConfigType::Bool),
+    ("relative", ConfigType::Bool),
+    ("from-stdin", ConfigType::Bool),
+    ("input", ConfigType::Repeated(&[ConfigType::String])),
+    ("print-miss", ConfigType::Bool),
+    ("script-miss", ConfigType::Bool),
+    ("jsonl-out", ConfigType::String),
+    ("graph", ConfigType::String),
+    ("graph-callers", ConfigType::String),
+    ("graph-callees", ConfigType::String),
+    ("graph-relative", ConfigType::String),
+    ("graph-layout", ConfigType::String),
+    ("graph-size", ConfigType::String),
+    ("graph-degrees", ConfigType::Bool),
+    ("graph-keep-orphans", ConfigType::Bool),
+    ("graph-count-repeats", ConfigType::Bool),
+    ("graph-rank", ConfigType::Bool),
+    ("graph-synthetic-root", ConfigType::Bool),
+    ("graph-undirected", ConfigType::Bool),
+    ("graph-highlight", ConfigType::String),
+    ("graph-stride", ConfigType::Count),
+    ("graph-flush-every", ConfigType::Count),
+    ("graph-min-percent", ConfigType::Count),
+    ("hist", ConfigType::Bool),
+    ("hist-callers", ConfigType::Bool),
+    ("hist-callees", ConfigType::Bool),
+    ("hist-pairs", ConfigType::Bool),
+    ("pairs-context", ConfigType::Count),
+    ("transitions", ConfigType::Bool),
+    ("hist-regex-group", ConfigType::String),
+    ("callers-of", ConfigType::Bool),
+    ("miss-hist", ConfigType::Bool),
+    ("list-functions", ConfigType::Bool),
+    ("top-adjacent", ConfigType::Bool),
+    ("match-depth-stats", ConfigType::Bool),
+    ("coverage", ConfigType::Bool),
+    ("gaps", ConfigType::Bool),
+    ("unique-stacks", ConfigType::Bool),
+    ("stacks-equal", ConfigType::Bool),
+    ("tree", ConfigType::Bool),
+    ("tree-callers", ConfigType::Bool),
+    ("tree-callees", ConfigType::Bool),
+    ("tree-butterfly", ConfigType::Bool),
+    ("tree-relative", ConfigType::String),
+    ("anchor", ConfigType::String),
+    ("exact", ConfigType::Bool),
+    ("search", ConfigType::String),
+    ("match-index", ConfigType::Index),
+    ("max-search-steps", ConfigType::Count),
+    ("match-budget", ConfigType::Count),
+    ("top-n", ConfigType::Count),
+    ("min-percent", ConfigType::Count),
+    ("max-name-width", ConfigType::Count),
+    ("tree-max-depth", ConfigType::Count),
+    ("tree-min-percent", ConfigType::Count),
+    ("tree-other", ConfigType::Bool),
+    ("tree-by-tid", ConfigType::Bool),
+    ("hot-path", ConfigType::Bool),
+    ("tree-cost", ConfigType::Bool),
+    ("tree-leaf", ConfigType::Bool),
+    ("frequency", ConfigType::Count),
+    ("max-samples", ConfigType::Count),
+    ("head", ConfigType::Count),
+    ("tail", ConfigType::Count),
+    ("summary-only", ConfigType::Bool),
+    ("oneline", ConfigType::Bool),
+    ("frames-only", ConfigType::Bool),
+    ("dump-parsed", ConfigType::Bool),
+    ("quiet", ConfigType::Bool),
+    ("verbose", ConfigType::Bool),
+    ("progress", ConfigType::Bool),
+    ("color", ConfigType::String),
+    ("rename", ConfigType::Repeated(&[ConfigType::String, ConfigType::String])),
+    ("dedup", ConfigType::String),
+    ("trim-generics", ConfigType::Bool),
+    ("rename-from-file", ConfigType::Repeated(&[ConfigType::String])),
+];
+
+impl ConfigType {
+    fn describe(self) -> String {
+        match self {
+            ConfigType::Bool => "`true` or `false`".to_string(),
+            ConfigType::Count => "an integer of at least 0".to_string(),
+            ConfigType::String => "a string".to_string(),
+            ConfigType::Index => "an integer or \"last\"".to_string(),
+            ConfigType::Repeated(&[ty]) => format!("an array of {}s", ty.noun()),
+            ConfigType::Repeated(tys) => {
+                let nouns: Vec<_> = tys.iter().map(|ty| ty.noun()).collect();
+                format!("an array of [{}] arrays", nouns.join(", "))
+            }
+        }
+    }
+
+    /// How many arguments the flag takes.
+    fn arity(self) -> usize {
+        match self {
+            ConfigType::Bool => 0,
+            ConfigType::Repeated(tys) => tys.len(),
+            _ => 1,
+        }
+    }
+
+    fn noun(self) -> &'static str {
+        match self {
+            ConfigType::Count => "integer",
+            _ => "string",
+        }
+    }
+
+    /// Checks `value` and appends the arguments it gives to `args`,
+    /// after `flag` if there are any.
+    fn push_args(self, flag: &str, value: Value, args: &mut Vec<String>) -> Result<(), ()> {
+        match (self, value) {
+            (ConfigType::Bool, Value::Bool(set)) => {
+                if set {
+                    args.push(flag.to_string());
+                }
+            }
+            (ConfigType::Repeated(tys), Value::Array(items)) => {
+                for item in items {
+                    args.push(flag.to_string());
+                    match (tys, item) {
+                        (&[ty], item) => args.push(ty.scalar(item)?),
+                        (tys, Value::Array(values)) if values.len() == tys.len() => {
+                            for (ty, value) in tys.iter().zip(values) {
+                                args.push(ty.scalar(value)?);
+                            }
+                        }
+                        _ => return Err(()),
+                    }
+                }
+            }
+            (ConfigType::Bool, _) | (ConfigType::Repeated(_), _) => return Err(()),
+            (ty, value) => {
+                args.push(flag.to_string());
+                args.push(ty.scalar(value)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// The single argument `value` gives, if it has this type.
+    fn scalar(self, value: Value) -> Result<String, ()> {
+        match (self, value) {
+            (ConfigType::Count, Value::Number(n)) if usize::from_str(&n).is_ok() => Ok(n),
+            (ConfigType::Index, Value::Number(n)) if usize::from_str(&n).is_ok() => Ok(n),
+            (ConfigType::Index, Value::String(s)) if s == "last" => Ok(s),
+            (ConfigType::String, Value::String(s)) => Ok(s),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Turns config file entries into the arguments they stand for: each
+/// key is the name of a flag, so `tree-max-depth = 3` is
+/// `--tree-max-depth 3`, and its value must have the type
+/// `CONFIG_KEYS` gives for it (see `ConfigType`). Entries for settings
+/// that `cli_args` also give are dropped, so the command line wins.
+fn config_args(entries: Vec<(String, Value)>, cli_args: &[String]) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut matcher = None;
+    for (key, value) in entries {
+        let ty = match CONFIG_KEYS.iter().find(|&&(name, _)| name == key) {
+            Some(&(_, ty)) => ty,
+            None => return Err(format!("`{}`: unknown key", key)),
+        };
+        let mut given = vec![];
+        ty.push_args(&format!("--{}", key), value, &mut given)
+          .map_err(|()| format!("`{}`: expected {}", key, ty.describe()))?;
+        if overridden(&key, cli_args) {
+            continue;
+        }
+        if key == "matcher" {
+            matcher = given.pop();
+        } else {
+            args.extend(given);
+        }
+    }
+    args.extend(matcher);
+    Ok(args)
+}
+
+/// Whether the command line sets what config key `key` would. The
+/// modes of each output (`tree`, `tree-callers`, ...) count as one
+/// setting. This looks at the arguments only, without parsing them,
+/// so that parsing (which can warn or read files) happens once.
+fn overridden(key: &str, cli_args: &[String]) -> bool {
+    let family = |key: &str, output: &str| {
+        key.strip_prefix(output)
+           .is_some_and(|mode| ["", "-callers", "-callees", "-butterfly"].contains(&mode))
+    };
+
+    let mut args = cli_args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None if arg.starts_with('-') => continue,
+            None if key == "matcher" => return true,
+            None => continue,
+        };
+        let same_output = ["graph", "hist", "tree"]
+            .iter()
+            .any(|&output| family(key, output) && family(flag, output));
+        if flag == key || same_output {
+            return true;
+        }
+        let ty = CONFIG_KEYS.iter().find(|&&(name, _)| name == flag).map(|&(_, ty)| ty);
+        for _ in 0..ty.map_or(0, ConfigType::arity) {
+            args.next();
+        }
+    }
+    false
+}
+
+/// Replaces each `@file` argument with the arguments read from `file`
+/// (see `split_response_file`). Arguments read from a file are not
+/// expanded again.
+fn expand_response_files<I>(args: I) -> Vec<String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut expanded = vec![];
+    for arg in args {
+        match arg.strip_prefix('@') {
+            Some(path) => {
+                let file_args = read_response_file(path);
+                expanded.extend(check_err("Error reading response file", file_args));
+            }
+            None => expanded.push(arg),
+        }
+    }
+    expanded
+}
+
+fn read_response_file(path: &str) -> Result<Vec<String>, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("`{}`: {}", path, e))?;
+    split_response_file(&text).map_err(|e| format!("`{}`: {}", path, e))
+}
+
+/// Splits the text of a response file into arguments, separated by
+/// whitespace (including newlines). Text in single or double quotes is
+/// taken literally, so `'{a} {b}'` is one argument and `''` is an
+/// empty one. Backslashes are not special, as they are common in
+/// regular expressions.
+fn split_response_file(text: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(other) => arg.push(other),
+                        None => return Err(format!("unterminated {} quote", c)),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(arg.split_off(0));
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                arg.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn parse_rename_rules(text: &str) -> Result<Vec<(Regex, String)>, String> {
+    let mut rules = vec![];
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line_number = index + 1;
+        let mut parts = line.splitn(2, '\t');
+        let pattern = parts.next().unwrap();
+        let replacement = match parts.next() {
+            Some(r) => r,
+            None => {
+                return Err(format!(
+                    "line {}: expected `<match><TAB><repl>`",
+                    line_number
+                ))
+            }
+        };
+        let regex = Regex::new(pattern).map_err(|e| {
+            format!("line {}: invalid regular expression: {}", line_number, e)
+        })?;
+        rules.push((regex, replacement.to_string()));
+    }
+    Ok(rules)
+}
+
+fn rename_frame(options: &Options, frame: String) -> String {
+    let mut frame = if options.trim_generics {
+        trim_generics(&frame)
+    } else {
+        frame
+    };
+    for (regex, repl) in &options.rename {
+        let tmp = regex.replace_all(&frame, |caps: &Captures| expand_replacement(caps, repl));
+        frame = tmp;
+    }
+    frame
+}
+
+/// Expands the `--rename` replacement `repl` for one match: `$1` and
+/// `$name` are replaced by what that group matched, `${name}` likewise
+/// (so that `${name}_x` is not read as the group `name_x`), and `$$` by
+/// a single `$`. A group that took no part in the match expands to
+/// nothing. The regex crate's own expansion has no `${name}` form.
+fn expand_replacement(caps: &Captures, repl: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = repl;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", rest),
+            },
+            None => {
+                let digits = rest.starts_with(|c: char| c.is_ascii_digit());
+                let in_name = |c: char| {
+                    if digits { c.is_ascii_digit() } else { c.is_alphanumeric() || c == '_' }
+                };
+                let end = rest.find(|c: char| !in_name(c)).unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            // not a reference, so the `$` is kept as is
+            expanded.push('$');
+            continue;
+        }
+        let value = match name.parse::<usize>() {
+            Ok(index) => caps.at(index),
+            Err(_) => caps.name(name),
+        };
+        expanded.push_str(value.unwrap_or(""));
+        rest = after;
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Removes the generic arguments from a Rust symbol name, so that
+/// `core::iter::adapters::map::Map<I,F>::next` becomes
+/// `core::iter::adapters::map::Map::next`. A `<` that follows a name
+/// (or `::`, for a turbofish) starts generic arguments, which are
+/// dropped up to the matching `>`; the `>` of a `->` never counts. Any
+/// other `<`, like the one in `<Vec<T> as Drop>::drop`, opens a
+/// qualified path, which is kept (as `<Vec as Drop>::drop`). A name
+/// whose brackets do not balance is returned unchanged.
+fn trim_generics(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut dropping = 0; // depth within the generic arguments being dropped
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '-' && chars.peek() == Some(&'>') {
+            chars.next();
+            if dropping == 0 {
+                out.push_str("->");
+            }
+            continue;
+        }
+
+        if dropping > 0 {
+            match c {
+                '<' => dropping += 1,
+                '>' => dropping -= 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        if c == '<' {
+            if out.ends_with("::") {
+                let len = out.len() - 2;
+                out.truncate(len);
+                dropping = 1;
+                continue;
+            }
+            if out.ends_with(|p: char| p.is_alphanumeric() || p == '_') {
+                dropping = 1;
+                continue;
+            }
+        }
+
+        out.push(c);
+    }
+
+    if dropping > 0 {
+        return name.to_string();
+    }
+    out
+}
+
+/// The range of lines of `args.header` that hold the frames matched in
+/// `result`. Returns `None` if the stack that was searched does not
+/// line up with the frame lines, as when `--rustc-query` or
+/// `--include-idle` rewrote it.
+fn selected_lines(args: &TraceArgs, result: SearchResult) -> Option<(usize, usize)> {
+    if args.header.len() != args.stack.len() + 1 {
+        return None;
+    }
+
+    // The search result is expressed counting backwards from
+    // **top** element in the stack, which is last in this list.
+    //
+    // Matcher: {a}..{b}
+    // against Frames:
+    //     z
+    //     b
+    //     y
+    //     a
+    //     x
+    // yields `{ first_callee_frame: 4, first_matching_frame: 1 }`
+    // we want to select from index `1..5`.
+    let len = args.header.len();
+    Some((len - result.first_callee_frame, len - result.first_matching_frame))
+}
+
+/// Prints a sample, highlighting the `selected` range of lines (see
+/// `selected_lines`).
+fn print_trace(header: &[String], selected: Option<(usize, usize)>, color: bool) {
+    if let Some((selection_start, selection_end)) = selected {
+        println!("{}", header[0]);
+        for string in &header[1..selection_start] {
+            println!("  {}", string);
+        }
+        for string in &header[selection_start..selection_end] {
+            println!("{}", color::highlight(color, &format!("| {}", string)));
+        }
+        for string in &header[selection_end..] {
+            println!("  {}", string);
         }
     } else {
         for string in header {
             println!("{}", string);
         }
     }
-    println!("");
+    println!();
+}
+
+/// An empty graph, set up as the `--graph-*` options ask.
+fn call_graph(options: &Options) -> CallGraph {
+    let mut graph = CallGraph::new();
+    graph.set_keep_orphans(options.graph_keep_orphans);
+    graph.set_min_percent(options.graph_min_percent);
+    graph.set_dedup(options.dedup == Dedup::PostRename);
+    graph.set_count_repeats(options.graph_count_repeats);
+    graph.set_rank(options.graph_rank);
+    graph.set_undirected(options.graph_undirected);
+    graph.set_highlight(options.graph_highlight.clone());
+    graph.set_layout(options.graph_layout);
+    graph.set_size(options.graph_size);
+    graph
+}
+
+/// An empty histogram for `--hist`, `--callers-of` or
+/// `--hist-regex-group`, which skips rows below `--min-percent` and
+/// shortens names to `--max-name-width`.
+fn histogram(options: &Options) -> Histogram {
+    let mut hist = Histogram::new();
+    hist.set_min_percent(options.min_percent);
+    hist.set_max_name_width(options.max_name_width);
+    hist
+}
+
+/// Brings `graph` up to date with the samples seen so far and writes
+/// it out, replacing whatever an earlier flush wrote.
+fn flush_graph(options: &Options, graph: &mut CallGraph, matches: usize, not_matches: usize) {
+    graph.set_total(graph_total(options, matches, not_matches), options.top_n);
+
+    if let Some(ref graph_file) = options.graph_file {
+        check_err(
+            &format!("Error printing graph to `{}`", graph_file),
+            dump_graph(graph, graph_file),
+        );
+    }
 }
 
 fn dump_graph(graph: &CallGraph, graph_file: &str) -> io::Result<()> {
-    let mut file = BufWriter::new(try!(File::create(graph_file)));
+    let mut file = BufWriter::new(File::create(graph_file)?);
     graph.dump(&mut file)
 }
 
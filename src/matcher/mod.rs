@@ -1,12 +1,14 @@
-/// Matchers match against stack traces. This is basically a simple
-/// parser combinator.
+//! Matchers match against stack traces. This is basically a simple
+//! parser combinator.
 
 #[cfg(test)]
 mod test;
 
-use rusty_peg::{self, Symbol};
-use regex::Regex;
-use std::fmt::{Debug, Error, Formatter};
+use demangle::with_demangled;
+use rusty_peg::Symbol;
+use regex::{self, Regex};
+use std::error;
+use std::fmt::{self, Debug, Error, Formatter};
 
 type StackTrace<'stack> = &'stack [StackFrame];
 type StackFrame = String;
@@ -47,56 +49,366 @@ enum MatchError {
     // nice to have an operator that *didn't* cut, like `,..,` or
     // something.
     IrrecoverableError,
+
+    // The search ran out of steps (see `SearchState`). Nothing may recover
+    // from this: it has to travel all the way up to the caller of
+    // `search_trace_limited`.
+    StepLimitExceeded,
+}
+
+/// The state of one top-level search: the steps it may still take
+/// (one step per attempt to match some matcher at some frame), and
+/// the modules bound so far by `{mod=$name}` matchers.
+struct SearchState {
+    steps_left: usize,
+    modules: Vec<(String, String)>,
+
+    // While `Matcher::capture` replays a match: the name of the
+    // capture group it wants, and the values that group took, in the
+    // order the frames were matched.
+    group: Option<String>,
+    captures: Vec<String>,
+}
+
+impl SearchState {
+    fn new(max_steps: usize) -> SearchState {
+        SearchState { steps_left: max_steps, modules: vec![], group: None, captures: vec![] }
+    }
+
+    fn unlimited() -> SearchState {
+        SearchState::new(usize::MAX)
+    }
+
+    fn step(&mut self) -> Result<(), MatchError> {
+        if self.steps_left == 0 {
+            return Err(MatchError::StepLimitExceeded);
+        }
+        self.steps_left -= 1;
+        Ok(())
+    }
+
+    fn module(&self, name: &str) -> Option<&str> {
+        self.modules.iter().find(|(n, _)| n == name).map(|(_, m)| &m[..])
+    }
+
+    /// Notes the value of the wanted capture group (if any) in
+    /// `frame`, which `regex` just matched.
+    fn capture(&mut self, regex: &Regex, frame: &str) {
+        if let Some(ref group) = self.group {
+            let value = regex.captures(frame).and_then(|c| c.name(group).map(str::to_string))
+                .or_else(|| with_demangled(frame, |name| {
+                    name.and_then(|n| regex.captures(n)?.name(group).map(str::to_string))
+                }));
+            self.captures.extend(value);
+        }
+    }
+
+    /// Returns a mark to later `reset` to, undoing the bindings (and
+    /// captures) made by a failed attempt.
+    fn mark(&self) -> (usize, usize) {
+        (self.modules.len(), self.captures.len())
+    }
+
+    fn reset(&mut self, (modules, captures): (usize, usize)) {
+        self.modules.truncate(modules);
+        self.captures.truncate(captures);
+    }
 }
 
+/// The error returned by `Matcher::search_trace_limited` when a search
+/// gives up.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StepLimitExceeded;
+
 ///////////////////////////////////////////////////////////////////////////
 
 mod parser;
 
-pub fn parse_matcher(s: &str) -> Result<Matcher, rusty_peg::Error> {
-    let mut parser = parser::Parser::new(());
-    match parser::MATCHER.parse_complete(&mut parser, s) {
+pub fn parse_matcher(s: &str) -> Result<Matcher, MatcherParseError> {
+    let mut parser = parser::Parser::new(None);
+    match parser::QUERY.parse_complete(&mut parser, s) {
         Ok(m) => Ok(m),
-        Err(err) => Err(err),
+        Err(err) => {
+            // a malformed `{...}` explains the failure better than
+            // whatever the last alternative tried expected
+            let (offset, expected) = match parser.base {
+                Some(brace_err) => (brace_err.offset, brace_err.expected),
+                None => (err.offset, err.expected),
+            };
+            Err(MatcherParseError { input: s.to_string(), offset, expected: expected.to_string() })
+        }
     }
 }
 
+/// Why `parse_matcher` rejected its input. This holds on to the input,
+/// so that `Display` can point at the problem:
+///
+/// ```text
+/// invalid matcher: expected end of input
+///   {a},{b}..
+///          ^
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatcherParseError {
+    pub input: String,
+    /// Byte offset into `input` where parsing failed.
+    pub offset: usize,
+    /// What the parser was looking for at `offset`.
+    pub expected: String,
+}
+
+impl fmt::Display for MatcherParseError {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let column = self.input[..self.offset].chars().count();
+        writeln!(fmt, "invalid matcher: expected {}", self.expected)?;
+        writeln!(fmt, "  {}", self.input)?;
+        write!(fmt, "  {}^", " ".repeat(column))
+    }
+}
+
+impl error::Error for MatcherParseError {}
+
 pub fn empty_matcher() -> Matcher {
     EmptyMatcher::new()
 }
 
-///////////////////////////////////////////////////////////////////////////
-
+/// A parsed query. Each kind of matcher is a variant of `MatcherKind`,
+/// so matching dispatches with a plain `match` and a matcher's
+/// structure can be inspected (see `simplify`).
+#[derive(Clone)]
 pub struct Matcher {
-    object: Box<MatcherTrait>,
+    kind: MatcherKind,
 }
 
-impl Matcher {
-    fn new<M: MatcherTrait + 'static>(m: M) -> Matcher {
-        Matcher {
-            object: Box::new(m),
-        }
-    }
+#[derive(Clone)]
+enum MatcherKind {
+    Regex(RegexMatcher),
+    RepeatRegex(RepeatRegexMatcher),
+    Wildcard(WildcardMatcher),
+    Empty(EmptyMatcher),
+    Paren(Box<ParenMatcher>),
+    Not(Box<NotMatcher>),
+    NotBelow(Box<NotBelowMatcher>),
+    Then(Box<ThenMatcher>),
+    Skip(Box<SkipMatcher>),
+    SkipNone(Box<SkipNoneMatcher>),
+    Or(Box<OrMatcher>),
+    Fraction(FractionMatcher),
+    RecurCount(RecurCountMatcher),
+    DepthRange(DepthRangeMatcher),
+    And(Box<AndMatcher>),
+    Exact(Box<ExactMatcher>),
+    Module(ModuleMatcher),
 }
 
-impl Clone for Matcher {
-    fn clone(&self) -> Matcher {
-        Matcher {
-            object: self.object.clone_object(),
-        }
+impl Matcher {
+    fn new(kind: MatcherKind) -> Matcher {
+        Matcher { kind }
     }
 }
 
 impl Debug for Matcher {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        write!(fmt, "{:?}", self.object)
+        match &self.kind {
+            MatcherKind::Regex(m) => write!(fmt, "{:?}", m),
+            MatcherKind::RepeatRegex(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Wildcard(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Empty(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Paren(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Not(m) => write!(fmt, "{:?}", m),
+            MatcherKind::NotBelow(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Then(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Skip(m) => write!(fmt, "{:?}", m),
+            MatcherKind::SkipNone(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Or(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Fraction(m) => write!(fmt, "{:?}", m),
+            MatcherKind::RecurCount(m) => write!(fmt, "{:?}", m),
+            MatcherKind::DepthRange(m) => write!(fmt, "{:?}", m),
+            MatcherKind::And(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Exact(m) => write!(fmt, "{:?}", m),
+            MatcherKind::Module(m) => write!(fmt, "{:?}", m),
+        }
     }
 }
 
+/// Which end of the stack `Matcher::search_trace_dir` starts from.
+/// Stacks are ordered from the root (outermost frame) to the leaf.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SearchDirection {
+    /// Find the outermost match. This is what `search_trace` does.
+    RootFirst,
+
+    /// Find the innermost match: the one starting at the deepest
+    /// frame.
+    LeafFirst,
+}
+
+/// Which of the places where a matcher matches a stack
+/// `Matcher::search_trace_nth` returns, counting in the order of the
+/// search. Two matches are different places if they start at
+/// different frames.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MatchIndex {
+    /// The `n`th match, counting from 1; `Nth(1)` is the match
+    /// `search_trace` finds.
+    Nth(usize),
+
+    /// The last match.
+    Last,
+}
+
 impl Matcher {
+    /// Like `search_trace`, but lets the caller pick which occurrence
+    /// is found when the matcher matches at several places.
+    ///
+    /// With `LeafFirst`, every starting frame is tried from the leaf
+    /// upwards and a failed `..` is not a cut: it only rules out the
+    /// starting frame it was tried from.
+    pub fn search_trace_dir<'stack>(
+        &self,
+        input: StackTrace<'stack>,
+        direction: SearchDirection,
+    ) -> Option<SearchResult> {
+        self.search_trace_nth_limited(input, direction, MatchIndex::Nth(1), usize::MAX)
+            .unwrap_or(None)
+    }
+
+    /// Like `search_trace_dir`, but gives up with `StepLimitExceeded`
+    /// after `max_steps` steps, where a step is one attempt to match
+    /// one part of the query at one frame.
+    ///
+    /// A plain query like `{a}..{b}..{c}` takes a number of steps
+    /// roughly linear in the depth of the stack, because a failed `..`
+    /// is a cut. But an alternation or a negation absorbs that cut and
+    /// lets the search carry on, so that each level of `..` nested
+    /// under one can multiply the work by the depth of the stack: on a
+    /// deep stack, `..(({a}..{b})/{x})` is quadratic. The limit keeps
+    /// such a query from hanging on one pathological sample.
+    pub fn search_trace_limited<'stack>(
+        &self,
+        input: StackTrace<'stack>,
+        direction: SearchDirection,
+        max_steps: usize,
+    ) -> Result<Option<SearchResult>, StepLimitExceeded> {
+        self.search_trace_nth_limited(input, direction, MatchIndex::Nth(1), max_steps)
+    }
+
+    /// Like `search_trace`, but returns the match picked by `index`
+    /// rather than the first one, or `None` if there are fewer
+    /// matches than that. After each match, the search carries on
+    /// from the frame below the one the match started at; a failed
+    /// `..` still cuts it short, so `{a}..{b}` finds no further match
+    /// once some `a` has no `b` below it.
+    pub fn search_trace_nth<'stack>(
+        &self,
+        input: StackTrace<'stack>,
+        index: MatchIndex,
+    ) -> Option<SearchResult> {
+        self.search_trace_nth_limited(input, SearchDirection::RootFirst, index, usize::MAX)
+            .unwrap_or(None)
+    }
+
+    /// Combines `search_trace_nth` and `search_trace_limited`. With
+    /// `LeafFirst`, matches are counted from the leaf upwards.
+    pub fn search_trace_nth_limited<'stack>(
+        &self,
+        input: StackTrace<'stack>,
+        direction: SearchDirection,
+        index: MatchIndex,
+        max_steps: usize,
+    ) -> Result<Option<SearchResult>, StepLimitExceeded> {
+        let state = &mut SearchState::new(max_steps);
+        let mut seen = 0;
+        let mut found = None;
+        self.search_dir(input, direction, state, |result| {
+            seen += 1;
+            found = Some(result);
+            index != MatchIndex::Nth(seen)
+        })?;
+        match index {
+            MatchIndex::Nth(n) if n != seen => Ok(None),
+            _ => Ok(found),
+        }
+    }
+
+    /// Calls `each` with every match of `self` in `input`, in the
+    /// order of `direction`, until it returns false.
+    fn search_dir<'stack, F>(
+        &self,
+        input: StackTrace<'stack>,
+        direction: SearchDirection,
+        state: &mut SearchState,
+        mut each: F,
+    ) -> Result<(), StepLimitExceeded>
+        where F: FnMut(SearchResult) -> bool
+    {
+        if let MatcherKind::And(m) = &self.kind {
+            return m.search_dir(input, direction, state, each);
+        }
+
+        // A whole-stack matcher must see every frame, so there is only
+        // one place to try it; so is there for any matcher when there
+        // are no frames.
+        if input.is_empty() || self.is_whole_stack() {
+            let result = if input.is_empty() {
+                self.search_empty(state)?
+            } else {
+                self.search_while(input, &empty_matcher(), state)?
+            };
+            if let Some(result) = result {
+                each(result);
+            }
+            return Ok(());
+        }
+
+        match direction {
+            SearchDirection::RootFirst => {
+                let mut start = 0;
+                while start < input.len() {
+                    state.reset((0, 0));
+                    let rest = &input[start..];
+                    let result = match self.search_while(rest, &empty_matcher(), state)? {
+                        Some(result) => SearchResult {
+                            first_matching_frame: start + result.first_matching_frame,
+                            first_callee_frame: start + result.first_callee_frame,
+                        },
+                        None => break,
+                    };
+                    if !each(result) {
+                        break;
+                    }
+                    start = result.first_matching_frame + 1;
+                }
+            }
+            SearchDirection::LeafFirst => {
+                for start in (0..input.len()).rev() {
+                    state.reset((0, 0));
+                    match self.match_trace(&input[start..], state) {
+                        Ok(suffix) => {
+                            let result = SearchResult {
+                                first_matching_frame: start,
+                                first_callee_frame: input.len() - suffix.len(),
+                            };
+                            if !each(result) {
+                                break;
+                            }
+                        }
+                        Err(MatchError::StepLimitExceeded) => return Err(StepLimitExceeded),
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Try to match `self` against `input`; if it fails, drop the
     /// bottom-most frame and match again. Keep doing this. If we ever
     /// find a match, return `Some`, else return `None`.
+    ///
+    /// An empty `input` matches only a matcher that can succeed without
+    /// consuming any frames, such as `()` or `!{a}`; the result is then
+    /// `SearchResult { first_matching_frame: 0, first_callee_frame: 0 }`.
     pub fn search_trace<'stack>(&self, input: StackTrace<'stack>) -> Option<SearchResult> {
         self.search_trace_while(input, &empty_matcher())
     }
@@ -109,53 +421,247 @@ impl Matcher {
         input: StackTrace<'stack>,
         condition: &Matcher,
     ) -> Option<SearchResult> {
+        let state = &mut SearchState::unlimited();
+        if let MatcherKind::And(m) = &self.kind {
+            let (reported, others) = m.split();
+            return match AndMatcher::all_match(&others, input, SearchDirection::RootFirst, state) {
+                Ok(true) => reported.search_trace_while(input, condition),
+                _ => None,
+            };
+        }
+        if input.is_empty() {
+            return self.search_empty(state).unwrap_or(None);
+        }
+        self.search_while(input, condition, state).unwrap_or(None)
+    }
+
+    /// Matches `self` against an empty stack. `search_while` would
+    /// give up at once, since there is no frame to start from, but at
+    /// the top level a sample with no frames should still match `()`.
+    /// (Inside a query, `search_while` keeps its behavior, so `{a}..()`
+    /// still needs a frame below the `a`.)
+    fn search_empty(&self, state: &mut SearchState)
+                    -> Result<Option<SearchResult>, StepLimitExceeded> {
+        match self.match_trace(&[], state) {
+            Ok(_) => Ok(Some(SearchResult { first_matching_frame: 0, first_callee_frame: 0 })),
+            Err(MatchError::StepLimitExceeded) => Err(StepLimitExceeded),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn search_while<'stack>(
+        &self,
+        input: StackTrace<'stack>,
+        condition: &Matcher,
+        state: &mut SearchState,
+    ) -> Result<Option<SearchResult>, StepLimitExceeded> {
         // Drop off frames from the top until we find a match. Return
         // the frames we dropped, and those that followed the match.
         let mut stack = input;
         let mut dropped = 0;
         while !stack.is_empty() {
-            match self.object.match_trace(stack) {
+            let mark = state.mark();
+            match self.match_trace(stack, state) {
                 Ok(suffix) => {
-                    return Some(SearchResult {
+                    return Ok(Some(SearchResult {
                         first_matching_frame: dropped,
                         first_callee_frame: input.len() - suffix.len(),
-                    });
+                    }));
                 }
                 Err(MatchError::RecoverableError) => {
-                    if condition.match_trace(stack).is_err() {
-                        return None;
+                    state.reset(mark);
+                    match condition.match_trace(stack, state) {
+                        Ok(_) => {}
+                        Err(MatchError::StepLimitExceeded) => return Err(StepLimitExceeded),
+                        Err(_) => return Ok(None),
                     }
                     dropped += 1;
                     stack = &stack[1..];
                 }
                 Err(MatchError::IrrecoverableError) => {
-                    return None;
+                    return Ok(None);
+                }
+                Err(MatchError::StepLimitExceeded) => {
+                    return Err(StepLimitExceeded);
                 }
             }
         }
-        None
+        Ok(None)
     }
 
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        self.object.match_trace(s)
+    /// Given a successful `result` from `search_trace` on `input`,
+    /// returns the index of the frame where the last `..` skip in
+    /// this matcher found its needle. For `{a}..{b}`, that is the
+    /// frame matching `b`. If the matcher contains no skip, this is
+    /// just `result.first_matching_frame`.
+    pub fn needle_frame(&self, input: StackTrace, result: SearchResult) -> usize {
+        let start = result.first_matching_frame;
+        let state = &mut SearchState::unlimited();
+        start + self.needle_offset(&input[start..], state).unwrap_or(0)
     }
 
-    fn is_empty(&self) -> bool {
-        self.object.is_empty()
+    /// Given a successful `result` from `search_trace` on `input`,
+    /// returns what the capture group `group` matched in the first
+    /// frame whose `{re}` or `{+re}` has such a group and took part in
+    /// the match (for `{+re}`, the first frame of the run). Like
+    /// `needle_frame`, this replays the match.
+    pub fn capture(&self, input: StackTrace, result: SearchResult, group: &str) -> Option<String> {
+        let mut state = SearchState::unlimited();
+        state.group = Some(group.to_string());
+        self.match_trace(&input[result.first_matching_frame..], &mut state).ok()?;
+        state.captures.into_iter().next()
     }
-}
 
-///////////////////////////////////////////////////////////////////////////
+    /// True if some `{re}` or `{+re}` in this matcher has a capture
+    /// group named `group`, so that `capture` can find it.
+    pub fn has_group(&self, group: &str) -> bool {
+        let has = |regex: &Regex| regex.capture_names().any(|name| name == Some(group));
+        match &self.kind {
+            MatcherKind::Regex(m) => has(&m.regex),
+            MatcherKind::RepeatRegex(m) => has(&m.regex),
+            MatcherKind::Paren(m) => m.matcher.has_group(group),
+            MatcherKind::Not(m) => m.matcher.has_group(group),
+            MatcherKind::NotBelow(m) => m.needle.has_group(group),
+            MatcherKind::Then(m) => m.left.has_group(group) || m.right.has_group(group),
+            MatcherKind::Skip(m) => m.needle.has_group(group),
+            MatcherKind::SkipNone(m) => m.needle.has_group(group),
+            MatcherKind::Or(m) => m.left.has_group(group) || m.right.has_group(group),
+            MatcherKind::And(m) => m.left.has_group(group) || m.right.has_group(group),
+            MatcherKind::Exact(m) => m.matcher.has_group(group),
+            MatcherKind::Wildcard(_) |
+            MatcherKind::Empty(_) |
+            MatcherKind::Fraction(_) |
+            MatcherKind::RecurCount(_) |
+            MatcherKind::DepthRange(_) |
+            MatcherKind::Module(_) => false,
+        }
+    }
+
+    /// Returns a matcher that matches a stack only if `self` matches
+    /// all of it, from the outermost frame to the innermost, as for
+    /// `--exact`. Fails if `self` skips frames (with `..`), since an
+    /// exact match may not have anything in between, or if it uses
+    /// `&`, whose clauses are each searched for anywhere.
+    pub fn exact(self) -> Result<Matcher, &'static str> {
+        if self.any(&|m| matches!(m.kind, MatcherKind::And(_))) {
+            return Err("an exact matcher cannot use `&`");
+        }
+        if self.any(&|m| {
+            matches!(m.kind,
+                     MatcherKind::Skip(_) | MatcherKind::SkipNone(_) | MatcherKind::NotBelow(_))
+        }) {
+            return Err("an exact matcher cannot skip frames with `..`");
+        }
+        Ok(Matcher::new(MatcherKind::Exact(Box::new(ExactMatcher { matcher: self }))))
+    }
+
+    /// True if `f` holds for this matcher or any matcher nested in it.
+    fn any(&self, f: &dyn Fn(&Matcher) -> bool) -> bool {
+        f(self) || match &self.kind {
+            MatcherKind::Paren(m) => m.matcher.any(f),
+            MatcherKind::Not(m) => m.matcher.any(f),
+            MatcherKind::NotBelow(m) => m.needle.any(f),
+            MatcherKind::Then(m) => m.left.any(f) || m.right.any(f),
+            MatcherKind::Skip(m) => m.needle.any(f) || m.condition.any(f),
+            MatcherKind::SkipNone(m) => m.needle.any(f),
+            MatcherKind::Or(m) => m.left.any(f) || m.right.any(f),
+            MatcherKind::And(m) => m.left.any(f) || m.right.any(f),
+            MatcherKind::Exact(m) => m.matcher.any(f),
+            MatcherKind::Regex(_) |
+            MatcherKind::RepeatRegex(_) |
+            MatcherKind::Wildcard(_) |
+            MatcherKind::Empty(_) |
+            MatcherKind::Fraction(_) |
+            MatcherKind::RecurCount(_) |
+            MatcherKind::DepthRange(_) |
+            MatcherKind::Module(_) => false,
+        }
+    }
+
+    /// Returns an equivalent matcher with the redundant parts folded
+    /// away: parentheses are dropped, empty matchers are removed from
+    /// sequences, and nested sequences and alternations are flattened
+    /// into right-associated chains, so that `(({a},{b}),{c})` becomes
+    /// `{a},{b},{c}`. The `Debug` output of the result shows its
+    /// structure, but since the parentheses are gone it is not
+    /// necessarily a valid query for the same thing.
+    pub fn simplify(&self) -> Matcher {
+        match &self.kind {
+            MatcherKind::Paren(m) => m.matcher.simplify(),
+            MatcherKind::Not(m) => NotMatcher::new(m.matcher.simplify()),
+            MatcherKind::NotBelow(m) => NotBelowMatcher::new(m.needle.simplify()),
+            MatcherKind::Then(m) => m.simplify(),
+            MatcherKind::Skip(m) => {
+                SkipMatcher::with_condition(m.needle.simplify(), m.condition.simplify())
+            }
+            MatcherKind::SkipNone(m) => SkipNoneMatcher::new(m.needle.simplify()),
+            MatcherKind::Or(m) => m.simplify(),
+            MatcherKind::And(m) => AndMatcher::new(m.left.simplify(), m.right.simplify()),
+            MatcherKind::Exact(m) => m.matcher.simplify().exact().unwrap(),
+            MatcherKind::Regex(_) |
+            MatcherKind::RepeatRegex(_) |
+            MatcherKind::Wildcard(_) |
+            MatcherKind::Empty(_) |
+            MatcherKind::Fraction(_) |
+            MatcherKind::RecurCount(_) |
+            MatcherKind::DepthRange(_) |
+            MatcherKind::Module(_) => self.clone(),
+        }
+    }
 
-trait MatcherTrait: Debug + 'static {
     /// Try to match `self` against `input` without skipping any frames.
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack>;
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        state.step()?;
+        match &self.kind {
+            MatcherKind::Regex(m) => m.match_trace(s, state),
+            MatcherKind::RepeatRegex(m) => m.match_trace(s, state),
+            MatcherKind::Wildcard(m) => m.match_trace(s),
+            MatcherKind::Empty(m) => m.match_trace(s),
+            MatcherKind::Paren(m) => m.matcher.match_trace(s, state),
+            MatcherKind::Not(m) => m.match_trace(s, state),
+            MatcherKind::NotBelow(m) => m.match_trace(s, state),
+            MatcherKind::Then(m) => m.match_trace(s, state),
+            MatcherKind::Skip(m) => m.match_trace(s, state),
+            MatcherKind::SkipNone(m) => m.match_trace(s, state),
+            MatcherKind::Or(m) => m.match_trace(s, state),
+            MatcherKind::Fraction(m) => m.match_trace(s),
+            MatcherKind::RecurCount(m) => m.match_trace(s),
+            MatcherKind::DepthRange(m) => m.match_trace(s),
+            MatcherKind::And(m) => m.split().0.match_trace(s, state),
+            MatcherKind::Exact(m) => m.match_trace(s, state),
+            MatcherKind::Module(m) => m.match_trace(s, state),
+        }
+    }
 
-    /// Clone this matcher.
-    fn clone_object(&self) -> Box<MatcherTrait>;
+    /// Assuming `self` matches at the start of `s`, returns the
+    /// offset within `s` of the needle found by the last `..` skip,
+    /// or `None` if there is no skip involved. This re-runs parts of
+    /// the match, so `state` must hold the bindings made before `s`.
+    fn needle_offset(&self, s: StackTrace, state: &mut SearchState) -> Option<usize> {
+        match &self.kind {
+            MatcherKind::Paren(m) => m.matcher.needle_offset(s, state),
+            MatcherKind::Then(m) => m.needle_offset(s, state),
+            MatcherKind::Skip(m) => m.needle_offset(s, state),
+            MatcherKind::Or(m) => m.needle_offset(s, state),
+            MatcherKind::And(m) => m.split().0.needle_offset(s, state),
+            _ => None,
+        }
+    }
 
     /// True if this is the empty matcher.
-    fn is_empty(&self) -> bool { false }
+    fn is_empty(&self) -> bool {
+        matches!(self.kind, MatcherKind::Empty(_))
+    }
+
+    /// True if this matcher judges the stack as a whole rather than
+    /// matching at a position, and so only makes sense at the top
+    /// level.
+    fn is_whole_stack(&self) -> bool {
+        matches!(self.kind,
+                 MatcherKind::Fraction(_) | MatcherKind::RecurCount(_) |
+                 MatcherKind::DepthRange(_) | MatcherKind::Exact(_))
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////
@@ -169,36 +675,38 @@ pub struct SearchResult {
 ///////////////////////////////////////////////////////////////////////////
 
 /// Consume any frame that matches the given regular expression.
+#[derive(Clone)]
 pub struct RegexMatcher {
     text: String,
     regex: Regex,
 }
 
+#[allow(clippy::new_ret_no_self)]
 impl RegexMatcher {
     pub fn new(r: &str) -> Matcher {
-        let regex = Regex::new(&r).unwrap();
-        Matcher::new(RegexMatcher {
+        Self::try_new(r).unwrap()
+    }
+
+    /// Like `new`, but reports an invalid regular expression rather
+    /// than panicking. The text is kept verbatim, so the `Debug`
+    /// output always shows exactly what was compiled.
+    pub fn try_new(r: &str) -> Result<Matcher, regex::Error> {
+        let regex = Regex::new(r)?;
+        Ok(Matcher::new(MatcherKind::Regex(RegexMatcher {
             text: r.to_string(),
-            regex: regex,
-        })
+            regex,
+        })))
     }
-}
 
-impl MatcherTrait for RegexMatcher {
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        if !s.is_empty() && self.regex.is_match(&s[0]) {
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        if !s.is_empty() && frame_matches(&self.regex, &s[0]) {
+            state.capture(&self.regex, &s[0]);
             Ok(&s[1..])
         } else {
             Err(MatchError::RecoverableError)
         }
     }
-
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(RegexMatcher {
-            text: self.text.clone(),
-            regex: self.regex.clone(),
-        })
-    }
 }
 
 impl Debug for RegexMatcher {
@@ -209,19 +717,59 @@ impl Debug for RegexMatcher {
 
 ///////////////////////////////////////////////////////////////////////////
 
-/// Consume any one frame.
-#[allow(dead_code)]
-pub struct WildcardMatcher {
-    dummy: (),
+/// Consume one or more consecutive frames that match the given regular
+/// expression. This is greedy and never gives frames back, so
+/// `{+a},{a}` can never match.
+#[derive(Clone)]
+pub struct RepeatRegexMatcher {
+    text: String,
+    regex: Regex,
 }
 
+#[allow(clippy::new_ret_no_self)]
+impl RepeatRegexMatcher {
+    pub fn new(r: &str) -> Matcher {
+        Self::try_new(r).unwrap()
+    }
+
+    pub fn try_new(r: &str) -> Result<Matcher, regex::Error> {
+        let regex = Regex::new(r)?;
+        Ok(Matcher::new(MatcherKind::RepeatRegex(RepeatRegexMatcher {
+            text: r.to_string(),
+            regex,
+        })))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        let count = s.iter().take_while(|frame| frame_matches(&self.regex, frame)).count();
+        if count > 0 {
+            state.capture(&self.regex, &s[0]);
+            Ok(&s[count..])
+        } else {
+            Err(MatchError::RecoverableError)
+        }
+    }
+}
+
+impl Debug for RepeatRegexMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{{+{}}}", self.text)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Consume any one frame.
+#[derive(Clone)]
+pub struct WildcardMatcher;
+
+#[allow(clippy::new_ret_no_self)]
 impl WildcardMatcher {
     pub fn new() -> Matcher {
-        Matcher::new(WildcardMatcher { dummy: () })
+        Matcher::new(MatcherKind::Wildcard(WildcardMatcher))
     }
-}
 
-impl MatcherTrait for WildcardMatcher {
     fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
         if !s.is_empty() {
             Ok(&s[1..])
@@ -229,10 +777,6 @@ impl MatcherTrait for WildcardMatcher {
             Err(MatchError::RecoverableError)
         }
     }
-
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(WildcardMatcher { dummy: () })
-    }
 }
 
 impl Debug for WildcardMatcher {
@@ -244,29 +788,18 @@ impl Debug for WildcardMatcher {
 ///////////////////////////////////////////////////////////////////////////
 
 /// Always succeeds, consuming no frames.
-#[allow(dead_code)]
-pub struct EmptyMatcher {
-    dummy: (),
-}
+#[derive(Clone)]
+pub struct EmptyMatcher;
 
+#[allow(clippy::new_ret_no_self)]
 impl EmptyMatcher {
     pub fn new() -> Matcher {
-        Matcher::new(EmptyMatcher { dummy: () })
+        Matcher::new(MatcherKind::Empty(EmptyMatcher))
     }
-}
 
-impl MatcherTrait for EmptyMatcher {
     fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
         Ok(s)
     }
-
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(EmptyMatcher { dummy: () })
-    }
-
-    fn is_empty(&self) -> bool {
-        true
-    }
 }
 
 impl Debug for EmptyMatcher {
@@ -278,25 +811,15 @@ impl Debug for EmptyMatcher {
 ///////////////////////////////////////////////////////////////////////////
 
 /// Try `matcher`.
+#[derive(Clone)]
 pub struct ParenMatcher {
     matcher: Matcher,
 }
 
+#[allow(clippy::new_ret_no_self)]
 impl ParenMatcher {
     pub fn new(other: Matcher) -> Matcher {
-        Matcher::new(ParenMatcher { matcher: other })
-    }
-}
-
-impl MatcherTrait for ParenMatcher {
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        self.matcher.match_trace(s)
-    }
-
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(ParenMatcher {
-            matcher: self.matcher.clone(),
-        })
+        Matcher::new(MatcherKind::Paren(Box::new(ParenMatcher { matcher: other })))
     }
 }
 
@@ -308,32 +831,33 @@ impl Debug for ParenMatcher {
 
 ///////////////////////////////////////////////////////////////////////////
 
-/// Try `matcher`; if it succeeds, fail. Otherwise, succeed, consuming no frames.
+/// `!M`: try `matcher` at the current position only; if it succeeds,
+/// fail. Otherwise, succeed, consuming no frames. So `{a},!{b}` means
+/// "an `a` whose direct callee is not `b`"; for "no `b` anywhere below
+/// `a`", see `NotBelowMatcher`.
+#[derive(Clone)]
 pub struct NotMatcher {
     matcher: Matcher,
 }
 
+#[allow(clippy::new_ret_no_self)]
 impl NotMatcher {
     pub fn new(other: Matcher) -> Matcher {
-        Matcher::new(NotMatcher { matcher: other })
+        Matcher::new(MatcherKind::Not(Box::new(NotMatcher { matcher: other })))
     }
-}
 
-impl MatcherTrait for NotMatcher {
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        // Make sure that `self.matcher` doesn't match *anywhere* in
-        // the trace:
-        match self.matcher.match_trace(s) {
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        // Bindings made inside the negation don't escape it.
+        let mark = state.mark();
+        let result = self.matcher.match_trace(s, state);
+        state.reset(mark);
+        match result {
             Ok(_) => Err(MatchError::RecoverableError),
+            Err(MatchError::StepLimitExceeded) => Err(MatchError::StepLimitExceeded),
             Err(_) => Ok(s),
         }
     }
-
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(NotMatcher {
-            matcher: self.matcher.clone(),
-        })
-    }
 }
 
 impl Debug for NotMatcher {
@@ -344,33 +868,94 @@ impl Debug for NotMatcher {
 
 ///////////////////////////////////////////////////////////////////////////
 
+/// `!..M`: succeed, consuming no frames, if `needle` matches at no
+/// position in the rest of the trace. Failure is recoverable, so
+/// `{a},!..{b}` goes on to try later `a`s; compare `SkipNoneMatcher`
+/// (`{a}..!{b}`), whose failure is a cut.
+#[derive(Clone)]
+pub struct NotBelowMatcher {
+    needle: Matcher,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl NotBelowMatcher {
+    pub fn new(needle: Matcher) -> Matcher {
+        Matcher::new(MatcherKind::NotBelow(Box::new(NotBelowMatcher { needle })))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        // Bindings made inside the negation don't escape it.
+        let mark = state.mark();
+        let result = self.needle.search_while(s, &empty_matcher(), state);
+        state.reset(mark);
+        match result {
+            Ok(Some(_)) => Err(MatchError::RecoverableError),
+            Ok(None) => Ok(s),
+            Err(StepLimitExceeded) => Err(MatchError::StepLimitExceeded),
+        }
+    }
+}
+
+impl Debug for NotBelowMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "!..{:?}", self.needle)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
 /// Try `left` then try `right` on what follows.
+#[derive(Clone)]
 pub struct ThenMatcher {
     left: Matcher,
     right: Matcher,
 }
 
+#[allow(clippy::new_ret_no_self)]
 impl ThenMatcher {
     pub fn new(left: Matcher, right: Matcher) -> Matcher {
-        Matcher::new(ThenMatcher {
-            left: left,
-            right: right,
-        })
+        Matcher::new(MatcherKind::Then(Box::new(ThenMatcher { left, right })))
     }
-}
 
-impl MatcherTrait for ThenMatcher {
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        let t = self.left.match_trace(s)?;
-        let u = self.right.match_trace(t)?;
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        let t = self.left.match_trace(s, state)?;
+        let u = self.right.match_trace(t, state)?;
         Ok(u)
     }
 
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(ThenMatcher {
-            left: self.left.clone(),
-            right: self.right.clone(),
-        })
+    fn needle_offset(&self, s: StackTrace, state: &mut SearchState) -> Option<usize> {
+        let mark = state.mark();
+        let t = match self.left.match_trace(s, state) {
+            Ok(t) => t,
+            Err(_) => return None,
+        };
+        let consumed = s.len() - t.len();
+        if let Some(n) = self.right.needle_offset(t, state) {
+            return Some(consumed + n);
+        }
+        state.reset(mark);
+        self.left.needle_offset(s, state)
+    }
+
+    fn simplify(&self) -> Matcher {
+        let left = self.left.simplify();
+        let right = self.right.simplify();
+        if left.is_empty() {
+            return right;
+        }
+        if right.is_empty() {
+            return left;
+        }
+        match left.kind {
+            // `(a,b),c` is `a,(b,c)`
+            MatcherKind::Then(inner) => {
+                let ThenMatcher { left: a, right: b } = *inner;
+                ThenMatcher::new(a, ThenMatcher::new(b, right).simplify())
+            }
+            kind => ThenMatcher::new(Matcher::new(kind), right),
+        }
     }
 }
 
@@ -384,36 +969,42 @@ impl Debug for ThenMatcher {
 
 /// Try `needle`: if it succeds, we are done. If it fails, test condition.
 /// If condition fails, then we fail. Otherwise, drop the frame and continue.
+#[derive(Clone)]
 pub struct SkipMatcher {
     needle: Matcher,
     condition: Matcher,
 }
 
+#[allow(clippy::new_ret_no_self)]
 impl SkipMatcher {
     pub fn new(needle: Matcher) -> Matcher {
         Self::with_condition(needle, empty_matcher())
     }
 
     pub fn with_condition(needle: Matcher, condition: Matcher) -> Matcher {
-        Matcher::new(SkipMatcher { needle, condition })
+        Matcher::new(MatcherKind::Skip(Box::new(SkipMatcher { needle, condition })))
     }
-}
 
-impl MatcherTrait for SkipMatcher {
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        match self.needle.search_trace_while(s, &self.condition) {
-            Some(SearchResult {
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        match self.needle.search_while(s, &self.condition, state) {
+            Ok(Some(SearchResult {
                 first_callee_frame, ..
-            }) => Ok(&s[first_callee_frame..]),
-            None => Err(MatchError::IrrecoverableError),
+            })) => Ok(&s[first_callee_frame..]),
+            Ok(None) => Err(MatchError::IrrecoverableError),
+            Err(StepLimitExceeded) => Err(MatchError::StepLimitExceeded),
         }
     }
 
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(SkipMatcher {
-            needle: self.needle.clone(),
-            condition: self.condition.clone(),
-        })
+    fn needle_offset(&self, s: StackTrace, state: &mut SearchState) -> Option<usize> {
+        let mark = state.mark();
+        let first_matching_frame = match self.needle.search_while(s, &self.condition, state) {
+            Ok(Some(result)) => result.first_matching_frame,
+            _ => return None,
+        };
+        let found = &s[first_matching_frame..];
+        state.reset(mark);
+        Some(first_matching_frame + self.needle.needle_offset(found, state).unwrap_or(0))
     }
 }
 
@@ -429,31 +1020,225 @@ impl Debug for SkipMatcher {
 
 ///////////////////////////////////////////////////////////////////////////
 
+/// Succeed, consuming no frames, if `needle` matches nowhere in the
+/// rest of the trace. If it does match somewhere, fail with a cut
+/// (`IrrecoverableError`), just like a failed `..` skip does, so that
+/// `{a}..!{b}` looks only at the first `a` rather than searching on for
+/// some later `a` with no `b` below it.
+///
+/// This differs from `!..{b}` (a `NotBelowMatcher`), whose failure is
+/// recoverable.
+#[derive(Clone)]
+pub struct SkipNoneMatcher {
+    needle: Matcher,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl SkipNoneMatcher {
+    pub fn new(needle: Matcher) -> Matcher {
+        Matcher::new(MatcherKind::SkipNone(Box::new(SkipNoneMatcher { needle })))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        let mark = state.mark();
+        let result = self.needle.search_while(s, &empty_matcher(), state);
+        state.reset(mark);
+        match result {
+            Ok(Some(_)) => Err(MatchError::IrrecoverableError),
+            Ok(None) => Ok(s),
+            Err(StepLimitExceeded) => Err(MatchError::StepLimitExceeded),
+        }
+    }
+}
+
+impl Debug for SkipNoneMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "..!{:?}", self.needle)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Succeed if at least `percent` percent of the frames match the given
+/// regular expression, consuming the whole trace. This looks at the
+/// stack as a whole rather than at a position, so it may only be used
+/// as the entire query (`{>=50%:re}`); the parser rejects it anywhere
+/// else. Failure is a cut, so a search never retries it on a shorter
+/// suffix of the stack.
+#[derive(Clone)]
+pub struct FractionMatcher {
+    percent: f64,
+    text: String,
+    regex: Regex,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl FractionMatcher {
+    pub fn new(percent: f64, r: &str) -> Matcher {
+        Self::try_new(percent, r).unwrap()
+    }
+
+    pub fn try_new(percent: f64, r: &str) -> Result<Matcher, regex::Error> {
+        let regex = Regex::new(r)?;
+        Ok(Matcher::new(MatcherKind::Fraction(FractionMatcher {
+            percent,
+            text: r.to_string(),
+            regex,
+        })))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
+        let count = s.iter().filter(|frame| frame_matches(&self.regex, frame)).count();
+        if !s.is_empty() && count as f64 * 100.0 >= self.percent * s.len() as f64 {
+            Ok(&s[s.len()..])
+        } else {
+            Err(MatchError::IrrecoverableError)
+        }
+    }
+}
+
+impl Debug for FractionMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{{>={}%:{}}}", self.percent, self.text)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Succeed if at least `count` frames, anywhere in the trace, match the
+/// given regular expression, consuming the whole trace. Unlike
+/// `{re{5,}}`, the frames need not be consecutive. Like
+/// `FractionMatcher`, this may only be the entire query
+/// (`{>=5#:re}`), and its failure is a cut.
+#[derive(Clone)]
+pub struct RecurCountMatcher {
+    count: usize,
+    text: String,
+    regex: Regex,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl RecurCountMatcher {
+    pub fn new(count: usize, r: &str) -> Matcher {
+        Self::try_new(count, r).unwrap()
+    }
+
+    pub fn try_new(count: usize, r: &str) -> Result<Matcher, regex::Error> {
+        let regex = Regex::new(r)?;
+        Ok(Matcher::new(MatcherKind::RecurCount(RecurCountMatcher {
+            count,
+            text: r.to_string(),
+            regex,
+        })))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
+        let count = s.iter().filter(|frame| frame_matches(&self.regex, frame)).count();
+        if !s.is_empty() && count >= self.count {
+            Ok(&s[s.len()..])
+        } else {
+            Err(MatchError::IrrecoverableError)
+        }
+    }
+}
+
+impl Debug for RecurCountMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{{>={}#:{}}}", self.count, self.text)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Succeed, consuming the whole trace, if it has at least `min` and at
+/// most `max` frames. Written `depth(N)`, `depth(>=N)`, `depth(>N)`,
+/// `depth(<=N)`, `depth(<N)`, or `depth(N..M)` for N to M frames
+/// inclusive. Like `FractionMatcher`, this judges the whole stack: it
+/// may only be the entire query or a clause of `&`, and its failure is
+/// a cut.
+#[derive(Clone)]
+pub struct DepthRangeMatcher {
+    min: usize,
+    max: usize,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl DepthRangeMatcher {
+    pub fn new(min: usize, max: usize) -> Matcher {
+        Matcher::new(MatcherKind::DepthRange(DepthRangeMatcher { min, max }))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
+        if self.min <= s.len() && s.len() <= self.max {
+            Ok(&s[s.len()..])
+        } else {
+            Err(MatchError::IrrecoverableError)
+        }
+    }
+}
+
+impl Debug for DepthRangeMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match (self.min, self.max) {
+            (min, usize::MAX) => write!(fmt, "depth(>={})", min),
+            (0, max) => write!(fmt, "depth(<={})", max),
+            (min, max) if min == max => write!(fmt, "depth({})", min),
+            (min, max) => write!(fmt, "depth({}..{})", min, max),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
 /// Try `left` first; if it fails, try `right.
+#[derive(Clone)]
 pub struct OrMatcher {
     left: Matcher,
     right: Matcher,
 }
 
+#[allow(clippy::new_ret_no_self)]
 impl OrMatcher {
     pub fn new(left: Matcher, right: Matcher) -> Matcher {
-        Matcher::new(OrMatcher {
-            left: left,
-            right: right,
-        })
+        Matcher::new(MatcherKind::Or(Box::new(OrMatcher { left, right })))
     }
-}
 
-impl MatcherTrait for OrMatcher {
-    fn match_trace<'stack>(&self, s: StackTrace<'stack>) -> MatchResult<'stack> {
-        self.left.match_trace(s).or_else(|_| self.right.match_trace(s))
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        let mark = state.mark();
+        match self.left.match_trace(s, state) {
+            Err(MatchError::StepLimitExceeded) => Err(MatchError::StepLimitExceeded),
+            Err(_) => {
+                state.reset(mark);
+                self.right.match_trace(s, state)
+            }
+            ok => ok,
+        }
+    }
+
+    fn needle_offset(&self, s: StackTrace, state: &mut SearchState) -> Option<usize> {
+        let mark = state.mark();
+        let left_matches = self.left.match_trace(s, state).is_ok();
+        state.reset(mark);
+        if left_matches {
+            self.left.needle_offset(s, state)
+        } else {
+            self.right.needle_offset(s, state)
+        }
     }
 
-    fn clone_object(&self) -> Box<MatcherTrait> {
-        Box::new(OrMatcher {
-            left: self.left.clone(),
-            right: self.right.clone(),
-        })
+    fn simplify(&self) -> Matcher {
+        let left = self.left.simplify();
+        let right = self.right.simplify();
+        match left.kind {
+            // `(a/b)/c` is `a/(b/c)`
+            MatcherKind::Or(inner) => {
+                let OrMatcher { left: a, right: b } = *inner;
+                OrMatcher::new(a, OrMatcher::new(b, right).simplify())
+            }
+            kind => OrMatcher::new(Matcher::new(kind), right),
+        }
     }
 }
 
@@ -462,3 +1247,197 @@ impl Debug for OrMatcher {
         write!(fmt, "{:?}/{:?}", self.left, self.right)
     }
 }
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Match `matcher` against the whole trace: it must start at the
+/// outermost frame and consume every frame down to the innermost.
+/// Built by `Matcher::exact`. This judges the whole stack, so its
+/// failure is a cut.
+#[derive(Clone)]
+pub struct ExactMatcher {
+    matcher: Matcher,
+}
+
+impl ExactMatcher {
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        match self.matcher.match_trace(s, state) {
+            Ok(rest) if rest.is_empty() => Ok(rest),
+            Err(MatchError::StepLimitExceeded) => Err(MatchError::StepLimitExceeded),
+            _ => Err(MatchError::IrrecoverableError),
+        }
+    }
+}
+
+impl Debug for ExactMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "exact({:?})", self.matcher)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// `M & N`: the sample must match both `M` and `N`, each searched for
+/// on its own in the whole stack. This is only allowed at the top of
+/// a query, which is what lets a whole-stack matcher such as
+/// `depth(>20)` be combined with a positional one, as in
+/// `{a} & depth(>20)`. The match reported (and used to split callers
+/// from callees) is that of the first clause that does not judge the
+/// whole stack.
+#[derive(Clone)]
+pub struct AndMatcher {
+    left: Matcher,
+    right: Matcher,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl AndMatcher {
+    pub fn new(left: Matcher, right: Matcher) -> Matcher {
+        Matcher::new(MatcherKind::And(Box::new(AndMatcher { left, right })))
+    }
+
+    /// The clause whose match is reported, and the others.
+    fn split(&self) -> (&Matcher, Vec<&Matcher>) {
+        let mut clauses = vec![&self.left];
+        let mut rest = &self.right;
+        while let MatcherKind::And(m) = &rest.kind {
+            clauses.push(&m.left);
+            rest = &m.right;
+        }
+        clauses.push(rest);
+
+        let reported = clauses.iter().position(|m| !m.is_whole_stack()).unwrap_or(0);
+        let reported = clauses.remove(reported);
+        (reported, clauses)
+    }
+
+    /// True if each of `clauses` matches somewhere in `input`.
+    fn all_match(
+        clauses: &[&Matcher],
+        input: StackTrace,
+        direction: SearchDirection,
+        state: &mut SearchState,
+    ) -> Result<bool, StepLimitExceeded> {
+        for clause in clauses {
+            let mut found = false;
+            state.reset((0, 0));
+            clause.search_dir(input, direction, state, |_| {
+                found = true;
+                false
+            })?;
+            if !found {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn search_dir<F>(
+        &self,
+        input: StackTrace,
+        direction: SearchDirection,
+        state: &mut SearchState,
+        each: F,
+    ) -> Result<(), StepLimitExceeded>
+        where F: FnMut(SearchResult) -> bool
+    {
+        let (reported, others) = self.split();
+        if AndMatcher::all_match(&others, input, direction, state)? {
+            state.reset((0, 0));
+            reported.search_dir(input, direction, state, each)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for AndMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{:?} & {:?}", self.left, self.right)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Consume a frame (matching the given regular expression, if any)
+/// whose module is the one bound to `name`. The first `{mod=$name}`
+/// to match in a search binds `name` to the module of its frame; the
+/// others must then match a frame in that same module. Bindings last
+/// for one top-level match: a new attempt (at the next starting
+/// frame, or in the other branch of `/`) starts afresh, and bindings
+/// made under `!` are dropped. See `module_of` for what a module is.
+#[derive(Clone)]
+pub struct ModuleMatcher {
+    name: String,
+    text: String,
+    regex: Regex,
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl ModuleMatcher {
+    pub fn new(name: &str, r: &str) -> Matcher {
+        Self::try_new(name, r).unwrap()
+    }
+
+    pub fn try_new(name: &str, r: &str) -> Result<Matcher, regex::Error> {
+        let regex = Regex::new(r)?;
+        Ok(Matcher::new(MatcherKind::Module(ModuleMatcher {
+            name: name.to_string(),
+            text: r.to_string(),
+            regex,
+        })))
+    }
+
+    fn match_trace<'stack>(&self, s: StackTrace<'stack>, state: &mut SearchState)
+                           -> MatchResult<'stack> {
+        let module = match s.first() {
+            Some(frame) if self.regex.is_match(frame) => module_of(frame),
+            _ => None,
+        };
+        let module = match module {
+            Some(module) => module,
+            None => return Err(MatchError::RecoverableError),
+        };
+        match state.module(&self.name) {
+            Some(bound) if bound != module => return Err(MatchError::RecoverableError),
+            Some(_) => {}
+            None => state.modules.push((self.name.clone(), module.to_string())),
+        }
+        Ok(&s[1..])
+    }
+}
+
+impl Debug for ModuleMatcher {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        if self.text.is_empty() {
+            write!(fmt, "{{mod=${}}}", self.name)
+        } else {
+            write!(fmt, "{{mod=${}:{}}}", self.name, self.text)
+        }
+    }
+}
+
+/// True if `regex` matches `frame` or, for a frame that perf left
+/// mangled, its demangled form; so `{drop_in_place}` finds both
+/// spellings of the function.
+fn frame_matches(regex: &Regex, frame: &str) -> bool {
+    regex.is_match(frame) || with_demangled(frame, |name| name.is_some_and(|n| regex.is_match(n)))
+}
+
+/// The module of a frame is its path without the last segment, so
+/// `rustc::ty::fold::fold_with` is in `rustc::ty::fold`. `::` inside
+/// generic arguments doesn't count. Frames with no path (such as C
+/// functions) have no module.
+fn module_of(frame: &str) -> Option<&str> {
+    let mut depth = 0;
+    let mut last = None;
+    for (i, c) in frame.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ':' if depth == 0 && frame[i..].starts_with("::") => last = Some(i),
+            _ => {}
+        }
+    }
+    last.filter(|&i| i > 0).map(|i| &frame[..i])
+}
@@ -1,35 +1,58 @@
 #![allow(dead_code)]
+// `rusty_peg!` expands to `try!`
+#![allow(deprecated)]
 
 use super::*;
 use rusty_peg::{Error, Symbol, Input, ParseResult};
 
+// Precedence, from tightest to loosest:
+//
+// | level      | syntax                                 | rule     |
+// |------------|----------------------------------------|----------|
+// | atom       | `{re}`, `{+re}`, `{mod=$x}`, `.`, `()`, `(M)`, `!M`, `!..M` | MATCHER0 |
+// | prefix `..`| `..M`                                  | MATCHER0 |
+// | or         | `M/N`  (right-assoc)                   | MATCHER1 |
+// | sequence   | `M,N`, `M..N`, `M..!N`, `!M..N`        | MATCHER  |
+// | and        | `M & N`  (top level only)              | QUERY    |
+//
+// So `{a}/{b},{c}` is `({a}/{b}),{c}`, `{a},{b}/{c}` is `{a},({b}/{c})`,
+// and `{a}..{b}/{c}` is `{a}..({b}/{c})`. The prefix `!` only applies to
+// an atom (`!{a}/{b}` is `(!{a})/{b}`), while the prefix `..` takes a
+// whole alternation (`..{a}/{b}` is `..({a}/{b})`, and `!..{a}/{b}` is
+// `!..({a}/{b})`). Sequences are right-associative.
 rusty_peg! {
-    parser Parser<'input> {
+    parser Parser<'input>: BraceError {
+        QUERY: Matcher =
+            (QUERY_AND / MATCHER);
+
+        QUERY_AND: Matcher =
+            (<lhs:MATCHER>, "&", <rhs:QUERY>) => AndMatcher::new(lhs, rhs);
+
         MATCHER: Matcher = (
             MATCHER_COMMA_MATCHER /
                 MATCHER_NOT_THEN_MATCHER /
                 MATCHER_THEN_NOT_MATCHER /
                 MATCHER_SKIP_MATCHER /
-                MATCHER0
+                MATCHER1
         );
 
         MATCHER_COMMA_MATCHER: Matcher =
-            (<lhs:MATCHER0>, ",", <rhs:MATCHER>) => {
+            (<lhs:MATCHER1>, ",", <rhs:MATCHER>) => {
                 ThenMatcher::new(lhs, rhs)
             };
 
         MATCHER_THEN_NOT_MATCHER: Matcher =
-            (<lhs:MATCHER0>, "..", "!", <rhs:MATCHER0>) => {
-                ThenMatcher::new(lhs, NotMatcher::new(SkipMatcher::new(rhs)))
+            (<lhs:MATCHER1>, "..", "!", <rhs:MATCHER1>) => {
+                ThenMatcher::new(lhs, SkipNoneMatcher::new(rhs))
             };
 
         MATCHER_SKIP_MATCHER: Matcher =
-            (<lhs:MATCHER0>, "..", <rhs:MATCHER>) => {
+            (<lhs:MATCHER1>, "..", <rhs:MATCHER>) => {
                 ThenMatcher::new(lhs, SkipMatcher::new(rhs))
             };
 
         MATCHER_NOT_THEN_MATCHER: Matcher =
-            ("!", <lhs:MATCHER0>, "..", <rhs:MATCHER0>) => {
+            ("!", <lhs:MATCHER0>, "..", <rhs:MATCHER1>) => {
                 SkipMatcher::with_condition(rhs, NotMatcher::new(lhs))
             };
 
@@ -40,10 +63,20 @@ rusty_peg! {
             (<lhs:MATCHER0>, "/", <rhs:MATCHER1>) => OrMatcher::new(lhs, rhs);
 
         MATCHER0: Matcher =
-            (MATCHER_RE / MATCHER_SKIP / MATCHER_PAREN / MATCHER_ANY);
+            (MATCHER_RE / MATCHER_DEPTH / MATCHER_SKIP / MATCHER_NOT_BELOW / MATCHER_NOT /
+             MATCHER_EMPTY / MATCHER_PAREN / MATCHER_ANY);
 
         MATCHER_SKIP: Matcher =
-            ("..", <rhs:MATCHER0>) => SkipMatcher::new(rhs);
+            ("..", <rhs:MATCHER1>) => SkipMatcher::new(rhs);
+
+        MATCHER_NOT_BELOW: Matcher =
+            ("!", "..", <rhs:MATCHER1>) => NotBelowMatcher::new(rhs);
+
+        MATCHER_NOT: Matcher =
+            ("!", <rhs:MATCHER0>) => NotMatcher::new(rhs);
+
+        MATCHER_EMPTY: Matcher =
+            ("(", ")") => EmptyMatcher::new();
 
         MATCHER_PAREN: Matcher =
             ("(", <rhs:MATCHER>, ")") => ParenMatcher::new(rhs);
@@ -53,6 +86,13 @@ rusty_peg! {
     }
 }
 
+/// The furthest error found inside a `{...}`, if any. rusty-peg only
+/// reports the error of the last alternative it tried, which for a bad
+/// `{...}` is whatever the last rule of `MATCHER0` expected; but no
+/// other rule can parse a `{`, so when the parse fails, this error is
+/// the one to report (see `parse_matcher`).
+pub type BraceError = Option<Error<'static>>;
+
 #[allow(non_camel_case_types)]
 pub struct MATCHER_RE;
 
@@ -60,43 +100,244 @@ impl<'input> Symbol<'input, Parser<'input>> for MATCHER_RE {
     type Output = Matcher;
 
     fn pretty_print(&self) -> String {
-        format!("MATCHER_RE")
+        "MATCHER_RE".to_string()
     }
 
-    fn parse(&self, _: &mut Parser<'input>, input: Input<'input>)
+    fn parse(&self, parser: &mut Parser<'input>, input: Input<'input>)
              -> ParseResult<'input,Matcher>
     {
-        let bytes = input.text.as_bytes();
-        let mut offset = input.offset;
-
-        if offset >= input.text.len() || bytes[offset] != ('{' as u8) {
+        if !input.text[input.offset..].starts_with('{') {
             return Err(Error { expected: "'{' character",
                                offset: input.offset });
         }
 
-        let mut balance = 1;
-        while balance != 0 {
-            offset += 1;
+        match parse_braces(input) {
+            Ok(output) => Ok(output),
+            Err(err) => {
+                if parser.base.as_ref().is_none_or(|e| e.offset <= err.offset) {
+                    parser.base = Some(Error { expected: err.expected, offset: err.offset });
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Parses the `{...}` at the start of `input`.
+fn parse_braces(input: Input) -> Result<(Input, Matcher), Error<'static>> {
+    let bytes = input.text.as_bytes();
+    let mut offset = input.offset;
+
+    let mut balance = 1;
+    while balance != 0 {
+        offset += 1;
+
+        if offset >= input.text.len() {
+            return Err(Error { expected: "matching '}' character",
+                               offset });
+        }
 
-            if offset >= input.text.len() {
-                return Err(Error { expected: "matching '}' character",
-                                   offset: offset });
+        if bytes[offset] == b'{' {
+            // A quantifier like `{2}` or `{1,3}` belongs to the
+            // regex; skip it whole so that it never affects the
+            // balance, even if the braces around it are lopsided.
+            if let Some(len) = quantifier_len(&bytes[offset..]) {
+                offset += len - 1;
+                continue;
             }
+            balance += 1;
+        } else if bytes[offset] == b'}' {
+            balance -= 1;
+        } else if bytes[offset] == b'\\' {
+            if offset + 1 >= input.text.len() {
+                return Err(Error { expected: "a character after '\\'",
+                                   offset: offset + 1 });
+            }
+            offset += 1; // skip next character
+        }
+    }
+
+    // `offset` is at the final `}`, which the loop checked is there
+    offset += 1; // consume final `}`
 
-            if bytes[offset] == ('{' as u8) {
-                balance += 1;
-            } else if bytes[offset] == ('}' as u8) {
-                balance -= 1;
-            } else if bytes[offset] == ('\\' as u8) {
-                offset += 1; // skip next character
+    let regex_str = &input.text[input.offset + 1 .. offset - 1];
+
+    // `{}` is `.`: one frame, whatever its name (the empty regex would
+    // say the same, but less plainly)
+    if regex_str.is_empty() {
+        return Ok((Input { text: input.text, offset }, WildcardMatcher::new()));
+    }
+
+    // `{>=N%:regex}` and `{>=N#:regex}` judge the whole stack, so
+    // they must be the entire query (or a clause of `&`)
+    if let Some(rest) = regex_str.strip_prefix(">=") {
+        if !is_whole_query(input.text, input.offset, offset) {
+            return Err(Error { expected: "`{>=N%:regex}` or `{>=N#:regex}` only as the \
+                                          whole matcher or a clause of `&`",
+                               offset: input.offset });
+        }
+        if let Some(i) = rest.find("#:") {
+            let count: usize = match rest[..i].parse() {
+                Ok(n) => n,
+                Err(_) => {
+                    return Err(Error { expected: "a count",
+                                       offset: input.offset + 3 });
+                }
+            };
+            return match RecurCountMatcher::try_new(count, &rest[i + 2..]) {
+                Ok(m) => Ok((Input { text: input.text, offset }, m)),
+                Err(_) => Err(Error { expected: "valid regular expression",
+                                      offset: input.offset + 1 }),
+            };
+        }
+        let (percent, regex) = match rest.find("%:") {
+            Some(i) => (&rest[..i], &rest[i + 2..]),
+            None => {
+                return Err(Error { expected: "`%:` or `#:` after the number",
+                                   offset: input.offset + 3 });
             }
+        };
+        let percent: f64 = match percent.parse() {
+            Ok(p) => p,
+            Err(_) => {
+                return Err(Error { expected: "a percentage",
+                                   offset: input.offset + 3 });
+            }
+        };
+        return match FractionMatcher::try_new(percent, regex) {
+            Ok(m) => Ok((Input { text: input.text, offset }, m)),
+            Err(_) => Err(Error { expected: "valid regular expression",
+                                  offset: input.offset + 1 }),
+        };
+    }
+
+    // `{mod=$name}` or `{mod=$name:regex}` matches a frame in the
+    // module bound to `name`
+    if let Some(rest) = regex_str.strip_prefix("mod=$") {
+        let (name, regex) = match rest.find(':') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(Error { expected: "a name after `mod=$`",
+                               offset: input.offset + 6 });
         }
+        return match ModuleMatcher::try_new(name, regex) {
+            Ok(m) => Ok((Input { text: input.text, offset }, m)),
+            Err(_) => Err(Error { expected: "valid regular expression",
+                                  offset: input.offset + 1 }),
+        };
+    }
 
-        offset += 1; // consume final `}`
+    // `{+regex}` matches a run of one or more frames
+    let regex = match regex_str.strip_prefix('+') {
+        Some(run) => RepeatRegexMatcher::try_new(run),
+        None => RegexMatcher::try_new(regex_str),
+    };
+    let regex: Matcher = match regex {
+        Ok(m) => m,
+        Err(_) => {
+            return Err(Error { expected: "valid regular expression",
+                               offset: input.offset + 1 });
+        }
+    };
+    let output = Input { text: input.text, offset };
+    Ok((output, regex))
+}
+
+#[allow(non_camel_case_types)]
+pub struct MATCHER_DEPTH;
+
+impl<'input> Symbol<'input, Parser<'input>> for MATCHER_DEPTH {
+    type Output = Matcher;
+
+    fn pretty_print(&self) -> String {
+        "MATCHER_DEPTH".to_string()
+    }
+
+    fn parse(&self, _: &mut Parser<'input>, input: Input<'input>)
+             -> ParseResult<'input,Matcher>
+    {
+        let start = input.offset;
+        let text = &input.text[start..];
+        if !text.starts_with("depth(") {
+            return Err(Error { expected: "`depth(`", offset: start });
+        }
+        let close = match text.find(')') {
+            Some(close) => close,
+            None => return Err(Error { expected: "`)` after the depth", offset: start + 6 }),
+        };
+        let end = start + close + 1;
+
+        // like `{>=N%:regex}`, `depth(..)` judges the whole stack
+        if !is_whole_query(input.text, start, end) {
+            return Err(Error { expected: "`depth(..)` only as the whole matcher or a clause \
+                                          of `&`",
+                               offset: start });
+        }
+
+        match depth_range(text[6..close].trim()) {
+            Some((min, max)) => {
+                Ok((Input { text: input.text, offset: end }, DepthRangeMatcher::new(min, max)))
+            }
+            None => Err(Error { expected: "a depth like `10`, `>=10`, `<10` or `3..8`",
+                                offset: start + 6 }),
+        }
+    }
+}
+
+/// Parses what goes between the parentheses of `depth(..)` into an
+/// inclusive range of depths.
+fn depth_range(spec: &str) -> Option<(usize, usize)> {
+    let number = |s: &str| s.trim().parse::<usize>().ok();
+    if let Some(n) = spec.strip_prefix(">=") {
+        Some((number(n)?, usize::MAX))
+    } else if let Some(n) = spec.strip_prefix("<=") {
+        Some((0, number(n)?))
+    } else if let Some(n) = spec.strip_prefix('>') {
+        Some((number(n)?.checked_add(1)?, usize::MAX))
+    } else if let Some(n) = spec.strip_prefix('<') {
+        Some((0, number(n)?.checked_sub(1)?))
+    } else if let Some(i) = spec.find("..") {
+        let (min, max) = (number(&spec[..i])?, number(&spec[i + 2..])?);
+        if min <= max { Some((min, max)) } else { None }
+    } else {
+        let n = number(spec)?;
+        Some((n, n))
+    }
+}
+
+/// True if the text from `start` to `end` of `text` is the whole
+/// query, or one of the clauses of a top-level `&`, which is where a
+/// matcher that judges the whole stack may go.
+fn is_whole_query(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].trim_end();
+    let after = text[end..].trim_start();
+    (before.is_empty() || before.ends_with('&')) && (after.is_empty() || after.starts_with('&'))
+}
+
+/// If `bytes` begins with a regex repetition quantifier (`{n}`,
+/// `{n,}` or `{n,m}`), returns its length in bytes.
+fn quantifier_len(bytes: &[u8]) -> Option<usize> {
+    let digits = |from: usize| {
+        bytes[from..].iter().take_while(|b| b.is_ascii_digit()).count()
+    };
+
+    let mut offset = 1;
+    let lo = digits(offset);
+    if lo == 0 {
+        return None;
+    }
+    offset += lo;
+
+    if offset < bytes.len() && bytes[offset] == b',' {
+        offset += 1;
+        offset += digits(offset);
+    }
 
-        let regex_str = &input.text[input.offset + 1 .. offset - 1];
-        let regex: Matcher = RegexMatcher::new(regex_str);
-        let output = Input { text: input.text, offset: offset };
-        return Ok((output, regex));
+    if offset < bytes.len() && bytes[offset] == b'}' {
+        Some(offset + 1)
+    } else {
+        None
     }
 }
@@ -1,55 +1,54 @@
+// The older tests spell frames as `format!("a")`.
+#![allow(clippy::useless_format)]
+
 use super::*;
 
 #[test]
 fn matcher() {
     let x = [format!("a"), format!("b"), format!("c")];
-    let m = Matcher::new(RegexMatcher::new("b"));
+    let m = RegexMatcher::new("b");
     let r = m.search_trace(&x).unwrap();
-    assert_eq!(r.prefix, &[format!("a")]);
-    assert_eq!(r.suffix, &[format!("c")]);
+    assert_eq!(r.first_matching_frame, 1);
+    assert_eq!(r.first_callee_frame, 2);
 }
 
 #[test]
 fn matcher_2() {
     let x = [format!("a"), format!("b"), format!("c")];
     let m =
-        Matcher::new(
-            ThenMatcher::new(
-                Matcher::new(RegexMatcher::new("b")),
-                Matcher::new(RegexMatcher::new("c"))));
+        ThenMatcher::new(
+            RegexMatcher::new("b"),
+            RegexMatcher::new("c"));
     let r = m.search_trace(&x).unwrap();
-    assert_eq!(r.prefix, &[format!("a")]);
-    assert!(r.suffix.is_empty());
+    assert_eq!(r.first_matching_frame, 1);
+    assert_eq!(r.first_callee_frame, 3);
 }
 
 #[test]
 fn matcher_3() {
     let x = [format!("a"), format!("b"), format!("c")];
     let m =
-        Matcher::new(
-            ThenMatcher::new(
-                Matcher::new(RegexMatcher::new("a")),
-                Matcher::new(RegexMatcher::new("c"))));
+        ThenMatcher::new(
+            RegexMatcher::new("a"),
+            RegexMatcher::new("c"));
     assert!(m.search_trace(&x).is_none());
 }
 
 #[test]
 fn matcher_4() {
     let m =
-        Matcher::new(
-            ThenMatcher::new(
-                Matcher::new(RegexMatcher::new("a")),
-                Matcher::new(SkipMatcher::new(
-                    Matcher::new(RegexMatcher::new("c"))))));
+        ThenMatcher::new(
+            RegexMatcher::new("a"),
+            SkipMatcher::new(RegexMatcher::new("c")));
 
     assert!(m.search_trace(&[format!("a"), format!("b"), format!("c")])
-             .unwrap().prefix.len() == 0);
+             .unwrap().first_matching_frame == 0);
 
     assert!(m.search_trace(&[format!("x"), format!("a"), format!("b"), format!("c")])
-             .unwrap().prefix.len() == 1);
+             .unwrap().first_matching_frame == 1);
 
     assert!(m.search_trace(&[format!("x"), format!("a"), format!("b"), format!("b"), format!("c")])
-             .unwrap().prefix.len() == 1);
+             .unwrap().first_matching_frame == 1);
 }
 
 #[test]
@@ -57,13 +56,13 @@ fn matcher_parse_a_dotdot_c() {
     let m = parse_matcher("{a}..{c}").unwrap();
 
     assert!(m.search_trace(&[format!("a"), format!("b"), format!("c")])
-             .unwrap().prefix.len() == 0);
+             .unwrap().first_matching_frame == 0);
 
     assert!(m.search_trace(&[format!("x"), format!("a"), format!("b"), format!("c")])
-             .unwrap().prefix.len() == 1);
+             .unwrap().first_matching_frame == 1);
 
     assert!(m.search_trace(&[format!("x"), format!("a"), format!("b"), format!("b"), format!("c")])
-             .unwrap().prefix.len() == 1);
+             .unwrap().first_matching_frame == 1);
 }
 
 #[test]
@@ -74,7 +73,7 @@ fn matcher_parse_a_then_c() {
              .is_none());
 
     assert!(m.search_trace(&[format!("a"), format!("c")])
-             .unwrap().prefix.len() == 0);
+             .unwrap().first_matching_frame == 0);
 }
 
 #[test]
@@ -127,3 +126,703 @@ fn matcher_parse_a_dotdot_b_dotdot_c() {
               .is_none());
 }
 
+
+#[test]
+fn matcher_brace_quantifier() {
+    let m = parse_matcher("{a{2}}").unwrap();
+    assert_eq!(format!("{:?}", m), "{a{2}}");
+
+    assert!(m.search_trace(&[format!("aa")]).is_some());
+    assert!(m.search_trace(&[format!("a")]).is_none());
+}
+
+#[test]
+fn matcher_brace_quantifier_range() {
+    let m = parse_matcher("{^foo{1,2}$},{b}").unwrap();
+    assert_eq!(format!("{:?}", m), "{^foo{1,2}$},{b}");
+
+    assert!(m.search_trace(&[format!("fooo"), format!("b")]).is_some());
+    assert!(m.search_trace(&[format!("foooo"), format!("b")]).is_none());
+}
+
+#[test]
+fn matcher_brace_escaped_quantifier() {
+    let m = parse_matcher(r"{a\{2\}}").unwrap();
+    assert_eq!(format!("{:?}", m), r"{a\{2\}}");
+
+    assert!(m.search_trace(&[format!("a{{2}}")]).is_some());
+    assert!(m.search_trace(&[format!("aa")]).is_none());
+}
+
+#[test]
+fn matcher_brace_nested_groups() {
+    let m = parse_matcher("{^(a{2}|b(c{1,}))$}..{d}").unwrap();
+    assert_eq!(format!("{:?}", m), "{^(a{2}|b(c{1,}))$},..{d}");
+
+    assert!(m.search_trace(&[format!("aa"), format!("x"), format!("d")]).is_some());
+    assert!(m.search_trace(&[format!("bcc"), format!("d")]).is_some());
+    assert!(m.search_trace(&[format!("b"), format!("d")]).is_none());
+}
+
+#[test]
+fn matcher_brace_invalid_regex() {
+    // used to panic inside `RegexMatcher::new`
+    assert!(parse_matcher("{a(}").is_err());
+}
+
+#[test]
+fn matcher_needle_frame() {
+    let x = [format!("x"), format!("a"), format!("y"), format!("b"), format!("c")];
+
+    let m = parse_matcher("{a}..{b}").unwrap();
+    let r = m.search_trace(&x).unwrap();
+    assert_eq!(m.needle_frame(&x, r), 3);
+
+    let m = parse_matcher("{a},{y}").unwrap();
+    let r = m.search_trace(&x).unwrap();
+    assert_eq!(m.needle_frame(&x, r), 1);
+
+    let m = parse_matcher("{a}..({y},{b})").unwrap();
+    let r = m.search_trace(&x).unwrap();
+    assert_eq!(m.needle_frame(&x, r), 2);
+}
+
+#[test]
+fn matcher_repeat_regex() {
+    let m = parse_matcher("{a},{+^core::iter::},{b}").unwrap();
+    assert_eq!(format!("{:?}", m), "{a},{+^core::iter::},{b}");
+
+    let it = || format!("core::iter::next");
+
+    // one frame in the run
+    assert!(m.search_trace(&[format!("a"), it(), format!("b")]).is_some());
+
+    // three frames in the run
+    assert!(m.search_trace(&[format!("a"), it(), it(), it(), format!("b")]).is_some());
+
+    // no frames in the run
+    assert!(m.search_trace(&[format!("a"), format!("b")]).is_none());
+}
+
+#[test]
+fn matcher_repeat_regex_consumes_run() {
+    let x = [format!("x"), format!("f1"), format!("f2"), format!("f3"), format!("g")];
+
+    let m = parse_matcher("{+^f}").unwrap();
+    let r = m.search_trace(&x).unwrap();
+    assert_eq!(r.first_matching_frame, 1);
+    assert_eq!(r.first_callee_frame, 4);
+
+    // greedy: the run swallows every `f` frame
+    assert!(parse_matcher("{+^f},{f3}").unwrap().search_trace(&x).is_none());
+}
+
+#[test]
+fn matcher_repeat_regex_zero_is_recoverable() {
+    // The run fails at `x`, but that failure is recoverable, so the
+    // search moves on and matches starting at `f`.
+    let m = parse_matcher("{+^f}").unwrap();
+    let r = m.search_trace(&[format!("x"), format!("f")]).unwrap();
+    assert_eq!(r.first_matching_frame, 1);
+
+    assert!(m.search_trace(&[format!("x"), format!("y")]).is_none());
+}
+
+fn frames(names: &[&str]) -> Vec<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+fn debug(s: &str) -> String {
+    format!("{:?}", parse_matcher(s).unwrap())
+}
+
+#[test]
+fn matcher_or() {
+    let m = parse_matcher("{^a$}/{^b$}").unwrap();
+    assert!(m.search_trace(&frames(&["a"])).is_some());
+    assert!(m.search_trace(&frames(&["b"])).is_some());
+    assert!(m.search_trace(&frames(&["c"])).is_none());
+}
+
+#[test]
+fn matcher_or_binds_tighter_than_comma() {
+    // `({a}/{b}),{c}`
+    let m = parse_matcher("{^a$}/{^b$},{^c$}").unwrap();
+    assert_eq!(debug("{^a$}/{^b$},{^c$}"), "{^a$}/{^b$},{^c$}");
+    assert!(m.search_trace(&frames(&["a", "c"])).is_some());
+    assert!(m.search_trace(&frames(&["b", "c"])).is_some());
+    assert!(m.search_trace(&frames(&["a"])).is_none());
+
+    // `{a},({b}/{c})`
+    let m = parse_matcher("{^a$},{^b$}/{^c$}").unwrap();
+    assert!(m.search_trace(&frames(&["a", "b"])).is_some());
+    assert!(m.search_trace(&frames(&["a", "c"])).is_some());
+    assert!(m.search_trace(&frames(&["c"])).is_none());
+
+    // explicit parentheses agree
+    let m = parse_matcher("({^a$}/{^b$}),{^c$}").unwrap();
+    assert!(m.search_trace(&frames(&["b", "c"])).is_some());
+    assert!(m.search_trace(&frames(&["b"])).is_none());
+}
+
+#[test]
+fn matcher_or_binds_tighter_than_skip() {
+    // `{a}..({b}/{c})`
+    let m = parse_matcher("{^a$}..{^b$}/{^c$}").unwrap();
+    assert_eq!(debug("{^a$}..{^b$}/{^c$}"), "{^a$},..{^b$}/{^c$}");
+    assert!(m.search_trace(&frames(&["a", "x", "b"])).is_some());
+    assert!(m.search_trace(&frames(&["a", "x", "c"])).is_some());
+    assert!(m.search_trace(&frames(&["c"])).is_none());
+
+    // the prefix form takes the whole alternation too
+    assert_eq!(debug("{^a$},..{^b$}/{^c$}"), "{^a$},..{^b$}/{^c$}");
+    let m = parse_matcher("{^a$},..{^b$}/{^c$}").unwrap();
+    assert!(m.search_trace(&frames(&["a", "x", "c"])).is_some());
+}
+
+#[test]
+fn matcher_or_with_not() {
+    // `{x}..!({a}/{b})`
+    let m = parse_matcher("{^x$}..!{^a$}/{^b$}").unwrap();
+    assert!(m.search_trace(&frames(&["x", "y"])).is_some());
+    assert!(m.search_trace(&frames(&["x", "y", "b"])).is_none());
+    assert!(m.search_trace(&frames(&["x", "a"])).is_none());
+}
+
+#[test]
+fn matcher_skip_none() {
+    let m = parse_matcher("{^a$}..!{^b$}").unwrap();
+    assert_eq!(format!("{:?}", m), "{^a$},..!{^b$}");
+
+    assert!(m.search_trace(&frames(&["a", "x"])).is_some());
+    assert!(m.search_trace(&frames(&["x", "a", "y"])).is_some());
+    assert!(m.search_trace(&frames(&["a"])).is_some());
+
+    // `b` below `a`
+    assert!(m.search_trace(&frames(&["a", "x", "b"])).is_none());
+    assert!(m.search_trace(&frames(&["a", "b"])).is_none());
+
+    // `b` above `a` does not count
+    assert!(m.search_trace(&frames(&["b", "a", "x"])).is_some());
+
+    // no `a` at all
+    assert!(m.search_trace(&frames(&["x", "y"])).is_none());
+}
+
+#[test]
+fn matcher_skip_none_cuts() {
+    // The first `a` has a `b` below it. That is a cut: we do not go on
+    // to try the second `a`, even though no `b` is below it.
+    let x = frames(&["a", "b", "a", "c"]);
+    assert!(parse_matcher("{^a$}..!{^b$}").unwrap().search_trace(&x).is_none());
+}
+
+#[test]
+fn matcher_not_below_does_not_cut() {
+    // Unlike `{a}..!{b}`, the spelled-out form `{a},!..{b}` does not
+    // cut, and matches the second `a`.
+    let x = frames(&["a", "b", "a", "c"]);
+    let m = parse_matcher("{^a$},!..{^b$}").unwrap();
+    assert_eq!(m.search_trace(&x).unwrap().first_matching_frame, 2);
+}
+
+#[test]
+fn matcher_search_direction() {
+    let x = frames(&["a", "x", "a", "y", "b"]);
+    let m = parse_matcher("{^a$}..{^b$}").unwrap();
+
+    let root = m.search_trace_dir(&x, SearchDirection::RootFirst).unwrap();
+    assert_eq!((root.first_matching_frame, root.first_callee_frame), (0, 5));
+
+    let leaf = m.search_trace_dir(&x, SearchDirection::LeafFirst).unwrap();
+    assert_eq!((leaf.first_matching_frame, leaf.first_callee_frame), (2, 5));
+
+    assert!(m.search_trace_dir(&frames(&["b", "a"]), SearchDirection::LeafFirst).is_none());
+}
+
+#[test]
+fn matcher_search_direction_cut() {
+    // Root-first, the cut at the outer `a` rules out the whole sample;
+    // leaf-first, the inner `a` is tried on its own.
+    let x = frames(&["a", "b", "a", "c"]);
+    let m = parse_matcher("{^a$}..!{^b$}").unwrap();
+    assert!(m.search_trace_dir(&x, SearchDirection::RootFirst).is_none());
+    let leaf = m.search_trace_dir(&x, SearchDirection::LeafFirst).unwrap();
+    assert_eq!(leaf.first_matching_frame, 2);
+}
+
+#[test]
+fn matcher_fraction() {
+    let m = parse_matcher("{>=50%:^je_}").unwrap();
+    assert_eq!(format!("{:?}", m), "{>=50%:^je_}");
+
+    // 2 of 3 frames match: 67%
+    let x = frames(&["main", "je_malloc", "je_arena"]);
+    assert!(m.search_trace(&x).is_some());
+    assert!(parse_matcher("{>=66%:^je_}").unwrap().search_trace(&x).is_some());
+    assert!(parse_matcher("{>=67.5%:^je_}").unwrap().search_trace(&x).is_none());
+    assert!(parse_matcher("{>=90%:^je_}").unwrap().search_trace(&x).is_none());
+
+    // 1 of 3 frames match: 33%. The suffix `["je_malloc"]` alone would
+    // be 100%, but only the whole stack counts, in either direction.
+    let y = frames(&["main", "x", "je_malloc"]);
+    assert!(m.search_trace(&y).is_none());
+    assert!(m.search_trace_dir(&y, SearchDirection::LeafFirst).is_none());
+
+    let result = m.search_trace(&x).unwrap();
+    assert_eq!((result.first_matching_frame, result.first_callee_frame), (0, 3));
+}
+
+#[test]
+fn matcher_fraction_top_level_only() {
+    assert!(parse_matcher("{>=50%:a},{b}").is_err());
+    assert!(parse_matcher("{b}..{>=50%:a}").is_err());
+    assert!(parse_matcher("({>=50%:a})").is_err());
+    assert!(parse_matcher("!{>=50%:a}").is_err());
+    assert!(parse_matcher("{>=x%:a}").is_err());
+    assert!(parse_matcher("{>=50:a}").is_err());
+}
+
+fn simplified(s: &str) -> String {
+    format!("{:?}", parse_matcher(s).unwrap().simplify())
+}
+
+#[test]
+fn matcher_simplify() {
+    assert_eq!(simplified("{a}"), "{a}");
+    assert_eq!(simplified("((({a})))"), "{a}");
+    assert_eq!(simplified("({a},{b}),{c}"), "{a},{b},{c}");
+    assert_eq!(simplified("(({a},{b}),({c},{d})),{e}"), "{a},{b},{c},{d},{e}");
+    assert_eq!(simplified("({a}/{b})/{c}"), "{a}/{b}/{c}");
+    assert_eq!(simplified("!({a})..({b})"), "..{b} while !{a}");
+    assert_eq!(simplified("{a}..!({b})"), "{a},..!{b}");
+
+    // the empty matcher disappears from sequences
+    let a = RegexMatcher::new("a");
+    let m = ThenMatcher::new(empty_matcher(), ThenMatcher::new(a, empty_matcher()));
+    assert_eq!(format!("{:?}", m.simplify()), "{a}");
+}
+
+#[test]
+fn matcher_simplify_is_equivalent() {
+    let traces = [
+        frames(&["a", "b", "c"]),
+        frames(&["x", "a", "y", "b", "c"]),
+        frames(&["a", "c"]),
+        frames(&["c", "b", "a"]),
+    ];
+    for query in &["(({a},{b}),{c})", "(({a}..{b}))..{c}", "({c}/{b})/{a}", "!({b})..{c}"] {
+        let m = parse_matcher(query).unwrap();
+        let s = m.simplify();
+        for trace in &traces {
+            let (x, y) = (m.search_trace(trace), s.search_trace(trace));
+            assert_eq!(x.map(|r| (r.first_matching_frame, r.first_callee_frame)),
+                       y.map(|r| (r.first_matching_frame, r.first_callee_frame)),
+                       "{} on {:?}", query, trace);
+            if let (Some(x), Some(y)) = (x, y) {
+                assert_eq!(m.needle_frame(trace, x), s.needle_frame(trace, y));
+            }
+        }
+    }
+}
+
+#[test]
+fn matcher_step_limit() {
+    let mut names: Vec<String> = (0..200).map(|_| "a".to_string()).collect();
+    let m = parse_matcher("..(({^a$}..{^b$})/{^x$})").unwrap();
+
+    // quadratic: at each `a`, the inner `..` scans the rest of the
+    // stack for a `b`, and the `/` absorbs its cut
+    assert!(m.search_trace(&names).is_none());
+    assert_eq!(m.search_trace_limited(&names, SearchDirection::RootFirst, 1000).err(),
+               Some(StepLimitExceeded));
+    assert_eq!(m.search_trace_limited(&names, SearchDirection::LeafFirst, 1000).err(),
+               Some(StepLimitExceeded));
+    assert!(m.search_trace_limited(&names, SearchDirection::RootFirst, 1_000_000)
+             .unwrap().is_none());
+
+    // a linear query fits easily, and finds the same match
+    names.push("b".to_string());
+    let m = parse_matcher("..{^a$}..{^b$}").unwrap();
+    let r = m.search_trace_limited(&names, SearchDirection::RootFirst, 1000).unwrap().unwrap();
+    assert_eq!((r.first_matching_frame, r.first_callee_frame), (0, 201));
+}
+
+#[test]
+fn matcher_parse_error_display() {
+    let err = parse_matcher("{a},{b}..").unwrap_err();
+    assert_eq!(err, MatcherParseError {
+        input: "{a},{b}..".to_string(),
+        offset: 7,
+        expected: "end of input".to_string(),
+    });
+    assert_eq!(err.to_string(),
+               "invalid matcher: expected end of input\n  {a},{b}..\n         ^");
+
+    // the caret counts characters, not bytes
+    let err = parse_matcher("{ä}x").unwrap_err();
+    assert_eq!(err.offset, 4);
+    assert!(err.to_string().ends_with("\n  {ä}x\n     ^"), "{}", err);
+}
+
+#[test]
+fn matcher_unterminated_braces() {
+    let err = |query: &str| {
+        let err = parse_matcher(query).unwrap_err();
+        (err.offset, err.expected)
+    };
+    assert_eq!(err(r"{a\"), (3, "a character after '\\'".to_string()));
+    assert_eq!(err(r"{a},{\"), (6, "a character after '\\'".to_string()));
+    assert_eq!(err("{"), (1, "matching '}' character".to_string()));
+    assert_eq!(err("{a},{b"), (6, "matching '}' character".to_string()));
+
+    // an escaped brace at the very end is still unbalanced, not an error
+    // in the escape
+    assert_eq!(err(r"{a\}"), (4, "matching '}' character".to_string()));
+}
+
+#[test]
+fn matcher_empty_braces() {
+    assert_eq!(format!("{:?}", parse_matcher("{}").unwrap()), ".");
+    assert_eq!(format!("{:?}", parse_matcher("{a},{}..{b}").unwrap()), "{a},.,..{b}");
+
+    let stacks = [&["a", "x", "b"][..], &["a", "b"], &["a", "x", "y", "b"], &["a"],
+                  &["x", "a", "y"]];
+    for query in &["{^a$},{},{^b$}", "{^a$},{}..{^b$}", "{^a$},{}", "..{^a$},{}"] {
+        // `(.)`, since `...` would not parse
+        let wildcard = query.replace("{}", "(.)");
+        let (m, w) = (parse_matcher(query).unwrap(), parse_matcher(&wildcard).unwrap());
+        for frames in &stacks {
+            let frames: Vec<String> = frames.iter().map(|s| s.to_string()).collect();
+            let found = |m: &Matcher| {
+                m.search_trace(&frames).map(|r| (r.first_matching_frame, r.first_callee_frame))
+            };
+            assert_eq!(found(&m), found(&w), "{} on {:?}", query, frames);
+        }
+    }
+}
+
+#[test]
+fn matcher_module_of() {
+    assert_eq!(module_of("rustc::ty::fold::fold_with"), Some("rustc::ty::fold"));
+    assert_eq!(module_of("Vec<a::B>::push"), Some("Vec<a::B>"));
+    assert_eq!(module_of("<impl a::Foo for b::Bar>::baz"), Some("<impl a::Foo for b::Bar>"));
+    assert_eq!(module_of("je_malloc"), None);
+    assert_eq!(module_of("::f"), None);
+}
+
+#[test]
+fn matcher_module_same() {
+    let m = parse_matcher("{mod=$m:^a::f$}..{mod=$m}").unwrap();
+    assert_eq!(format!("{:?}", m), "{mod=$m:^a::f$},..{mod=$m}");
+
+    let x = frames(&["main", "a::f", "b::g", "a::h", "c::i"]);
+    let result = m.search_trace(&x).unwrap();
+    assert_eq!((result.first_matching_frame, result.first_callee_frame), (1, 4));
+    assert_eq!(m.needle_frame(&x, result), 3);
+}
+
+#[test]
+fn matcher_module_cross() {
+    let m = parse_matcher("{mod=$m:^a::f$}..{mod=$m}").unwrap();
+    assert!(m.search_trace(&frames(&["main", "a::f", "b::g", "c::h"])).is_none());
+
+    // `a::f` is not in the same module as `a::b::g`
+    assert!(m.search_trace(&frames(&["a::f", "a::b::g"])).is_none());
+
+    // frames without a path have no module
+    let any = parse_matcher("{mod=$m},{mod=$m}").unwrap();
+    assert!(any.search_trace(&frames(&["je_malloc", "je_free"])).is_none());
+    assert!(any.search_trace(&frames(&["main", "x::a", "y::b", "y::c"])).is_some());
+}
+
+#[test]
+fn matcher_module_binding_scope() {
+    // The failed attempt at the first `a::f` must not leave `m` bound
+    // to `a` when the search retries at `b::f`.
+    let m = parse_matcher("{mod=$m:::f$},{mod=$m:::g$}").unwrap();
+    let x = frames(&["a::f", "c::g", "b::f", "b::g"]);
+    assert_eq!(m.search_trace(&x).unwrap().first_matching_frame, 2);
+
+    // Nor do bindings escape the left side of an `/` that failed.
+    let m = parse_matcher("(.,{mod=$m:^b::},{^x$})/{mod=$m:^a::},.,{mod=$m}").unwrap();
+    let r = m.search_trace(&frames(&["a::f", "b::g", "a::h"])).unwrap();
+    assert_eq!(r.first_matching_frame, 0);
+
+    // or a negation.
+    let m = parse_matcher("!{mod=$m:^a::}..{mod=$m}").unwrap();
+    assert!(m.search_trace(&frames(&["b::f"])).is_some());
+}
+
+#[test]
+fn matcher_module_parse_errors() {
+    assert!(parse_matcher("{mod=$}").is_err());
+    assert!(parse_matcher("{mod=$a-b}").is_err());
+    assert!(parse_matcher("{mod=$m:(}").is_err());
+}
+
+#[test]
+fn matcher_recur_count() {
+    let m = parse_matcher("{>=3#:^f$}").unwrap();
+    assert_eq!(format!("{:?}", m), "{>=3#:^f$}");
+
+    // three `f` frames, none of them adjacent
+    let x = frames(&["main", "f", "g", "f", "h", "f", "g"]);
+    let result = m.search_trace(&x).unwrap();
+    assert_eq!((result.first_matching_frame, result.first_callee_frame), (0, 7));
+    assert!(m.search_trace_dir(&x, SearchDirection::LeafFirst).is_some());
+    assert!(parse_matcher("{>=4#:^f$}").unwrap().search_trace(&x).is_none());
+
+    // whereas three consecutive `f` frames are nowhere to be found
+    assert!(parse_matcher("{^f$},{^f$},{^f$}").unwrap().search_trace(&x).is_none());
+
+    // only two recurrences are not enough
+    assert!(m.search_trace(&frames(&["f", "g", "f", "g"])).is_none());
+}
+
+#[test]
+fn matcher_recur_count_top_level_only() {
+    assert!(parse_matcher("{>=3#:a},{b}").is_err());
+    assert!(parse_matcher("{b}..{>=3#:a}").is_err());
+    assert!(parse_matcher("!{>=3#:a}").is_err());
+    assert!(parse_matcher("{>=x#:a}").is_err());
+    assert!(parse_matcher("{>=1.5#:a}").is_err());
+}
+
+#[test]
+fn matcher_mangled_and_demangled() {
+    const MANGLED: &str = "_ZN4core3ptr13drop_in_place17h0123456789abcdefE";
+    let demangled = frames(&["main", "core::ptr::drop_in_place", "free"]);
+    let mangled = frames(&["main", MANGLED, "free"]);
+
+    // a regex written against either spelling finds both
+    for text in &["{^core::ptr::drop_in_place$},{free}", "{^_ZN4core3ptr13drop_in_place},{free}"] {
+        let m = parse_matcher(text).unwrap();
+        assert_eq!(m.search_trace(&demangled).map(|r| r.first_matching_frame),
+                   if text.contains("_ZN") { None } else { Some(1) });
+        assert_eq!(m.search_trace(&mangled).map(|r| r.first_matching_frame), Some(1));
+    }
+
+    let m = parse_matcher("{>=2#:drop_in_place}").unwrap();
+    assert!(m.search_trace(&frames(&["main", MANGLED, "core::ptr::drop_in_place"])).is_some());
+}
+
+#[test]
+fn matcher_not_here_vs_not_below() {
+    let here = parse_matcher("{^a$},!{^b$}").unwrap();
+    let below = parse_matcher("{^a$},!..{^b$}").unwrap();
+    match below.simplify().kind {
+        MatcherKind::Then(ref m) => assert!(matches!(m.right.kind, MatcherKind::NotBelow(_))),
+        _ => panic!("expected a sequence"),
+    }
+
+    // `b` is below `a`, but not directly
+    let x = frames(&["a", "x", "b"]);
+    assert!(here.search_trace(&x).is_some());
+    assert!(below.search_trace(&x).is_none());
+
+    // `b` directly below `a`
+    let x = frames(&["a", "b"]);
+    assert!(here.search_trace(&x).is_none());
+    assert!(below.search_trace(&x).is_none());
+
+    // no `b` at all
+    let x = frames(&["a", "x"]);
+    assert!(here.search_trace(&x).is_some());
+    assert!(below.search_trace(&x).is_some());
+
+    // `!` applies to a single atom: `(!{a})/{b}`
+    let m = parse_matcher("!{^a$}/{^b$}").unwrap();
+    assert!(m.search_trace(&frames(&["c"])).is_some());
+
+    // `!..` binds a whole alternation, like `..`
+    assert_eq!(debug("!..{a}/{b}"), "!..{a}/{b}");
+    let m = parse_matcher("{^m$},!..{^a$}/{^b$}").unwrap();
+    assert!(m.search_trace(&frames(&["m", "x", "b"])).is_none());
+    assert!(m.search_trace(&frames(&["m", "x", "c"])).is_some());
+}
+
+#[test]
+fn matcher_empty_stack() {
+    let empty: [String; 0] = [];
+    let m = parse_matcher("()").unwrap();
+    assert_eq!(debug("()"), "()");
+    assert!(m.is_empty());
+
+    let r = m.search_trace(&empty).unwrap();
+    assert_eq!((r.first_matching_frame, r.first_callee_frame), (0, 0));
+    for &direction in &[SearchDirection::RootFirst, SearchDirection::LeafFirst] {
+        let r = m.search_trace_limited(&empty, direction, 10).unwrap().unwrap();
+        assert_eq!((r.first_matching_frame, r.first_callee_frame), (0, 0));
+    }
+
+    // `()` matches every stack, consuming nothing
+    let r = m.search_trace(&frames(&["a"])).unwrap();
+    assert_eq!((r.first_matching_frame, r.first_callee_frame), (0, 0));
+
+    // a matcher that needs a frame still misses an empty stack, while
+    // one that can succeed without a frame matches it
+    assert!(parse_matcher("{a}").unwrap().search_trace(&empty).is_none());
+    assert!(parse_matcher("{a}/()").unwrap().search_trace(&empty).is_some());
+    assert!(parse_matcher("!{a}").unwrap().search_trace(&empty).is_some());
+
+    // inside a query, `()` still parses as a matcher of its own
+    assert_eq!(debug("{a},()"), "{a},()");
+    assert!(parse_matcher("{a}..()").unwrap().search_trace(&frames(&["a"])).is_none());
+}
+
+#[test]
+fn matcher_capture() {
+    let m = parse_matcher(r"{^main$}..{^queries::(?P<query>\w+)::force$}").unwrap();
+    assert!(m.has_group("query"));
+    assert!(!m.has_group("other"));
+
+    let x = frames(&["main", "queries::typeck::force", "queries::borrowck::force"]);
+    let r = m.search_trace(&x).unwrap();
+    assert_eq!(m.capture(&x, r, "query"), Some("typeck".to_string()));
+    assert_eq!(m.capture(&x, r, "other"), None);
+
+    // only the frames that took part in the match count; a capture
+    // inside a negation is forgotten
+    let m = parse_matcher(r"!({^(?P<q>a)$},{^z$}),{^a$}").unwrap();
+    let x = frames(&["a", "b"]);
+    assert_eq!(m.capture(&x, m.search_trace(&x).unwrap(), "q"), None);
+    let m = parse_matcher(r"{(?P<q>^x$)}/{^a$},{^(?P<q>\w+)$}").unwrap();
+    assert_eq!(m.capture(&x, m.search_trace(&x).unwrap(), "q"), Some("b".to_string()));
+
+    // `{+re}` captures from the first frame of its run, and a mangled
+    // frame from its demangled name
+    let m = parse_matcher(r"{+^core::(?P<m>\w+)::}").unwrap();
+    let x = frames(&["core::ptr::a", "core::mem::b"]);
+    assert_eq!(m.capture(&x, m.search_trace(&x).unwrap(), "m"), Some("ptr".to_string()));
+    let x = frames(&["_ZN4core3ptr13drop_in_place17h0123456789abcdefE"]);
+    assert_eq!(m.capture(&x, m.search_trace(&x).unwrap(), "m"), Some("ptr".to_string()));
+}
+
+#[test]
+fn matcher_search_nth() {
+    let x = frames(&["main", "a", "x", "a", "y", "a", "b"]);
+    let m = parse_matcher("{^a$}").unwrap();
+    let nth = |m: &Matcher, index| {
+        m.search_trace_nth(&x, index).map(|r| (r.first_matching_frame, r.first_callee_frame))
+    };
+
+    assert_eq!(nth(&m, MatchIndex::Nth(1)), Some((1, 2)));
+    assert_eq!(nth(&m, MatchIndex::Nth(2)), Some((3, 4)));
+    assert_eq!(nth(&m, MatchIndex::Nth(3)), Some((5, 6)));
+    assert_eq!(nth(&m, MatchIndex::Last), Some((5, 6)));
+    assert_eq!(nth(&m, MatchIndex::Nth(4)), None);
+
+    // leaf-first counts from the other end
+    let leaf = m.search_trace_nth_limited(&x, SearchDirection::LeafFirst, MatchIndex::Nth(2), 100);
+    assert_eq!(leaf.unwrap().map(|r| r.first_matching_frame), Some(3));
+
+    // the cut still applies: the `a` at 3 has no `x` below it, so the
+    // search stops there rather than going on to the `a` at 5
+    let m = parse_matcher("{^a$}..{^x$}").unwrap();
+    assert_eq!(nth(&m, MatchIndex::Nth(1)), Some((1, 3)));
+    assert_eq!(nth(&m, MatchIndex::Nth(2)), None);
+    assert_eq!(nth(&m, MatchIndex::Last), Some((1, 3)));
+
+    assert!(m.search_trace_nth(&[], MatchIndex::Last).is_none());
+}
+
+#[test]
+fn matcher_depth() {
+    let depth = |s: &str, n: usize| {
+        let x: Vec<String> = (0..n).map(|i| format!("f{}", i)).collect();
+        parse_matcher(s).unwrap().search_trace(&x).is_some()
+    };
+
+    assert!(depth("depth(>=3)", 3));
+    assert!(!depth("depth(>=3)", 2));
+    assert!(depth("depth(>3)", 4));
+    assert!(!depth("depth(>3)", 3));
+    assert!(depth("depth(<3)", 2));
+    assert!(!depth("depth(<3)", 3));
+    assert!(depth("depth(<=3)", 3));
+    assert!(depth("depth(3)", 3));
+    assert!(!depth("depth(3)", 4));
+    assert!(depth("depth(3..8)", 3) && depth("depth(3..8)", 8));
+    assert!(!depth("depth(3..8)", 2) && !depth("depth(3..8)", 9));
+    assert!(depth("depth(0)", 0));
+
+    assert_eq!(debug("depth( > 20 )"), "depth(>=21)");
+    assert_eq!(debug("depth(<4)"), "depth(<=3)");
+    assert_eq!(debug("depth(3..8)"), "depth(3..8)");
+}
+
+#[test]
+fn matcher_depth_top_level_only() {
+    assert!(parse_matcher("depth(>2),{b}").is_err());
+    assert!(parse_matcher("{b}..depth(>2)").is_err());
+    assert!(parse_matcher("(depth(>2))").is_err());
+    assert!(parse_matcher("!depth(>2)").is_err());
+    assert!(parse_matcher("depth(x)").is_err());
+    assert!(parse_matcher("depth(<0)").is_err());
+    assert!(parse_matcher("depth(8..3)").is_err());
+    assert!(parse_matcher("depth(3").is_err());
+    assert!(parse_matcher("({a} & depth(3))").is_err());
+}
+
+#[test]
+fn matcher_and_depth() {
+    let shallow = frames(&["main", "a", "b"]);
+    let deep = frames(&["main", "x", "y", "a", "b"]);
+
+    // deep stacks that contain `a`, whichever way round
+    for query in &["{^a$} & depth(>3)", "depth(>3) & {^a$}"] {
+        let m = parse_matcher(query).unwrap();
+        assert!(m.search_trace(&shallow).is_none(), "{}", query);
+        let r = m.search_trace(&deep).unwrap();
+        assert_eq!((r.first_matching_frame, r.first_callee_frame), (3, 4), "{}", query);
+    }
+    assert_eq!(debug("depth(>3)&{^a$}"), "depth(>=4) & {^a$}");
+
+    // the depth is that of the whole stack, not of what is below `a`
+    assert!(parse_matcher("{^a$} & depth(5)").unwrap().search_trace(&deep).is_some());
+
+    // clauses are searched for independently, with whole-stack ones
+    // alongside
+    let m = parse_matcher("{^b$} & {^x$} & {>=50%:^[xyab]$}").unwrap();
+    let r = m.search_trace(&deep).unwrap();
+    assert_eq!(r.first_matching_frame, 4);
+    assert!(m.search_trace(&shallow).is_none());
+
+    // `&` binds loosest of all
+    assert_eq!(debug("{a},{b} & {c}..{d}"), "{a},{b} & {c},..{d}");
+    assert!(parse_matcher("{>=50%:a} & {b}").is_ok());
+
+    // and works with the other entry points
+    let m = parse_matcher(r"{^(?P<f>a)$} & depth(>3)").unwrap();
+    let r = m.search_trace_dir(&deep, SearchDirection::LeafFirst).unwrap();
+    assert_eq!(m.capture(&deep, r, "f"), Some("a".to_string()));
+    assert_eq!(m.needle_frame(&deep, r), 3);
+    assert_eq!(format!("{:?}", m.simplify()), format!("{:?}", m));
+}
+
+#[test]
+fn matcher_exact() {
+    let m = parse_matcher("{^main$},{^a$},{^b$}").unwrap().exact().unwrap();
+    assert_eq!(format!("{:?}", m), "exact({^main$},{^a$},{^b$})");
+
+    let r = m.search_trace(&frames(&["main", "a", "b"])).unwrap();
+    assert_eq!((r.first_matching_frame, r.first_callee_frame), (0, 3));
+
+    // a frame more at either end, or in between, is not the same stack
+    assert!(m.search_trace(&frames(&["start", "main", "a", "b"])).is_none());
+    assert!(m.search_trace(&frames(&["main", "a", "b", "c"])).is_none());
+    assert!(m.search_trace(&frames(&["main", "a", "x", "b"])).is_none());
+    assert!(m.search_trace_dir(&frames(&["main", "a", "b", "c"]), SearchDirection::LeafFirst)
+             .is_none());
+
+    // wildcards and runs still work, as long as every frame is covered
+    let m = parse_matcher("{^main$},{+^a$},.").unwrap().exact().unwrap();
+    assert!(m.search_trace(&frames(&["main", "a", "a", "z"])).is_some());
+    assert!(m.search_trace(&frames(&["main", "a", "a"])).is_none());
+
+    assert!(parse_matcher("{a}..{b}").unwrap().exact().is_err());
+    assert!(parse_matcher("{a},({b}/..{c})").unwrap().exact().is_err());
+    assert!(parse_matcher("{a},!..{b}").unwrap().exact().is_err());
+    assert!(parse_matcher("{a} & depth(1)").unwrap().exact().is_err());
+}
@@ -0,0 +1,82 @@
+//! Counts `(caller, leaf)` pairs: the innermost frame of each sample
+//! together with the frame that called it, or with the last few
+//! callers when a longer context is asked for (see `set_context`).
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Result, Write};
+use util::percent;
+
+use super::AddFrames;
+
+#[cfg(test)]
+mod test;
+
+pub struct Pairs {
+    pairs: HashMap<(String, String), usize>,
+    context: usize,
+}
+
+impl Default for Pairs {
+    fn default() -> Pairs {
+        Pairs::new()
+    }
+}
+
+impl Pairs {
+    pub fn new() -> Pairs {
+        Pairs { pairs: HashMap::new(), context: 1 }
+    }
+
+    /// Keep up to `context` callers of each leaf (default 1), joined
+    /// outermost first with ` -> `. Stacks that differ only further
+    /// out are counted together. Must be at least 1.
+    pub fn set_context(&mut self, context: usize) {
+        assert!(context > 0);
+        self.context = context;
+    }
+
+    /// The `n` most frequent pairs as `(count, caller, leaf)`, most
+    /// frequent first.
+    pub fn top(&self, n: usize) -> Vec<(usize, &str, &str)> {
+        let mut pairs: Vec<(usize, &str, &str)> =
+            self.pairs.iter()
+                      .map(|((caller, leaf), &count)| (count, &caller[..], &leaf[..]))
+                      .collect();
+
+        pairs.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+        pairs.truncate(n);
+        pairs
+    }
+
+    pub fn dump(&self, out: &mut dyn Write, total: usize, threshold: usize) -> Result<()> {
+        for (count, caller, leaf) in self.top(threshold).into_iter().rev() {
+            writeln!(out, "{:3}% {} -> {}", percent(count, total), caller, leaf)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddFrames for Pairs {
+    /// Samples with fewer than two frames have no caller and are ignored.
+    fn add_frames<I>(&mut self, frames: I)
+        where I: Iterator<Item=String>
+    {
+        let mut last = VecDeque::with_capacity(self.context + 1);
+        for frame in frames {
+            if last.len() > self.context {
+                last.pop_front();
+            }
+            last.push_back(frame);
+        }
+
+        if last.len() >= 2 {
+            let leaf = last.pop_back().unwrap();
+            let callers: Vec<String> = last.into_iter().collect();
+            *self.pairs.entry((callers.join(" -> "), leaf)).or_insert(0) += 1;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pairs.clear();
+    }
+}
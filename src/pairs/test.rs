@@ -0,0 +1,63 @@
+use super::*;
+
+fn add(pairs: &mut Pairs, frames: &[&str]) {
+    pairs.add_frames(frames.iter().map(|s| s.to_string()));
+}
+
+#[test]
+fn caller_leaf_pairs() {
+    let mut pairs = Pairs::new();
+    add(&mut pairs, &["main", "vec::push", "malloc"]);
+    add(&mut pairs, &["main", "parse", "vec::push", "malloc"]);
+    add(&mut pairs, &["main", "string::new", "malloc"]);
+    add(&mut pairs, &["main", "parse", "memcpy"]);
+    add(&mut pairs, &["main"]);
+    add(&mut pairs, &[]);
+
+    assert_eq!(pairs.top(10), vec![
+        (2, "vec::push", "malloc"),
+        (1, "parse", "memcpy"),
+        (1, "string::new", "malloc"),
+    ]);
+
+    let mut out = vec![];
+    pairs.dump(&mut out, 5, 2).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), " 20% parse -> memcpy\n 40% vec::push -> malloc\n");
+}
+
+#[test]
+fn context() {
+    let stacks: &[&[&str]] = &[
+        &["main", "a", "parse", "vec::push", "malloc"],
+        &["main", "b", "parse", "vec::push", "malloc"],
+        &["vec::push", "malloc"],
+    ];
+
+    let mut pairs = Pairs::new();
+    for stack in stacks {
+        add(&mut pairs, stack);
+    }
+    assert_eq!(pairs.top(10), vec![(3, "vec::push", "malloc")]);
+
+    let mut pairs = Pairs::new();
+    pairs.set_context(3);
+    for stack in stacks {
+        add(&mut pairs, stack);
+    }
+    assert_eq!(pairs.top(10), vec![
+        (1, "a -> parse -> vec::push", "malloc"),
+        (1, "b -> parse -> vec::push", "malloc"),
+        (1, "vec::push", "malloc"),
+    ]);
+}
+
+#[test]
+fn clear_is_like_new() {
+    let mut pairs = Pairs::new();
+    add(&mut pairs, &["main", "a", "b"]);
+    pairs.clear();
+    assert!(pairs.top(10).is_empty());
+
+    add(&mut pairs, &["main", "c"]);
+    assert_eq!(pairs.top(10), vec![(1, "main", "c")]);
+}
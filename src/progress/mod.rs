@@ -0,0 +1,59 @@
+//! A progress indicator for `--progress`. It is meant for stderr, so
+//! that it never mixes with the report on stdout.
+
+use std::io::{Result, Write};
+
+#[cfg(test)]
+mod test;
+
+/// Without a known input size, the sample count is shown every this
+/// many samples.
+const SAMPLES_PER_TICK: usize = 10_000;
+
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+pub struct Progress<W: Write> {
+    out: W,
+    total_bytes: Option<u64>,
+    /// The percentage (or, without `total_bytes`, the tick) last shown.
+    shown: Option<u64>,
+}
+
+impl<W: Write> Progress<W> {
+    /// With `total_bytes`, progress is shown as a percentage of the
+    /// input read; otherwise as a spinner with the sample count.
+    pub fn new(out: W, total_bytes: Option<u64>) -> Progress<W> {
+        Progress {
+            out,
+            total_bytes: total_bytes.filter(|&total| total > 0),
+            shown: None,
+        }
+    }
+
+    /// Records that `samples` samples were read from the first `bytes`
+    /// bytes of input, redrawing the line when the display changes.
+    pub fn update(&mut self, bytes: u64, samples: usize) -> Result<()> {
+        let tick = match self.total_bytes {
+            Some(total) => bytes.min(total) * 100 / total,
+            None => (samples / SAMPLES_PER_TICK) as u64,
+        };
+        if self.shown == Some(tick) {
+            return Ok(());
+        }
+        self.shown = Some(tick);
+
+        match self.total_bytes {
+            Some(_) => write!(self.out, "\r{:3}% ({} samples)", tick, samples)?,
+            None => write!(self.out, "\r{} {} samples", SPINNER[tick as usize % 4], samples)?,
+        }
+        self.out.flush()
+    }
+
+    /// Ends the progress line, if one was drawn.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.shown.is_some() {
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use super::*;
+
+fn shown(total_bytes: Option<u64>, updates: &[(u64, usize)]) -> String {
+    let mut out = vec![];
+    {
+        let mut progress = Progress::new(&mut out, total_bytes);
+        for &(bytes, samples) in updates {
+            progress.update(bytes, samples).unwrap();
+        }
+        progress.finish().unwrap();
+    }
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn percent_of_bytes() {
+    assert_eq!(shown(Some(200), &[(10, 1), (20, 2), (100, 10), (200, 20)]),
+               "\r  5% (1 samples)\r 10% (2 samples)\r 50% (10 samples)\r100% (20 samples)\n");
+
+    // unchanged percentages are not redrawn
+    assert_eq!(shown(Some(1000), &[(1, 1), (2, 2), (9, 3)]), "\r  0% (1 samples)\n");
+}
+
+#[test]
+fn spinner_without_size() {
+    assert_eq!(shown(None, &[(1, 1), (2, 2), (3, 10_000), (4, 25_000)]),
+               "\r| 1 samples\r/ 10000 samples\r- 25000 samples\n");
+    assert_eq!(shown(Some(0), &[(1, 1)]), "\r| 1 samples\n");
+}
+
+#[test]
+fn nothing_read() {
+    assert_eq!(shown(Some(100), &[]), "");
+}
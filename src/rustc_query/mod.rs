@@ -2,7 +2,9 @@
 //!
 //! We are looking for symbols like this:
 //!
-//!     rustc::ty::maps::<impl rustc::ty::maps::queries::borrowck<'tcx>>::force
+//! ```text
+//! rustc::ty::maps::<impl rustc::ty::maps::queries::borrowck<'tcx>>::force
+//! ```
 //!
 //! which we want to transform to just the name of the query `borrowck`.
 
@@ -14,6 +16,9 @@ const QUERY_PREFIX_2: &str = "_ZN5rustc2ty4maps15__query_compute";
 use std::str::FromStr;
 use trace::TraceArgs;
 
+#[cfg(test)]
+mod test;
+
 pub fn to_query_stack(trace_args: &mut TraceArgs) {
     let stack: Vec<String> = ::std::iter::once("main()")
         .chain(trace_args.stack.iter().filter_map(|s| match_query(s)))
@@ -23,13 +28,39 @@ pub fn to_query_stack(trace_args: &mut TraceArgs) {
     trace_args.stack = stack;
 }
 
+/// Like `to_query_stack`, but each query is written as
+/// `query <- caller`, where `caller` is the innermost non-query frame
+/// between it and the query (or `main()`) above it: the code that
+/// forced the query. A query called directly by another query is left
+/// as is.
+pub fn to_query_context_stack(trace_args: &mut TraceArgs) {
+    trace_args.stack = query_context_stack(&trace_args.stack);
+}
+
+fn query_context_stack(stack: &[String]) -> Vec<String> {
+    let mut queries = vec!["main()".to_string()];
+    let mut caller = None;
+    for frame in stack {
+        match match_query(frame) {
+            Some(query) => {
+                queries.push(match caller.take() {
+                    Some(caller) => format!("{} <- {}", query, caller),
+                    None => query.to_string(),
+                });
+            }
+            None => caller = Some(frame),
+        }
+    }
+    queries
+}
+
 fn match_query(frame: &str) -> Option<&str> {
     // Try multiple formats a query symbol can have in different versions of
     // the compiler.
     let query_name = if frame.starts_with(QUERY_PREFIX_0) && frame.ends_with(QUERY_SUFFIX_0) {
         &frame[QUERY_PREFIX_0.len()..frame.len() - QUERY_SUFFIX_0.len()]
-    } else if frame.starts_with(QUERY_PREFIX_1) {
-        &frame[QUERY_PREFIX_1.len()..]
+    } else if let Some(name) = frame.strip_prefix(QUERY_PREFIX_1) {
+        name
     } else if frame.starts_with(QUERY_PREFIX_2) {
         // This is a mangled symbol, we have to parse out how many characters
         // to read for the query name.
@@ -0,0 +1,28 @@
+use super::*;
+
+const BORROWCK: &str = "rustc::ty::maps::<impl rustc::ty::maps::queries::borrowck<'tcx>>::force";
+const TYPECK: &str = "rustc::ty::maps::__query_compute::typeck_tables_of";
+const MIR: &str = "_ZN5rustc2ty4maps15__query_compute9optimized17h0123456789abcdefE";
+
+fn stack(frames: &[&str]) -> Vec<String> {
+    frames.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn context() {
+    let frames = stack(&["main", "rustc_driver::compile", BORROWCK, "rustc_mir::borrowck",
+                         "rustc_mir::check_body", TYPECK, MIR, "rustc_mir::optimize"]);
+    assert_eq!(query_context_stack(&frames), [
+        "main()",
+        "borrowck<'tcx> <- rustc_driver::compile",
+        "typeck_tables_of <- rustc_mir::check_body",
+        "optimized",
+    ]);
+}
+
+#[test]
+fn no_context() {
+    // frames below the last query are not the caller of anything
+    assert_eq!(query_context_stack(&stack(&[TYPECK, "leaf"])), ["main()", "typeck_tables_of"]);
+    assert_eq!(query_context_stack(&stack(&["main", "leaf"])), ["main()"]);
+}
@@ -0,0 +1,90 @@
+//! Counts how often each distinct whole stack occurs.
+
+use std::collections::HashMap;
+use util::percent;
+
+use super::AddFrames;
+
+#[cfg(test)]
+mod test;
+
+pub struct Stacks {
+    stacks: HashMap<Vec<String>, usize>,
+    samples: usize,
+}
+
+impl Default for Stacks {
+    fn default() -> Stacks {
+        Stacks::new()
+    }
+}
+
+impl Stacks {
+    pub fn new() -> Stacks {
+        Stacks { stacks: HashMap::new(), samples: 0 }
+    }
+
+    /// Number of distinct stacks seen.
+    pub fn distinct(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Number of samples added.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// The `n` most frequent stacks, most frequent first. Ties are
+    /// broken by the stack itself, so the order is deterministic.
+    pub fn top(&self, n: usize) -> Vec<(usize, &[String])> {
+        let mut stacks: Vec<(usize, &[String])> =
+            self.stacks.iter()
+                       .map(|(key, &value)| (value, &key[..]))
+                       .collect();
+
+        stacks.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+        stacks.truncate(n);
+        stacks
+    }
+
+    /// The smallest number of distinct stacks that together account
+    /// for at least `percent` percent of the samples. This tells
+    /// whether a hot path is really one path or many.
+    pub fn covering(&self, percent: usize) -> usize {
+        let mut counts: Vec<usize> = self.stacks.values().cloned().collect();
+        counts.sort_by(|a, b| b.cmp(a));
+
+        let mut covered = 0;
+        for (index, count) in counts.into_iter().enumerate() {
+            if covered * 100 >= percent * self.samples {
+                return index;
+            }
+            covered += count;
+        }
+        self.stacks.len()
+    }
+
+    pub fn dump(&self, total: usize, threshold: usize) {
+        println!("Distinct stacks: {} (of {} samples)", self.distinct(), self.samples);
+        println!("Stacks covering 50% / 90% of samples: {} / {}",
+                 self.covering(50), self.covering(90));
+        for (count, stack) in self.top(threshold).into_iter().rev() {
+            let percentage = percent(count, total);
+            println!("{:3}% {}", percentage, stack.join(";"));
+        }
+    }
+}
+
+impl AddFrames for Stacks {
+    fn add_frames<I>(&mut self, frames: I)
+        where I: Iterator<Item=String>
+    {
+        self.samples += 1;
+        *self.stacks.entry(frames.collect()).or_insert(0) += 1;
+    }
+
+    fn clear(&mut self) {
+        self.stacks.clear();
+        self.samples = 0;
+    }
+}
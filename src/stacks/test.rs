@@ -0,0 +1,63 @@
+use super::*;
+
+fn add(stacks: &mut Stacks, frames: &[&str]) {
+    stacks.add_frames(frames.iter().map(|s| s.to_string()));
+}
+
+#[test]
+fn distinct_and_top() {
+    let mut stacks = Stacks::new();
+    add(&mut stacks, &["main", "a", "b"]);
+    add(&mut stacks, &["main", "a"]);
+    add(&mut stacks, &["main", "a", "b"]);
+    add(&mut stacks, &["main", "c"]);
+    add(&mut stacks, &["main", "a", "b"]);
+    add(&mut stacks, &["main", "c"]);
+
+    assert_eq!(stacks.distinct(), 3);
+    assert_eq!(stacks.samples(), 6);
+
+    let top = stacks.top(2);
+    assert_eq!(top.len(), 2);
+    assert_eq!(top[0].0, 3);
+    assert_eq!(top[0].1.join(";"), "main;a;b");
+    assert_eq!(top[1].0, 2);
+    assert_eq!(top[1].1.join(";"), "main;c");
+
+    assert_eq!(stacks.top(10).len(), 3);
+}
+
+#[test]
+fn covering() {
+    let mut stacks = Stacks::new();
+    assert_eq!(stacks.covering(50), 0);
+
+    // one hot path with 6 of 10 samples, then 2 + 1 + 1
+    for _ in 0..6 {
+        add(&mut stacks, &["main", "hot"]);
+    }
+    add(&mut stacks, &["main", "a"]);
+    add(&mut stacks, &["main", "a"]);
+    add(&mut stacks, &["main", "b"]);
+    add(&mut stacks, &["main", "c"]);
+
+    assert_eq!(stacks.covering(0), 0);
+    assert_eq!(stacks.covering(50), 1);
+    assert_eq!(stacks.covering(60), 1);
+    assert_eq!(stacks.covering(61), 2);
+    assert_eq!(stacks.covering(90), 3);
+    assert_eq!(stacks.covering(100), 4);
+}
+
+#[test]
+fn clear_is_like_new() {
+    let mut stacks = Stacks::new();
+    add(&mut stacks, &["main", "a"]);
+    add(&mut stacks, &["main", "b"]);
+    stacks.clear();
+    assert_eq!((stacks.distinct(), stacks.samples()), (0, 0));
+
+    add(&mut stacks, &["main", "c"]);
+    assert_eq!(stacks.top(10).len(), 1);
+    assert_eq!(stacks.samples(), 1);
+}
@@ -0,0 +1,1421 @@
+use super::*;
+
+fn options(args: &[&str]) -> Options {
+    parse_args(args.iter().map(|s| s.to_string()))
+}
+
+#[test]
+fn process_name_regex_vs_exact() {
+    let regex = options(&["--process-name", "node"]);
+    assert!(process_name_matches(&regex, "node"));
+    assert!(process_name_matches(&regex, "nodejs"));
+
+    let exact = options(&["--process-name-exact", "node"]);
+    assert!(process_name_matches(&exact, "node"));
+    assert!(!process_name_matches(&exact, "nodejs"));
+}
+
+#[test]
+fn pid_name() {
+    let o = options(&["--pid-name", "42", "kworker", "--pid-name", "7", "ksoftirqd",
+                      "--process-name-exact", "kworker"]);
+    assert_eq!(process_name(&o, ":42"), "kworker");
+    assert_eq!(process_name(&o, ":7"), "ksoftirqd");
+    assert_eq!(process_name(&o, ":8"), ":8");
+    assert_eq!(process_name(&o, "42"), "42");
+
+    assert!(process_name_matches(&o, process_name(&o, ":42")));
+    assert!(!process_name_matches(&o, process_name(&o, ":7")));
+    assert!(!process_name_matches(&o, ":42"));
+}
+
+#[test]
+fn process_name_exact_metacharacters() {
+    let exact = options(&["--process-name-exact", "g++.real"]);
+    assert!(process_name_matches(&exact, "g++.real"));
+    assert!(!process_name_matches(&exact, "gg+Xreal"));
+}
+
+fn stack(frames: &[&str]) -> Vec<String> {
+    frames.iter().map(|s| s.to_string()).collect()
+}
+
+fn search(options: &Options, frames: &[String]) -> bool {
+    let matcher = options.matcher.as_ref().unwrap();
+    root_and_leaf_match(options, frames) && matcher.search_trace(frames).is_some()
+}
+
+#[test]
+fn root_and_leaf_filters() {
+    let o = options(&["--root", "^main$", "--leaf", "^je_"]);
+    assert!(root_and_leaf_match(&o, &stack(&["main", "a", "je_malloc"])));
+    assert!(!root_and_leaf_match(&o, &stack(&["start", "a", "je_malloc"])));
+    assert!(!root_and_leaf_match(&o, &stack(&["main", "je_malloc", "a"])));
+    assert!(!root_and_leaf_match(&o, &[]));
+
+    assert!(root_and_leaf_match(&options(&[]), &[]));
+}
+
+#[test]
+fn root_and_leaf_filters_with_matcher() {
+    let o = options(&["--root", "^main$", "--leaf", "^je_", "{^lib::}"]);
+    assert!(search(&o, &stack(&["main", "lib::f", "je_malloc"])));
+
+    // matcher fails
+    assert!(!search(&o, &stack(&["main", "app::f", "je_malloc"])));
+
+    // matcher succeeds but the leaf filter fails
+    assert!(!search(&o, &stack(&["main", "lib::f", "memcpy"])));
+
+    // matcher succeeds but the root filter fails
+    assert!(!search(&o, &stack(&["thread_start", "lib::f", "je_malloc"])));
+}
+
+#[test]
+fn leaf_regex_agrees_with_matcher() {
+    let fast = options(&["--leaf-regex", "^je_"]);
+    let slow = options(&["..{^je_},!."]);
+    let samples = [
+        stack(&["main", "a", "je_malloc"]),
+        stack(&["main", "je_malloc", "a"]),
+        stack(&["je_free"]),
+        stack(&["main"]),
+        vec![],
+    ];
+    for frames in &samples {
+        let expected = slow.matcher.as_ref().unwrap().search_trace(frames).is_some();
+        assert_eq!(root_and_leaf_match(&fast, frames), expected, "{:?}", frames);
+    }
+}
+
+/// Records every sample passed to `add_frames`.
+struct Samples(Vec<Vec<String>>);
+
+impl AddFrames for Samples {
+    fn add_frames<I>(&mut self, frames: I)
+    where
+        I: Iterator<Item = String>,
+    {
+        self.0.push(frames.collect());
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn split(args: &[&str], mode: GraphMode, frames: &[&str]) -> Vec<String> {
+    let o = options(args);
+    let matcher = o.matcher.as_ref().unwrap();
+    let frames = stack(frames);
+    let result = search_sample(&o, matcher, &frames).unwrap().unwrap();
+    let mut samples = Samples(vec![]);
+    add_frames(matcher, mode, &frames, result, &o, &mut samples, false);
+    samples.0.pop().unwrap()
+}
+
+const ANCHOR_STACK: &[&str] = &["top", "a", "x", "b", "c"];
+
+#[test]
+fn anchor_callers() {
+    let m = "matched `{a},..{b}`";
+    let callers = |args: &[&str]| split(args, GraphMode::Caller, ANCHOR_STACK);
+
+    assert_eq!(callers(&["{a}..{b}"]), vec![m, "top"]);
+    assert_eq!(callers(&["--anchor", "start", "{a}..{b}"]), vec![m, "top"]);
+    assert_eq!(callers(&["--anchor", "needle", "{a}..{b}"]), vec![m, "x", "a", "top"]);
+    assert_eq!(callers(&["--anchor", "end", "{a}..{b}"]), vec![m, "b", "x", "a", "top"]);
+}
+
+#[test]
+fn anchor_callees() {
+    let m = "matched `{a},..{b}`";
+    let callees = |args: &[&str]| split(args, GraphMode::Callee, ANCHOR_STACK);
+
+    assert_eq!(callees(&["{a}..{b}"]), vec![m, "c"]);
+    assert_eq!(callees(&["--anchor", "start", "{a}..{b}"]), vec![m, "a", "x", "b", "c"]);
+    assert_eq!(callees(&["--anchor", "needle", "{a}..{b}"]), vec![m, "c"]);
+    assert_eq!(callees(&["--anchor", "end", "{a}..{b}"]), vec![m, "c"]);
+}
+
+#[test]
+fn match_index() {
+    let frames = &["main", "f", "a", "f", "b", "f", "c"];
+    let callees = |args: &[&str]| split(args, GraphMode::Callee, frames);
+    let m = "matched `{^f$}`";
+
+    assert_eq!(callees(&["{^f$}"]), vec![m, "a", "f", "b", "f", "c"]);
+    assert_eq!(callees(&["--match-index", "1", "{^f$}"]), callees(&["{^f$}"]));
+    assert_eq!(callees(&["--match-index", "2", "{^f$}"]), vec![m, "b", "f", "c"]);
+    assert_eq!(callees(&["--match-index", "last", "{^f$}"]), vec![m, "c"]);
+
+    // counted in the --search order
+    let leaf_first = ["--search", "leaf-first", "--match-index", "2", "{^f$}"];
+    assert_eq!(callees(&leaf_first), vec![m, "b", "f", "c"]);
+    let leaf_first = ["--search", "leaf-first", "--match-index", "last", "{^f$}"];
+    assert_eq!(callees(&leaf_first), vec![m, "a", "f", "b", "f", "c"]);
+
+    let o = options(&["--match-index", "4", "{^f$}"]);
+    assert!(search_sample(&o, o.matcher.as_ref().unwrap(), &stack(frames)).unwrap().is_none());
+}
+
+#[test]
+fn exact() {
+    let o = options(&["--exact", "{^main$},{^a$}"]);
+    assert!(search(&o, &stack(&["main", "a"])));
+    assert!(!search(&o, &stack(&["main", "a", "b"])));
+    assert!(!search(&o, &stack(&["start", "main", "a"])));
+
+    // the whole stack is the match, so there are no callers or callees
+    assert_eq!(split(&["--exact", "{^top$},.,.,.,{^c$}"], GraphMode::Callee, ANCHOR_STACK),
+               ["matched `exact({^top$},.,.,.,{^c$})`"]);
+}
+
+#[test]
+fn anchor_needle_without_skip() {
+    // With no `..`, the needle is the first matching frame.
+    let callees = split(&["--anchor", "needle", "{a},{x}"], GraphMode::Callee, ANCHOR_STACK);
+    assert_eq!(callees, vec!["matched `{a},{x}`", "x", "b", "c"]);
+}
+
+#[test]
+fn anchor_needle_nested_skip() {
+    let callers = split(&["--anchor", "needle", "{a}..{x}..{c}"], GraphMode::Caller, ANCHOR_STACK);
+    assert_eq!(callers, vec!["matched `{a},..{x},..{c}`", "b", "x", "a", "top"]);
+}
+
+#[test]
+fn rename_from_file() {
+    let path = env::temp_dir().join("perf-focus-rename-from-file.txt");
+    ::std::fs::write(
+        &path,
+        "# collapse the select module\n\
+         (^middle::traits::select)::.*\t$1\n\
+         \n\
+         ::[a-zA-Z0-9_]+$\t\n",
+    ).unwrap();
+    let path = path.to_str().unwrap();
+
+    // file rules run first here, since the file comes first
+    let o = options(&["--rename-from-file", path, "--rename", "^middle", "mid"]);
+    assert_eq!(o.rename.len(), 3);
+    assert_eq!(
+        rename_frame(&o, "middle::traits::select::foo::bar".to_string()),
+        "mid::traits"
+    );
+    assert_eq!(rename_frame(&o, "je_malloc".to_string()), "je_malloc");
+
+    // ...and last here
+    let o = options(&["--rename", "^middle::traits", "x", "--rename-from-file", path]);
+    assert_eq!(rename_frame(&o, "middle::traits::select::foo".to_string()), "x::select");
+}
+
+#[test]
+fn rename_capture_groups() {
+    let o = options(&["--rename", r"(?P<m>\w+)::.*", "$m"]);
+    assert_eq!(rename_frame(&o, "core::ptr::drop_in_place".to_string()), "core");
+
+    // `${name}` and `$1` work too, and every match in the name is replaced
+    let o = options(&["--rename", r"(?P<t>\w+)<\w+>", "${t}_", "--rename", r"^(\w+)_::", "$1."]);
+    assert_eq!(rename_frame(&o, "Vec<T>::push<U>".to_string()), "Vec.push_");
+
+    // a group that took no part in the match expands to nothing
+    let o = options(&["--rename", r"^(?P<a>x)?y", "[$a]"]);
+    assert_eq!(rename_frame(&o, "y".to_string()), "[]");
+
+    // `$$` is a literal `$`, as is a `$` that starts no reference
+    let o = options(&["--rename", "^(?P<m>\\w+)$", "$$$m $"]);
+    assert_eq!(rename_frame(&o, "alloc".to_string()), "$alloc $");
+}
+
+#[test]
+fn rename_rules_errors() {
+    let err = parse_rename_rules("a\tb\n\n# ok\n(unclosed\tx\n").unwrap_err();
+    assert!(err.starts_with("line 4: invalid regular expression"), "{}", err);
+
+    let err = parse_rename_rules("no tab here").unwrap_err();
+    assert!(err.starts_with("line 1: expected"), "{}", err);
+}
+
+#[test]
+fn summary_only_detection() {
+    assert!(counts_only(&options(&[])));
+    assert!(counts_only(&options(&["{a}..{b}", "--relative"])));
+    assert!(counts_only(&options(&["--summary-only", "{a}"])));
+
+    assert!(!counts_only(&options(&["--tree-callees"])));
+    assert!(!counts_only(&options(&["--hist"])));
+    assert!(!counts_only(&options(&["--graph", "out.dot"])));
+    assert!(!counts_only(&options(&["--unique-stacks"])));
+    assert!(!counts_only(&options(&["--hist-pairs"])));
+    assert!(!counts_only(&options(&["--print-match"])));
+    assert!(!counts_only(&options(&["--script-miss"])));
+    assert!(!counts_only(&options(&["--jsonl-out", "out.jsonl"])));
+}
+
+#[test]
+fn idle_bucket() {
+    let samples = vec![
+        stack(&["main", "work"]),
+        stack(&[]),
+        stack(&["[unknown]", "[unknown]"]),
+        stack(&["main", "[unknown]"]),
+    ];
+
+    let count = |args: &[&str]| {
+        let o = options(args);
+        let empty_matcher = matcher::empty_matcher();
+        let matcher = o.matcher.as_ref().unwrap_or(&empty_matcher);
+        let mut hist = Histogram::new();
+        let mut matches = 0;
+        for sample in &samples {
+            let mut sample = sample.clone();
+            if o.include_idle {
+                bucket_idle(&mut sample);
+            }
+            if let Some(result) = search_sample(&o, matcher, &sample).unwrap() {
+                matches += 1;
+                add_frames(matcher, GraphMode::All, &sample, result, &o, &mut hist, false);
+            }
+        }
+        let mut out = vec![];
+        hist.dump(&mut out, samples.len(), 22, false).unwrap();
+        (matches, String::from_utf8(out).unwrap())
+    };
+
+    // by default the empty stack does not match at all, and the
+    // all-`[unknown]` one is reported as such
+    let (matches, out) = count(&[]);
+    assert_eq!(matches, 3);
+    assert!(!out.contains(IDLE_FRAME), "{}", out);
+    assert!(out.contains(" 50% [unknown]"), "{}", out);
+
+    let (matches, out) = count(&["--include-idle"]);
+    assert_eq!(matches, 4);
+    assert!(out.contains(" 50% [idle]"), "{}", out);
+    assert!(out.contains(" 25% [unknown]"), "{}", out);
+    assert!(out.contains(" 50% main"), "{}", out);
+
+    // an explicit `()` matches the empty stack too
+    let (matches, _) = count(&["()"]);
+    assert_eq!(matches, 4);
+}
+
+#[test]
+fn truncated_final_sample() {
+    // `head -c` cut the input off right after a header line
+    const DUMP: &str = "\
+rustc 100/101 1.0: cycles:
+\t2 a (/x)
+\t1 main (/x)
+
+rustc 100/101 1.1: cycles:";
+
+    let outcomes = |args: &[&str]| {
+        let o = options(args);
+        let empty_matcher = matcher::empty_matcher();
+        let matcher = o.matcher.as_ref().unwrap_or(&empty_matcher);
+        let mut outcomes = vec![];
+        trace::each_trace_from(&mut io::Cursor::new(DUMP.as_bytes()), |mut args| {
+            if o.include_idle {
+                bucket_idle(&mut args.stack);
+            }
+            let result = search_sample(&o, matcher, &args.stack).unwrap();
+            outcomes.push((args.stack.clone(), result.and_then(|r| selected_lines(&args, r))));
+        }).unwrap();
+        outcomes
+    };
+
+    // the last sample has no frames, just like a header-only sample
+    // in the middle of the input would
+    let main_a = stack(&["main", "a"]);
+    assert_eq!(outcomes(&[]), [(main_a.clone(), Some((3, 3))), (vec![], None)]);
+    assert_eq!(outcomes(&["()"]), [(main_a.clone(), Some((3, 3))), (vec![], Some((1, 1)))]);
+
+    // `[idle]` matches, but has no line of its own to highlight
+    assert_eq!(outcomes(&["--include-idle"]),
+               [(main_a, Some((3, 3))), (stack(&[IDLE_FRAME]), None)]);
+}
+
+#[test]
+fn search_direction_changes_split() {
+    let frames = &["top", "a", "x", "a", "c"];
+    let m = "matched `{^a$}`";
+    assert_eq!(split(&["{^a$}"], GraphMode::Callee, frames), vec![m, "x", "a", "c"]);
+    assert_eq!(split(&["--search", "leaf-first", "{^a$}"], GraphMode::Callee, frames), vec![m, "c"]);
+}
+
+#[test]
+fn graph_relative_denominator() {
+    let dot = |args: &[&str]| {
+        let o = options(args);
+        let mut graph = CallGraph::new();
+        graph.add_frames(stack(&["main", "a"]).into_iter());
+        graph.set_total(graph_total(&o, 1, 3), o.top_n);
+        let mut out = vec![];
+        graph.dump(&mut out).unwrap();
+
+        // node order depends on hashing
+        let mut lines: Vec<String> =
+            String::from_utf8(out).unwrap().lines().map(|l| l.to_string()).collect();
+        lines.sort();
+        lines.join("\n")
+    };
+
+    let matched = dot(&[]);
+    assert!(matched.contains("[label=\"100%\"]"), "{}", matched);
+    assert_eq!(matched, dot(&["--graph-relative", "matched"]));
+
+    let all = dot(&["--graph-relative", "all"]);
+    assert!(all.contains("[label=\"25%\"]"), "{}", all);
+
+    // `--relative` does not change what `all` means for the graph
+    assert_eq!(all, dot(&["--relative", "--graph-relative", "all"]));
+}
+
+#[test]
+fn tree_by_tid() {
+    let o = options(&["--tree", "--tree-by-tid", "--relative", "{main}"]);
+    assert!(validate(&o).is_ok());
+    let matcher = o.matcher.as_ref().unwrap();
+
+    // thread 1 spends 3 of 4 samples in `a`; thread 2 only has `b`;
+    // thread 3 never matches
+    let samples = [(1, &["main", "a"][..]), (1, &["main", "a"]), (2, &["main", "b"]),
+                   (1, &["main", "a"]), (1, &["main", "c"]), (2, &["idle"]), (3, &["idle"])];
+    let mut tree = TreeOutput::new(&o);
+    let mut thread_trees = HashMap::new();
+    for &(tid, frames) in &samples {
+        let frames = stack(frames);
+        let output = tree_output(&o, &mut tree, &mut thread_trees, Some(tid));
+        match matcher.search_trace(&frames) {
+            Some(result) => {
+                output.matched += 1;
+                add_frames(matcher, GraphMode::All, &frames, result, &o, &mut output.tree, false);
+            }
+            None => output.missed += 1,
+        }
+    }
+    assert_eq!(tree.tree.root_total(), 0);
+
+    let mut write = |tid: u32, label: &str| {
+        let mut out = vec![];
+        thread_trees.get_mut(&Some(tid)).unwrap().write(&mut out, &o, label, false).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(write(1, "Tree (tid 1)"), "\nTree (tid 1)\n\
+                                         | main (100% total, 0% self)\n\
+                                         : | a (75% total, 75% self)\n\
+                                         : | c (25% total, 25% self)\n");
+    assert_eq!(write(2, "Tree (tid 2)"), "\nTree (tid 2)\n\
+                                         | main (100% total, 0% self)\n\
+                                         : | b (100% total, 100% self)\n");
+
+    // all threads together: no header for thread 3
+    let mut out = vec![];
+    write_thread_trees(&mut out, &o, &mut thread_trees, "Tree", false).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("\nTree (tid 1)\n"), "{}", out);
+    assert!(out.contains("\nTree (tid 2)\n"), "{}", out);
+    assert!(!out.contains("tid 3"), "{}", out);
+}
+
+#[test]
+fn hot_path() {
+    let o = options(&["--tree", "--hot-path", "{main}"]);
+    assert!(validate(&o).is_ok());
+    let matcher = o.matcher.as_ref().unwrap();
+
+    let samples = [&["main", "a", "x"][..], &["main", "a", "x"], &["main", "a", "y"],
+                   &["main", "b"], &["idle"]];
+    let mut output = TreeOutput::new(&o);
+    for frames in &samples {
+        let frames = stack(frames);
+        match matcher.search_trace(&frames) {
+            Some(result) => {
+                output.matched += 1;
+                add_frames(matcher, GraphMode::All, &frames, result, &o, &mut output.tree, false);
+            }
+            None => output.missed += 1,
+        }
+    }
+
+    let mut write = |o: &Options| {
+        let mut out = vec![];
+        output.write(&mut out, o, "Hot Path", false).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(write(&o), "\nHot Path\n\
+                           | main (80% total)\n\
+                           : | a (60% total)\n\
+                           : : | x (40% total)\n");
+    let o = options(&["--tree", "--hot-path", "--tree-max-depth", "1", "{main}"]);
+    assert_eq!(write(&o), "\nHot Path\n| main (80% total)\n: | a (60% total)\n");
+    let o = options(&["--tree", "--hot-path", "--tree-min-percent", "50", "{main}"]);
+    assert_eq!(write(&o), "\nHot Path\n| main (80% total)\n: | a (60% total)\n");
+
+    assert!(validate(&options(&["--tree", "--hot-path", "--tree-leaf"])).is_err());
+}
+
+#[test]
+fn tree_relative_denominator() {
+    let dump = |args: &[&str]| {
+        let o = options(args);
+        let mut tree = Tree::new();
+        tree.add_frames(stack(&["main", "a"]).into_iter());
+        let mut out = vec![];
+        tree.dump(&mut out, tree_total(&o, 1, 3), usize::MAX, 0, false).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let matched = dump(&["--tree-relative", "matched"]);
+    assert_eq!(matched, "| main (100% total, 0% self)\n: | a (100% total, 100% self)\n");
+    assert_eq!(matched, dump(&["--relative"]));
+
+    let all = dump(&["--tree-relative", "all"]);
+    assert_eq!(all, "| main (25% total, 0% self)\n: | a (25% total, 25% self)\n");
+    assert_eq!(all, dump(&[]));
+    assert_eq!(all, dump(&["--relative", "--tree-relative", "all"]));
+}
+
+#[test]
+fn frequency_time_estimates() {
+    assert_eq!(options(&[]).frequency, None);
+    assert_eq!(options(&["--frequency", "997", "{a}"]).frequency, Some(997));
+    assert!(counts_only(&options(&["--frequency", "997"])));
+
+    assert_eq!(seconds_str(0, 1000), "0.00s");
+    assert_eq!(seconds_str(1250, 1000), "1.25s");
+    assert_eq!(seconds_str(3, 997), "0.00s");
+    assert_eq!(seconds_str(99700, 997), "100.00s");
+}
+
+#[test]
+fn max_search_steps() {
+    assert_eq!(options(&[]).max_search_steps, usize::MAX);
+    let o = options(&["--max-search-steps", "50", "..(({^a$}..{^b$})/{^x$})"]);
+    assert_eq!(o.max_search_steps, 50);
+
+    let frames = stack(&["a"; 20]);
+    let matcher = o.matcher.as_ref().unwrap();
+    assert!(matcher.search_trace_limited(&frames, o.search, o.max_search_steps).is_err());
+
+    // `--match-budget` is the same limit; a tiny one stops even a
+    // sample that would match
+    let o = options(&["--match-budget", "3", "..{^a$}..{^a$}..{^b$}"]);
+    assert_eq!(o.max_search_steps, 3);
+    let matcher = o.matcher.as_ref().unwrap();
+    let frames = stack(&["a", "x", "a", "y", "b"]);
+    assert!(matcher.search_trace_limited(&frames, o.search, usize::MAX).unwrap().is_some());
+    assert!(matcher.search_trace_limited(&frames, o.search, o.max_search_steps).is_err());
+}
+
+#[test]
+fn callers_of() {
+    let o = options(&["--callers-of", "{^x$}"]);
+    assert!(o.callers_of);
+    assert!(!counts_only(&o));
+    let matcher = o.matcher.as_ref().unwrap();
+
+    let mut callers = Histogram::new();
+    for frames in &[&["main", "a", "x"][..], &["main", "b", "x", "y"], &["main", "a", "x"], &["x"]] {
+        let frames = stack(frames);
+        let result = matcher.search_trace(&frames).unwrap();
+        callers.add(caller_of(&frames, result).to_string(), 1);
+    }
+
+    let mut counts: Vec<(&str, usize)> = callers.iter().collect();
+    counts.sort();
+    assert_eq!(counts, [("[no caller]", 1), ("a", 2), ("b", 1)]);
+}
+
+#[test]
+fn miss_hist() {
+    let o = options(&["--miss-hist", "--rename", "^std::.*$", "std", "{^a$}"]);
+    assert!(o.miss_hist);
+    assert!(!counts_only(&o));
+    let matcher = o.matcher.as_ref().unwrap();
+
+    let mut misses = histogram(&o);
+    let samples = [&["main", "a", "x"][..], &["main", "b", "std::io::read"],
+                   &["main", "b", "std::fs::write"], &["main", "c"], &[]];
+    for frames in &samples {
+        let frames = stack(frames);
+        if matcher.search_trace(&frames).is_none() {
+            misses.add(rename_frame(&o, leaf_of(&frames).to_string()), 1);
+        }
+    }
+
+    let mut counts: Vec<(&str, usize)> = misses.iter().collect();
+    counts.sort();
+    assert_eq!(counts, [("[no frames]", 1), ("c", 1), ("std", 2)]);
+}
+
+#[test]
+fn hist_regex_group() {
+    let query = r"({^main$}..{^rustc::queries::(?P<query>\w+)::force$})/{^idle$}";
+    let o = options(&["--hist-regex-group", "query", query]);
+    assert_eq!(o.hist_regex_group, Some("query".to_string()));
+    assert!(!counts_only(&o));
+    assert!(validate(&o).is_ok());
+    assert!(validate(&options(&["--hist-regex-group", "q", query])).is_err());
+    assert!(validate(&options(&["--hist-regex-group", "query"])).is_err());
+
+    let matcher = o.matcher.as_ref().unwrap();
+    let mut groups = Histogram::new();
+    for frames in &[&["main", "rustc::queries::typeck::force", "x"][..],
+                    &["main", "a", "rustc::queries::borrowck::force"],
+                    &["main", "rustc::queries::typeck::force"],
+                    &["idle"]] {
+        let frames = stack(frames);
+        let result = matcher.search_trace(&frames).unwrap();
+        groups.add(group_value(matcher, &frames, result, "query"), 1);
+    }
+
+    let mut counts: Vec<(&str, usize)> = groups.iter().collect();
+    counts.sort();
+    assert_eq!(counts, [("[no group]", 1), ("borrowck", 1), ("typeck", 2)]);
+}
+
+#[test]
+fn list_functions() {
+    let o = options(&["--list-functions", "--rename", "^x::.*", "x::*", "{main}"]);
+    assert!(!counts_only(&o));
+
+    let mut functions = BTreeSet::new();
+    for frames in &[&["main", "x::b", "malloc"][..], &["main", "x::a"], &["main", "free"]] {
+        add_functions(&o, &stack(frames), &mut functions);
+    }
+    let functions: Vec<&str> = functions.iter().map(|s| &s[..]).collect();
+    assert_eq!(functions, ["free", "main", "malloc", "x::*"]);
+}
+
+#[test]
+fn top_adjacent() {
+    let o = options(&["--top-adjacent", "{^x$}"]);
+    assert!(o.top_adjacent);
+    assert!(!counts_only(&o));
+    let matcher = o.matcher.as_ref().unwrap();
+
+    let adjacent = |frames: &[&str]| {
+        let frames = stack(frames);
+        let result = matcher.search_trace(&frames).unwrap();
+        (caller_of(&frames, result).to_string(), callee_of(&frames, result).to_string())
+    };
+
+    assert_eq!(adjacent(&["main", "a", "x", "y", "z"]), ("a".to_string(), "y".to_string()));
+
+    // matches at either end of the stack
+    assert_eq!(adjacent(&["x", "y"]), ("[no caller]".to_string(), "y".to_string()));
+    assert_eq!(adjacent(&["main", "x"]), ("main".to_string(), "[no callee]".to_string()));
+    assert_eq!(adjacent(&["x"]), ("[no caller]".to_string(), "[no callee]".to_string()));
+}
+
+#[test]
+fn trim_generics_examples() {
+    let cases = [
+        ("je_malloc", "je_malloc"),
+        ("core::iter::adapters::map::Map<I,F>::next", "core::iter::adapters::map::Map::next"),
+        ("alloc::vec::Vec<core::option::Option<alloc::string::String>>::push",
+         "alloc::vec::Vec::push"),
+        ("core::ops::function::FnOnce<fn(&u8) -> Box<u32>>::call_once",
+         "core::ops::function::FnOnce::call_once"),
+        ("core::iter::Iterator::collect::<alloc::vec::Vec<u8>>", "core::iter::Iterator::collect"),
+        ("<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop",
+         "<alloc::vec::Vec as core::ops::drop::Drop>::drop"),
+        ("<fn() -> u32 as Foo>::call", "<fn() -> u32 as Foo>::call"),
+        ("<core::cell::RefCell<T> as core::fmt::Debug>::fmt",
+         "<core::cell::RefCell as core::fmt::Debug>::fmt"),
+        ("foo::Bar<baz", "foo::Bar<baz"),
+    ];
+    for &(name, trimmed) in &cases {
+        assert_eq!(trim_generics(name), trimmed);
+    }
+}
+
+#[test]
+fn trim_generics_before_rename() {
+    let o = options(&["--trim-generics", "--rename", "^core::iter::adapters::", "iter::"]);
+    assert_eq!(rename_frame(&o, "core::iter::adapters::map::Map<I,F>::next".to_string()),
+               "iter::map::Map::next");
+    assert_eq!(rename_frame(&options(&[]), "Map<I,F>::next".to_string()), "Map<I,F>::next");
+}
+
+#[test]
+fn dedup_and_rename() {
+    // `x::a` and `x::b` both rename to `x`; `x::a` also recurses
+    let frames = ["main", "x::a", "x::a", "x::b"];
+    let count = |dedup: &str| {
+        let o = options(&["--hist", "--dedup", dedup, "--rename", "::.*", "", "{main}"]);
+        let mut hist = Histogram::new();
+        hist.set_dedup(o.dedup == Dedup::PostRename);
+        let frames = stack(&frames);
+        let matcher = o.matcher.as_ref().unwrap();
+        let result = matcher.search_trace(&frames).unwrap();
+        let dedup_first = o.dedup == Dedup::PreRename;
+        add_frames(matcher, GraphMode::All, &frames, result, &o, &mut hist, dedup_first);
+        let count = hist.iter().find(|&(name, _)| name == "x").unwrap().1;
+        count
+    };
+
+    assert_eq!(count("post-rename"), 1);
+    assert_eq!(count("pre-rename"), 2);
+    assert_eq!(count("none"), 3);
+}
+
+#[test]
+fn dedup_keeps_graph_edges() {
+    // `a` recurses through `x`; deduplicating must only change how
+    // often `a` is counted, not which calls the graph shows
+    let graph = |dedup: &str| {
+        let o = options(&["--graph", "out.dot", "--dedup", dedup, "{main}"]);
+        let mut graph = CallGraph::new();
+        graph.set_dedup(o.dedup == Dedup::PostRename);
+        let frames = stack(&["main", "a", "x", "a", "y"]);
+        let matcher = o.matcher.as_ref().unwrap();
+        let result = matcher.search_trace(&frames).unwrap();
+        let dedup_first = o.dedup == Dedup::PreRename;
+        add_frames(matcher, GraphMode::All, &frames, result, &o, &mut graph, dedup_first);
+        graph.set_total(1, 22);
+        let mut out = vec![];
+        graph.dump(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let edges = |dot: &str| {
+        let mut names = HashMap::new();
+        let mut edges = vec![];
+        for line in dot.lines().map(str::trim) {
+            if let Some((node, rest)) = line.split_once(" [label=\"") {
+                if !node.contains(" -> ") {
+                    names.insert(node.to_string(), rest.split(' ').next().unwrap().to_string());
+                }
+            }
+        }
+        for line in dot.lines().map(str::trim) {
+            if let Some((caller, rest)) = line.split_once(" -> ") {
+                let callee = rest.split(' ').next().unwrap();
+                edges.push(format!("{}->{}", names[caller], names[callee]));
+            }
+        }
+        edges.sort();
+        edges
+    };
+
+    let expected = vec!["a->x", "a->y", "main->a", "x->a"];
+    for dedup in &["none", "pre-rename", "post-rename"] {
+        let dot = graph(dedup);
+        assert_eq!(edges(&dot), expected, "--dedup {}:\n{}", dedup, dot);
+        let a_count = if *dedup == "none" { "200%" } else { "100%" };
+        assert!(dot.contains(&format!("[label=\"a ({})\"]", a_count)), "{}", dot);
+    }
+}
+
+#[test]
+fn match_depth_stats() {
+    let o = options(&["--match-depth-stats", "{^x$}"]);
+    assert!(o.match_depth_stats);
+    assert!(!counts_only(&o));
+
+    let mut depths = DepthStats::default();
+    assert_eq!(depths.summary(), None);
+
+    let matcher = o.matcher.as_ref().unwrap();
+    for frames in &[&["x"][..], &["main", "a", "x"], &["main", "x"], &["main", "a", "b", "c", "x"]] {
+        depths.add(matcher.search_trace(&stack(frames)).unwrap().first_matching_frame);
+    }
+    assert_eq!(depths.summary(), Some((0, 4, 1.75, 1.5)));
+
+    depths.add(2);
+    assert_eq!(depths.summary(), Some((0, 4, 1.8, 2.0)));
+}
+
+#[test]
+fn gaps() {
+    let o = options(&["--gaps", "{main}"]);
+    assert!(o.gaps);
+    assert!(!counts_only(&o));
+
+    let mut gaps = GapStats::default();
+    gaps.add(Some(10.0));
+    assert_eq!(gaps.summary(), None);
+
+    // a burst, a pause, and a sample with no timestamp
+    for &timestamp in &[Some(10.000_005), Some(10.000_055), None, Some(10.5), Some(12.5)] {
+        gaps.add(timestamp);
+    }
+    let (min, max, mean) = gaps.summary().unwrap();
+    assert!((min - 0.000_005).abs() < 1e-9, "{}", min);
+    assert!((max - 2.0).abs() < 1e-9, "{}", max);
+    assert!((mean - 0.625).abs() < 1e-9, "{}", mean);
+
+    assert_eq!(gaps.histogram(), [
+        ("< 10us", 1),
+        ("< 100us", 1),
+        ("< 1ms", 0),
+        ("< 10ms", 0),
+        ("< 100ms", 0),
+        ("< 1s", 1),
+        (">= 1s", 1),
+    ]);
+}
+
+const CPU_DUMP: &str = "\
+rustc 100/101 [000] 1.0: cycles:
+\t1 main (/x)
+
+rustc 100/102 [003] 1.1: cycles:
+\t1 main (/x)
+
+rustc 100/101 [003] 1.2: cycles:
+\t1 main (/x)
+";
+
+#[test]
+fn dump_parsed() {
+    let o = options(&["--dump-parsed"]);
+    assert!(o.dump_parsed);
+
+    let dump = "\
+:18830 18830 [002] 2552105.017823:     250000 cycles:u:
+\t1 leaf (/x)
+\t2 main (/x)
+
+rustc cycles:
+";
+    let mut text = String::new();
+    trace::each_trace_from(&mut io::Cursor::new(dump.as_bytes()), |args| {
+        text += &parsed_sample(text.matches("Sample").count() + 1, &args);
+    }).unwrap();
+    assert_eq!(text, "\
+Sample 1
+  process  : `:18830`
+  tid      : 18830
+  cpu      : 2
+  timestamp: 2552105.017823
+  event    : cycles:u
+  period   : 250000
+  stack    : 2 frames, outermost first
+      0 main
+      1 leaf
+Sample 2
+  process  : `rustc`
+  tid      : (none)
+  cpu      : (none)
+  timestamp: (none)
+  event    : (none)
+  period   : (none)
+  stack    : 0 frames, outermost first
+");
+}
+
+#[test]
+fn head_and_tail() {
+    let dump = "\
+rustc 100/101 1.0: cycles:
+\t1 a (/x)
+
+other 200/201 2.0: cycles:
+\t1 b (/x)
+
+rustc 100/101 3.0: cycles:
+\t1 c (/x)
+
+rustc 100/101 4.0: cycles:
+\t1 d (/x)
+
+other 200/201 5.0: cycles:
+\t1 e (/x)
+";
+    // the leaf of each sample that reaches `process`; the window counts
+    // only samples that pass the filters
+    let window = |args: &[&str]| {
+        let o = options(args);
+        let mut feed = Feed::new(&o);
+        let mut seen = vec![];
+        let mut process = |args: TraceArgs, _: &mut Filtered| seen.push(args.stack[0].clone());
+        trace::each_trace_from(&mut io::Cursor::new(dump.as_bytes()), |args| {
+            feed.sample(&o, args, &mut process)
+        }).unwrap();
+        feed.finish(&mut process);
+        seen
+    };
+
+    assert_eq!(window(&["--process-name-exact", "rustc"]), ["a", "c", "d"]);
+    assert_eq!(window(&["--process-name-exact", "rustc", "--head", "2"]), ["a", "c"]);
+    assert_eq!(window(&["--process-name-exact", "rustc", "--tail", "2"]), ["c", "d"]);
+    assert_eq!(window(&["--tail", "2"]), ["d", "e"]);
+    assert_eq!(window(&["--tail", "10"]), ["a", "b", "c", "d", "e"]);
+
+    // the kept samples come back whole
+    let mut tail = Tail::new(1);
+    let mut dropped = 0;
+    trace::each_trace_from(&mut io::Cursor::new(dump.as_bytes()), |args| {
+        if tail.push(&args) {
+            dropped += 1;
+        }
+    }).unwrap();
+    assert_eq!(dropped, 4);
+    let last = tail.samples[0].args();
+    assert_eq!((last.process_name, last.tid, last.timestamp), ("other", Some(201), Some(5.0)));
+    assert_eq!(last.header, ["other 200/201 5.0: cycles:", "\t1 e (/x)"]);
+
+    assert_eq!(validate(&options(&["--head", "2", "--tail", "2"])).unwrap_err(),
+               "--tail cannot be combined with --head");
+    assert_eq!(validate(&options(&["--max-samples", "2", "--tail", "2"])).unwrap_err(),
+               "--tail cannot be combined with --max-samples");
+}
+
+#[test]
+fn coverage() {
+    let o = options(&["--coverage", "{^a$},{^b$}"]);
+    assert!(counts_only(&o));
+    let matcher = o.matcher.as_ref().unwrap();
+
+    // 2 of the 3 samples match, but the match spans 2 of 10 frames
+    let mut coverage = Coverage::default();
+    assert_eq!(coverage.fraction(), None);
+    for frames in &[&["main", "a", "b", "x"][..], &["main", "x", "y"], &["a", "b", "c"]] {
+        let frames = stack(frames);
+        coverage.add(frames.len(), matcher.search_trace(&frames));
+    }
+    assert_eq!((coverage.covered, coverage.frames), (4, 10));
+    assert_eq!(coverage.fraction(), Some(0.4));
+
+    // `..` makes the match start at the outermost frame
+    let o = options(&["--coverage", "..{^b$}"]);
+    let mut coverage = Coverage::default();
+    let frames = stack(&["main", "a", "b", "x"]);
+    coverage.add(frames.len(), o.matcher.as_ref().unwrap().search_trace(&frames));
+    assert_eq!(coverage.fraction(), Some(0.75));
+}
+
+#[test]
+fn frames_only() {
+    const DUMP: &str = "\
+rustc 100/101 1.0: cycles:
+\t3 alloc::vec::Vec<T>::push (/x)
+\t2 rustc::parse (/x)
+\t1 main (/x)
+
+rustc 100/101 1.1: cycles:
+\t1 main (/x)
+";
+    let o = options(&["--frames-only", "--trim-generics"]);
+    assert!(!counts_only(&o));
+    assert!(validate(&o).is_ok());
+
+    let mut lines = vec![];
+    trace::each_trace_from(&mut io::Cursor::new(DUMP.as_bytes()), |args| {
+        lines.push(folded(&o, &args.stack));
+    }).unwrap();
+    assert_eq!(lines, ["main;rustc::parse;alloc::vec::Vec::push", "main"]);
+}
+
+#[test]
+fn oneline_summary() {
+    let path = env::temp_dir().join("perf-focus-oneline.txt");
+    ::std::fs::write(&path, "\
+rustc 100/101 1.0: cycles:
+\t2 a (/x)
+\t1 main (/x)
+
+rustc 100/101 1.1: cycles:
+\t1 main (/x)
+
+rustc 100/101 1.2: cycles:
+\t2 a (/x)
+\t1 main (/x)
+").unwrap();
+    let path = path.to_str().unwrap();
+
+    let o = options(&["--input", path, "--oneline", "{^a$}"]);
+    assert!(o.quiet);
+    assert!(validate(&o).is_ok());
+    assert!(validate(&options(&["--oneline", "--hist"])).is_err());
+
+    let (mut matches, mut total) = (0, 0);
+    trace::each_trace(&o.input, false, |args| {
+        total += 1;
+        if search(&o, &args.stack) {
+            matches += 1;
+        }
+    }).unwrap();
+    assert_eq!(oneline(&o, matches, total), format!("{}: matches=2 total=3 pct=66%", path));
+
+    assert_eq!(oneline(&options(&["--from-stdin"]), 0, 0), "-: matches=0 total=0 pct=0%");
+    assert_eq!(oneline(&options(&[]), 1, 4), "perf.data: matches=1 total=4 pct=25%");
+}
+
+#[test]
+fn cpu_filter() {
+    let tids_on = |args: &[&str]| {
+        let o = options(args);
+        let mut tids = vec![];
+        trace::each_trace_from(&mut io::Cursor::new(CPU_DUMP.as_bytes()), |args| {
+            if cpu_matches(&o, args.cpu).unwrap() {
+                tids.push(args.tid.unwrap());
+            }
+        }).unwrap();
+        tids
+    };
+
+    assert_eq!(tids_on(&[]), [101, 102, 101]);
+    assert_eq!(tids_on(&["--cpu", "3"]), [102, 101]);
+    assert_eq!(tids_on(&["--cpu", "0"]), [101]);
+    assert!(tids_on(&["--cpu", "7"]).is_empty());
+
+    // no CPU column
+    assert_eq!(cpu_matches(&options(&[]), None), Ok(true));
+    assert!(cpu_matches(&options(&["--cpu", "3"]), None).is_err());
+}
+
+#[test]
+fn verbose() {
+    let o = options(&["--verbose", "--process-name", "rustc", "--cpu", "3", "--root", "main",
+                      "{h}"]);
+    assert!(o.verbose);
+
+    let mut data = b"\t9 stray (/x)\n\n".to_vec();
+    data.extend_from_slice(b"rustc 100/101 [003] 1.0: cycles:\n\t2 f\xff (/x)\n\t1 main (/x)\n\n");
+    data.extend_from_slice(b"cargo 200/201 [003] 1.1: cycles:\n\t1 main (/x)\n\n");
+    data.extend_from_slice(b"rustc 100/101 [000] 1.2: cycles:\n\t1 main (/x)\n\n");
+    data.extend_from_slice(b"rustc 100/102 [003] 1.3: cycles:\n\t3 (/x)\n---\n\t1 start (/x)\n\n");
+    data.extend_from_slice(b"rustc 100/102 [003] 1.4: cycles:\n\t2 h (/x)\n\t1 main (/x)\n");
+
+    let report = |o: &Options| {
+        let matcher = o.matcher.as_ref().unwrap();
+        let mut feed = Feed::new(o);
+        let (mut matches, mut not_matches) = (0, 0);
+        let mut process = |args: TraceArgs, filtered: &mut Filtered| {
+            if filtered.root_and_leaf(o, &args.stack)
+                && matcher.search_trace(&args.stack).is_some()
+            {
+                matches += 1;
+            } else {
+                not_matches += 1;
+            }
+        };
+        let stats = trace::each_trace_from(&mut io::Cursor::new(&data[..]), |args| {
+            feed.sample(o, args, &mut process)
+        }).unwrap();
+        feed.finish(&mut process);
+        feed.filtered.report(matches, not_matches, 0, &stats)
+    };
+
+    assert_eq!(report(&o), [
+        ("Samples read", 5),
+        ("Dropped by process name", 1),
+        ("Dropped by --cpu", 1),
+        ("Before the --tail window", 0),
+        ("Not matching --root/--leaf", 1),
+        ("Over --max-search-steps", 0),
+        ("Matches", 1),
+        ("Not matches", 2),
+        ("Lines not valid UTF-8", 1),
+        ("Frames without a symbol", 1),
+        ("`---` marker lines", 1),
+        ("Frames before any header", 1),
+    ]);
+
+    // samples that fall out of the `--tail` window were still read
+    let o = options(&["--verbose", "--process-name", "rustc", "--cpu", "3", "--root", "main",
+                      "--tail", "1", "{h}"]);
+    assert_eq!(report(&o)[..7], [
+        ("Samples read", 5),
+        ("Dropped by process name", 1),
+        ("Dropped by --cpu", 1),
+        ("Before the --tail window", 2),
+        ("Not matching --root/--leaf", 0),
+        ("Over --max-search-steps", 0),
+        ("Matches", 1),
+    ]);
+}
+
+#[test]
+fn max_samples() {
+    // the limit is checked as each sample comes in, so that reading
+    // stops early only if there was a sample left over
+    let tids_with = |args: &[&str]| {
+        let o = options(args);
+        let mut feed = Feed::new(&o);
+        let mut tids = vec![];
+        let mut process = |args: TraceArgs, _: &mut Filtered| tids.push(args.tid.unwrap());
+        trace::each_trace_from(&mut io::Cursor::new(CPU_DUMP.as_bytes()), |args| {
+            feed.sample(&o, args, &mut process)
+        }).unwrap();
+        (tids, feed.stopped_early)
+    };
+
+    assert_eq!(tids_with(&[]), (vec![101, 102, 101], false));
+    assert_eq!(tids_with(&["--max-samples", "2"]), (vec![101, 102], true));
+    assert_eq!(tids_with(&["--max-samples", "1"]), (vec![101], true));
+    assert_eq!(tids_with(&["--max-samples", "3"]), (vec![101, 102, 101], false));
+}
+
+#[test]
+fn hist_and_tree_together() {
+    let o = options(&["--hist-callees", "--tree-callees", "{^a$}"]);
+    assert!(matches!(o.hist_mode, Some(GraphMode::Callee)));
+    assert!(matches!(o.tree_mode, Some(GraphMode::Callee)));
+
+    let frames = stack(&["main", "a", "b"]);
+    let matcher = o.matcher.as_ref().unwrap();
+    let result = matcher.search_trace(&frames).unwrap();
+    let mut hist = Histogram::new();
+    let mut tree = Tree::new();
+    add_frames(matcher, GraphMode::Callee, &frames, result, &o, &mut hist, false);
+    add_frames(matcher, GraphMode::Callee, &frames, result, &o, &mut tree, false);
+    assert_eq!(hist.iter().count(), 2);
+    assert_eq!(tree.root_total(), 1);
+    assert!(tree.find(&["matched `{^a$}`", "b"]).is_some());
+}
+
+#[test]
+fn tree_butterfly() {
+    let o = options(&["--tree-butterfly", "--rename", "^x::.*", "x", "{^m$}"]);
+    assert!(matches!(o.tree_mode, Some(GraphMode::Butterfly)));
+    let matcher = o.matcher.as_ref().unwrap();
+
+    let mut tree = Tree::new();
+    for frames in &[&["main", "x::a", "m", "b", "c"][..], &["main", "m", "b"]] {
+        let frames = stack(frames);
+        let result = matcher.search_trace(&frames).unwrap();
+        add_butterfly(matcher, &frames, result, &o, &mut tree, 1);
+    }
+
+    let total = |path: &[&str]| tree.find(path).map(|node| node.hits_total());
+    assert_eq!(tree.root_total(), 2);
+    assert_eq!(total(&["matched `{^m$}`"]), Some(2));
+    assert_eq!(total(&["matched `{^m$}`", "[callers]", "x", "main"]), Some(1));
+    assert_eq!(total(&["matched `{^m$}`", "[callers]", "main"]), Some(1));
+    assert_eq!(total(&["matched `{^m$}`", "[callees]", "b"]), Some(2));
+    assert_eq!(total(&["matched `{^m$}`", "[callees]", "b", "c"]), Some(1));
+}
+
+#[test]
+fn graph_stride() {
+    let o = options(&["--graph", "out.dot", "--graph-stride", "2", "{main}"]);
+    assert_eq!(o.graph_stride, 2);
+
+    let frames = stack(&["main", "a", "b", "c", "d", "e"]);
+    let matcher = o.matcher.as_ref().unwrap();
+    let result = matcher.search_trace(&frames).unwrap();
+    let mut samples = Samples(vec![]);
+    {
+        let mut strided = Strided { acc: &mut samples, stride: o.graph_stride };
+        add_frames(matcher, GraphMode::All, &frames, result, &o, &mut strided, false);
+    }
+    assert_eq!(samples.0, vec![vec!["main", "b", "d"]]);
+
+    // Callees start with the synthetic "matched" node.
+    let mut samples = Samples(vec![]);
+    {
+        let mut strided = Strided { acc: &mut samples, stride: 3 };
+        add_frames(matcher, GraphMode::Callee, &frames, result, &o, &mut strided, false);
+    }
+    assert_eq!(samples.0, vec![vec!["matched `{main}`", "c"]]);
+}
+
+#[test]
+fn graph_synthetic_root() {
+    let o = options(&["--graph", "out.dot", "--graph-synthetic-root"]);
+    assert!(o.graph_synthetic_root);
+
+    let mut graph = CallGraph::new();
+    for sample in &[&["main", "a"][..], &["thread_start", "b"], &["main", "b"]] {
+        let mut rooted = Rooted { acc: &mut graph, enabled: o.graph_synthetic_root };
+        rooted.add_frames(stack(sample).into_iter());
+    }
+    graph.set_total(3, 22);
+    let mut out = vec![];
+    graph.dump(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    // one shared root above both entry points; other percentages are
+    // as they would be without it
+    assert_eq!(out.matches("<root>").count(), 1, "{}", out);
+    assert!(out.contains("[label=\"<root> (100%)\"]"), "{}", out);
+    assert!(out.contains("[label=\"main (66%)\"]"), "{}", out);
+    assert_eq!(out.lines().filter(|l| l.starts_with("  n0 -> ")).count(), 2, "{}", out);
+}
+
+#[test]
+fn graph_flush_every() {
+    let path = env::temp_dir().join("perf-focus-graph-flush-every.dot");
+    let o = options(&["--graph", path.to_str().unwrap(), "--graph-flush-every", "2", "{main}"]);
+    assert_eq!(o.graph_flush_every, Some(2));
+
+    let mut graph = CallGraph::new();
+    graph.add_frames(stack(&["main", "a"]).into_iter());
+    flush_graph(&o, &mut graph, 1, 0);
+    let first = ::std::fs::read_to_string(&path).unwrap();
+    assert!(first.contains("[label=\"100%\"]"), "{}", first);
+
+    graph.add_frames(stack(&["main", "b"]).into_iter());
+    flush_graph(&o, &mut graph, 2, 0);
+    let second = ::std::fs::read_to_string(&path).unwrap();
+    assert_eq!(second.matches("[label=\"50%\"]").count(), 2, "{}", second);
+}
+
+#[test]
+fn min_percent() {
+    // `main` is in every sample, `a` in 3 of 4, `b` in 1 of 4
+    let samples = [stack(&["main", "a"]), stack(&["main", "a"]), stack(&["main", "a"]),
+                   stack(&["main", "b"])];
+    let path = env::temp_dir().join("perf-focus-min-percent.dot");
+    let path = path.to_str().unwrap();
+
+    let outputs = |args: &[&str]| {
+        let o = options(args);
+        let mut graph = call_graph(&o);
+        let mut hist = histogram(&o);
+        let mut tree = Tree::new();
+        for frames in &samples {
+            graph.add_frames(frames.iter().cloned());
+            hist.add_frames(frames.iter().cloned());
+            tree.add_frames(frames.iter().cloned());
+        }
+        graph.set_total(4, o.top_n);
+        let mut graph_out = vec![];
+        graph.dump(&mut graph_out).unwrap();
+        let mut hist_out = vec![];
+        hist.dump(&mut hist_out, 4, o.top_n, false).unwrap();
+        let mut tree_out = vec![];
+        tree.dump(&mut tree_out, 4, usize::MAX, o.tree_min_percent, false).unwrap();
+        let has_b = |out: Vec<u8>| String::from_utf8(out).unwrap().contains(" b");
+        let has_b_node = |out: Vec<u8>| String::from_utf8(out).unwrap().contains("\"b (");
+        (has_b_node(graph_out), has_b(hist_out), has_b(tree_out))
+    };
+
+    let all = ["--hist", "--tree", "--graph", path];
+    assert_eq!(outputs(&all), (true, true, true));
+
+    // the global floor reaches every output
+    let floored: Vec<&str> = all.iter().cloned().chain(vec!["--min-percent", "50"]).collect();
+    assert_eq!(outputs(&floored), (false, false, false));
+
+    // and the per-mode flags win over it, before or after it
+    let mut overridden = floored.clone();
+    overridden.extend(&["--graph-min-percent", "10"]);
+    assert_eq!(outputs(&overridden), (true, false, false));
+    let mut overridden = vec!["--tree-min-percent", "0"];
+    overridden.extend(&floored);
+    assert_eq!(outputs(&overridden), (false, false, true));
+
+    let o = options(&["--min-percent", "5", "--graph-min-percent", "1"]);
+    assert_eq!((o.graph_min_percent, o.tree_min_percent), (1, 5));
+}
+
+#[test]
+fn graph_from_several_inputs() {
+    // the first file stops right after its last frame
+    let phase1 = env::temp_dir().join("perf-focus-phase1.txt");
+    ::std::fs::write(&phase1, "rustc 100/101 1.0: cycles:\n\t2 a (/x)\n\t1 main (/x)").unwrap();
+    let phase2 = env::temp_dir().join("perf-focus-phase2.txt");
+    ::std::fs::write(&phase2, "rustc 100/101 2.0: cycles:\n\t2 b (/x)\n\t1 main (/x)\n").unwrap();
+    let (phase1, phase2) = (phase1.to_str().unwrap(), phase2.to_str().unwrap());
+
+    let o = options(&["--input", phase1, "--input", phase2]);
+    assert_eq!(o.input.name(), format!("{},{}", phase1, phase2));
+
+    let mut graph = CallGraph::new();
+    let mut samples = 0;
+    trace::each_trace(&o.input, false, |args| {
+        samples += 1;
+        graph.add_frames(args.stack.into_iter());
+    }).unwrap();
+    assert_eq!(samples, 2);
+
+    graph.set_total(samples, o.top_n);
+    let mut out = vec![];
+    graph.dump(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("[label=\"main (100%)\"]"), "{}", out);
+    assert!(out.contains("[label=\"a (50%)\"]"), "{}", out);
+    assert!(out.contains("[label=\"b (50%)\"]"), "{}", out);
+}
+
+#[test]
+fn graph_size() {
+    assert_eq!(parse_graph_size("7.5,10"), Some((7.5, 10.0)));
+    assert_eq!(parse_graph_size(" 8 , 6 "), Some((8.0, 6.0)));
+    assert_eq!(parse_graph_size("8"), None);
+    assert_eq!(parse_graph_size("8,6,4"), None);
+    assert_eq!(parse_graph_size("0,6"), None);
+    assert_eq!(parse_graph_size("x,6"), None);
+}
+
+#[test]
+fn top_n_from_env() {
+    // Other tests may parse options meanwhile; none of them depend on
+    // the exact default.
+    env::set_var(TOP_N_VAR, "40");
+    assert_eq!(options(&[]).top_n, 40);
+    assert_eq!(options(&["--top-n", "3"]).top_n, 3);
+
+    env::set_var(TOP_N_VAR, "lots");
+    assert_eq!(options(&["--quiet"]).top_n, DEFAULT_TOP_N);
+
+    env::remove_var(TOP_N_VAR);
+    assert_eq!(options(&[]).top_n, DEFAULT_TOP_N);
+}
+
+#[test]
+fn response_file() {
+    let path = env::temp_dir().join("perf-focus-response-file.txt");
+    ::std::fs::write(
+        &path,
+        "--hist-callees --top-n 5\n\
+         --rename '::.*' ''\n\
+         \"{^a::} .. {^b$}\"\n",
+    ).unwrap();
+    let arg = format!("@{}", path.to_str().unwrap());
+
+    let o = options(&["--from-stdin", &arg]);
+    assert_eq!(o.input, Input::Stdin);
+    assert!(matches!(o.hist_mode, Some(GraphMode::Callee)));
+    assert_eq!(o.top_n, 5);
+    assert_eq!(rename_frame(&o, "x::y".to_string()), "x");
+    assert_eq!(format!("{:?}", o.matcher.unwrap()), "{^a::},..{^b$}");
+}
+
+#[test]
+fn config_file() {
+    let path = env::temp_dir().join("perf-focus-config.toml");
+    ::std::fs::write(
+        &path,
+        "matcher = '{^a::}..{^b$}'\n\
+         tree-callees = true\n\
+         tree-max-depth = 3\n\
+         tree-min-percent = 2\n\
+         color = \"never\"\n\
+         quiet = false\n\
+         rename = [['::.*', ''], ['^b$', 'c']]\n",
+    ).unwrap();
+    let path = path.to_str().unwrap();
+
+    let o = options(&["--config", path]);
+    assert_eq!(format!("{:?}", o.matcher.as_ref().unwrap()), "{^a::},..{^b$}");
+    assert!(matches!(o.tree_mode, Some(GraphMode::Callee)));
+    assert_eq!((o.tree_max_depth, o.tree_min_percent), (3, 2));
+    assert!(matches!(o.color, ColorChoice::Never));
+    assert!(!o.quiet);
+    assert_eq!(rename_frame(&o, "x::y".to_string()), "x");
+    assert_eq!(rename_frame(&o, "b".to_string()), "c");
+
+    // the command line wins, also over a different mode of the same output
+    let o = options(&["--tree", "--tree-max-depth", "5", "--config", path, "{main}"]);
+    assert_eq!(format!("{:?}", o.matcher.as_ref().unwrap()), "{main}");
+    assert!(matches!(o.tree_mode, Some(GraphMode::All)));
+    assert_eq!((o.tree_max_depth, o.tree_min_percent), (5, 2));
+}
+
+#[test]
+fn config_file_types() {
+    let check = |text: &str| config_args(config::parse(text).unwrap(), &[]);
+
+    assert_eq!(check("top-n = [true]\n").unwrap_err(),
+               "`top-n`: expected an integer of at least 0");
+    assert_eq!(check("hist = 3\n").unwrap_err(), "`hist`: expected `true` or `false`");
+    assert_eq!(check("tree-max-depth = \"abc\"\n").unwrap_err(),
+               "`tree-max-depth`: expected an integer of at least 0");
+    assert_eq!(check("top-n = -1\n").unwrap_err(),
+               "`top-n`: expected an integer of at least 0");
+    assert_eq!(check("matcher = 3\n").unwrap_err(), "`matcher`: expected a string");
+    assert_eq!(check("rename = ['a', 'b']\n").unwrap_err(),
+               "`rename`: expected an array of [string, string] arrays");
+    assert_eq!(check("input = 'x'\n").unwrap_err(), "`input`: expected an array of strings");
+    assert_eq!(check("match-index = 'first'\n").unwrap_err(),
+               "`match-index`: expected an integer or \"last\"");
+    assert_eq!(check("colour = 'never'\n").unwrap_err(), "`colour`: unknown key");
+
+    assert_eq!(check("pid-name = [[7, 'x']]\nmatch-index = 'last'\nhist = false\n").unwrap(),
+               ["--pid-name", "7", "x", "--match-index", "last"]);
+}
+
+#[test]
+fn config_overridden_by_args() {
+    let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    // arguments of flags are not the matcher, even if they look like one
+    let cli = args(&["--graph-callers", "out.dot", "--rename", "{x}", "y", "--quiet"]);
+    assert!(!overridden("matcher", &cli));
+    assert!(overridden("graph", &cli));
+    assert!(!overridden("graph-min-percent", &cli));
+    assert!(!overridden("hist", &cli));
+    assert!(overridden("rename", &cli));
+    assert!(overridden("quiet", &cli));
+
+    let cli = args(&["--tree-callees", "--top-n", "5", "{main}"]);
+    assert!(overridden("matcher", &cli));
+    assert!(overridden("tree-butterfly", &cli));
+    assert!(!overridden("tree-max-depth", &cli));
+    assert!(overridden("top-n", &cli));
+}
+
+#[test]
+fn split_response_file_quoting() {
+    assert_eq!(split_response_file("  a\tb\n\nc  ").unwrap(), ["a", "b", "c"]);
+    assert_eq!(split_response_file("'a b' x\"y z\"w '' \"'\"").unwrap(),
+               ["a b", "xy zw", "", "'"]);
+    assert_eq!(split_response_file(r"{a\{2\}}").unwrap(), [r"{a\{2\}}"]);
+    assert!(split_response_file("'a b").is_err());
+}
+
+#[test]
+fn validate_conflicts() {
+    let error = |args: &[&str]| validate(&options(args)).err().unwrap();
+
+    assert!(validate(&options(&["--hist", "--tree", "--graph", "g.dot", "{a}"])).is_ok());
+    assert_eq!(error(&["--print-match", "--script-match"]),
+               "--print-match and --script-match cannot be combined");
+    assert_eq!(error(&["--hist", "--graph-degrees"]),
+               "--graph-degrees needs one of the --graph options");
+    assert_eq!(error(&["--summary-only", "--tree"]),
+               "--summary-only cannot be combined with output or aggregation options");
+    assert_eq!(error(&["--max-search-steps", "0"]), "--max-search-steps must be at least 1");
+    assert_eq!(error(&["--frames-only", "--hist"]),
+               "--frames-only cannot be combined with output or aggregation options");
+    assert!(error(&["--frames-only", "--summary-only"]).starts_with("--summary-only"));
+}
+
+#[test]
+fn validate_ignored_flags() {
+    let warnings = |args: &[&str]| validate(&options(args)).unwrap();
+
+    assert!(warnings(&["--tree-callees", "--tree-max-depth", "3", "--top-n", "5", "--hist"])
+        .is_empty());
+    assert_eq!(warnings(&["--hist", "--tree-max-depth", "3", "--tree-min-percent", "2"]),
+               ["ignoring --tree-max-depth, --tree-min-percent: no --tree option given"]);
+    assert_eq!(warnings(&["--tree", "--graph-size", "7,7", "--graph-stride", "2"]),
+               ["ignoring --graph-stride, --graph-size: no --graph option given"]);
+    assert_eq!(warnings(&["--tree", "--top-n", "5"]),
+               ["ignoring --top-n: no graph, histogram or list output asked for"]);
+    assert_eq!(warnings(&["--hist", "--anchor", "start"]),
+               ["ignoring --anchor: no caller or callee output asked for"]);
+    assert_eq!(warnings(&["--tree-leaf", "--dedup", "none"]), [
+        "ignoring --tree-leaf: no --tree option given",
+        "ignoring --dedup: no --graph or --hist option given",
+    ]);
+    assert_eq!(warnings(&["--min-percent", "5"]),
+               ["ignoring --min-percent: no graph, histogram or tree output asked for"]);
+    assert!(warnings(&["--tree", "--min-percent", "5"]).is_empty());
+}
+
+#[test]
+fn stacks_equal_clusters() {
+    let o = options(&["--stacks-equal", "--rename", "::.*", "", "{main}"]);
+    assert!(o.unique_stacks);
+
+    // after renaming, `x::a` and `x::b` are the same path
+    let mut stacks = Stacks::new();
+    let matcher = o.matcher.as_ref().unwrap();
+    for frames in &[["main", "x::a"], ["main", "x::b"], ["main", "y::c"]] {
+        let frames = stack(frames);
+        let result = matcher.search_trace(&frames).unwrap();
+        add_frames(matcher, GraphMode::All, &frames, result, &o, &mut stacks, false);
+    }
+    assert_eq!(stacks.distinct(), 2);
+    assert_eq!(stacks.covering(50), 1);
+    assert_eq!(stacks.covering(90), 2);
+}
@@ -1,83 +1,318 @@
-use itertools::Itertools;
-use std::io::{self, BufRead};
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::ControlFlow;
+use std::str;
 use std::process::{Command, Stdio};
 
+use progress::Progress;
+
 #[cfg(test)]
 mod test;
 
+/// The name used for frames that have no symbol.
+pub const UNKNOWN_FRAME: &str = "[unknown]";
+
 pub struct TraceArgs<'a> {
+    /// The lines of the sample exactly as `perf script` printed them,
+    /// header line first, without the blank line that separates
+    /// samples. Only the line endings (`\n` or `\r\n`) are removed;
+    /// `#` comment lines and `---` marker lines are not part of any
+    /// sample. A line that is not valid UTF-8 has each bad sequence
+    /// replaced by U+FFFD.
     pub header: &'a [String],
     pub process_name: &'a str,
+    /// Thread id from the header line, if one could be found.
+    pub tid: Option<u32>,
+    /// CPU the sample was taken on (the `[003]` column of the header
+    /// line), if the dump has one.
+    pub cpu: Option<u32>,
+    /// Timestamp (in seconds) from the header line, if one could be found.
+    pub timestamp: Option<f64>,
+    /// Event name that follows the timestamp (`cycles`, `cycles:u`),
+    /// without its trailing `:`, if the header line has one.
+    pub event: Option<&'a str>,
+    /// Sample period, which perf prints between the timestamp and the
+    /// event with `perf script -F +period`. For events like cache
+    /// misses or allocations, it is the cost of the sample.
+    pub cost: Option<u64>,
     pub stack: Vec<String>,
 }
 
-pub fn each_trace<F>(from_stdin: bool, callback: F) -> io::Result<()>
+/// A copy of a `TraceArgs` that owns its data, for keeping a sample
+/// after the `each_trace` callback returns.
+pub struct OwnedTrace {
+    header: Vec<String>,
+    process_name: String,
+    tid: Option<u32>,
+    cpu: Option<u32>,
+    timestamp: Option<f64>,
+    event: Option<String>,
+    cost: Option<u64>,
+    stack: Vec<String>,
+}
+
+impl OwnedTrace {
+    /// The sample, as `each_trace` first passed it on (the stack is
+    /// copied, since callers may rewrite it).
+    pub fn args(&self) -> TraceArgs<'_> {
+        TraceArgs {
+            header: &self.header,
+            process_name: &self.process_name,
+            tid: self.tid,
+            cpu: self.cpu,
+            timestamp: self.timestamp,
+            event: self.event.as_deref(),
+            cost: self.cost,
+            stack: self.stack.clone(),
+        }
+    }
+}
+
+impl<'a> TraceArgs<'a> {
+    pub fn to_owned_trace(&self) -> OwnedTrace {
+        OwnedTrace {
+            header: self.header.to_vec(),
+            process_name: self.process_name.to_string(),
+            tid: self.tid,
+            cpu: self.cpu,
+            timestamp: self.timestamp,
+            event: self.event.map(str::to_string),
+            cost: self.cost,
+            stack: self.stack.clone(),
+        }
+    }
+
+    /// Reconstructs the text of the sample from `header`, with `\n`
+    /// line endings and no trailing blank line.
+    pub fn raw(&self) -> String {
+        let mut text = String::new();
+        for line in self.header {
+            text.push_str(line);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Counts of the oddities `each_trace` ran into while parsing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ParseStats {
+    /// Lines that were not valid UTF-8 (see `TraceArgs::header`).
+    pub lossy_lines: usize,
+    /// Frames with no symbol, reported as `[unknown]`.
+    pub unknown_frames: usize,
+    /// `---` marker lines, which were skipped.
+    pub marker_lines: usize,
+    /// Frame lines with no header line before them, which were dropped.
+    pub stray_frames: usize,
+}
+
+/// What the callback given to `each_trace` returns: either `()`, to
+/// see every sample, or a `ControlFlow<()>` that is `Break` to stop
+/// reading after the current sample.
+pub trait Flow {
+    fn keep_going(self) -> bool;
+}
+
+impl Flow for () {
+    fn keep_going(self) -> bool {
+        true
+    }
+}
+
+impl Flow for ControlFlow<()> {
+    fn keep_going(self) -> bool {
+        self.is_continue()
+    }
+}
+
+/// Where `each_trace` reads `perf script` output from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    /// Run `perf script`, which reads `perf.data`.
+    PerfScript,
+    Stdin,
+    /// Files holding `perf script` output, read one after the other
+    /// as if they were one.
+    Files(Vec<String>),
+}
+
+impl Input {
+    /// A name for the input in messages: the file names, separated by
+    /// commas, `-` for stdin, or the `perf.data` that `perf script`
+    /// reads.
+    pub fn name(&self) -> String {
+        match self {
+            Input::PerfScript => "perf.data".to_string(),
+            Input::Stdin => "-".to_string(),
+            Input::Files(paths) => paths.join(","),
+        }
+    }
+}
+
+/// Calls `callback` on each sample of `perf script` output, read from
+/// `input`, until it asks to stop (see `Flow`). Returns what was odd
+/// about the input (see `ParseStats`). With `progress`, a progress
+/// indicator is drawn on stderr; see `each_trace_with_progress`.
+pub fn each_trace<F, R>(input: &Input, progress: bool, callback: F) -> io::Result<ParseStats>
 where
-    F: FnMut(TraceArgs),
+    F: FnMut(TraceArgs) -> R,
+    R: Flow,
 {
-    if from_stdin {
-        let stdin = io::stdin();
-        let mut stdin = stdin.lock();
-        each_trace_impl(&mut stdin, callback)
+    // only a regular file has a size to measure against
+    let size = |metadata: io::Result<fs::Metadata>| {
+        metadata.ok().filter(|metadata| metadata.is_file()).map(|metadata| metadata.len())
+    };
+
+    match input {
+        Input::Stdin => {
+            let stdin = io::stdin();
+            let total = if progress { size(fs::metadata("/dev/stdin")) } else { None };
+            read(&mut stdin.lock(), progress, total, callback)
+        }
+        Input::Files(paths) => {
+            // a blank line after each file keeps a file that does not
+            // end in one from running into the next
+            let mut chained: Box<dyn Read> = Box::new(io::empty());
+            let mut total = Some(0);
+            for path in paths {
+                let file = File::open(path)?;
+                total = total.and_then(|total| Some(total + size(file.metadata())? + 1));
+                chained = Box::new(chained.chain(file).chain(&b"\n"[..]));
+            }
+            let total = if progress { total } else { None };
+            read(&mut io::BufReader::new(chained), progress, total, callback)
+        }
+        Input::PerfScript => {
+            let mut cmd = Command::new("perf")
+                .arg("script")
+                .stderr(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let mut stdout = io::BufReader::new(cmd.stdout.as_mut().unwrap());
+            read(&mut stdout, progress, None, callback)
+        }
+    }
+}
+
+fn read<F, R>(input: &mut dyn BufRead, progress: bool, total_bytes: Option<u64>, callback: F)
+              -> io::Result<ParseStats>
+where
+    F: FnMut(TraceArgs) -> R,
+    R: Flow,
+{
+    if progress {
+        each_trace_with_progress(input, &mut io::stderr(), total_bytes, callback)
     } else {
-        let mut cmd = Command::new("perf")
-            .arg("script")
-            .stderr(Stdio::null())
-            .stdout(Stdio::piped())
-            .spawn()?;
-        each_trace_impl(
-            &mut io::BufReader::new(cmd.stdout.as_mut().unwrap()),
-            callback,
-        )
+        each_trace_from(input, callback)
     }
 }
 
-fn each_trace_impl<F>(stdin: &mut BufRead, mut callback: F) -> io::Result<()>
+/// Like `each_trace_from`, but draws a `Progress` indicator on `out`
+/// as samples are read: the percentage of `total_bytes` consumed if
+/// the input size is known, or else a spinner with the sample count.
+pub fn each_trace_with_progress<F, R>(
+    stdin: &mut dyn BufRead,
+    out: &mut dyn Write,
+    total_bytes: Option<u64>,
+    mut callback: F,
+) -> io::Result<ParseStats>
 where
-    F: FnMut(TraceArgs),
+    F: FnMut(TraceArgs) -> R,
+    R: Flow,
 {
-    let mut trigger = |mut frames: Vec<String>| -> Vec<String> {
-        if !frames.is_empty() {
-            // frames is a vector containing one sample from perf,
-            // including the header line:
-            //
-            // rustc 18883 2323302.039150: cycles:
-            //     7f82e6dee178 je_arena_salloc (/some/path.so)
-            //     ...
-
-            {
-                // First, extract the name of the process
-                let mut header_words = frames[0].split(char::is_whitespace);
-                let process_name = header_words.next().unwrap_or("<no-process>");
-
-                // Next, create a secondary vector containing just the
-                // callstack. Put this in order from top to bottom
-                // (reverse of perf), since that's what the matching code
-                // expects. (Arguably we should rewrite the matching
-                // code.)
-                let mut stack = vec![];
-                for frame in frames[1..].iter().rev() {
-                    let words = frame.trim().split(char::is_whitespace);
-                    let fn_name: String = words
-                        .skip(1)
-                        .take_while(|w| !w.starts_with('('))
-                        .intersperse(" ")
-                        .collect();
-                    stack.push(fn_name);
-                }
+    let bytes = Cell::new(0);
+    let mut progress = Progress::new(out, total_bytes);
+    let mut samples = 0;
+    let stats = each_trace_from(&mut Counting { inner: stdin, bytes: &bytes }, |args| {
+        samples += 1;
+        // the indicator is best-effort; a closed stderr mustn't stop the run
+        let _ = progress.update(bytes.get(), samples);
+        callback(args)
+    })?;
+    let _ = progress.finish();
+    Ok(stats)
+}
 
-                let args = TraceArgs {
-                    header: &frames,
-                    process_name: process_name,
-                    stack: stack,
-                };
-                callback(args);
-            }
+/// A reader that counts the bytes consumed through it.
+struct Counting<'a> {
+    inner: &'a mut dyn BufRead,
+    bytes: &'a Cell<u64>,
+}
 
-            frames.truncate(0);
+impl<'a> Read for Counting<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.set(self.bytes.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<'a> BufRead for Counting<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes.set(self.bytes.get() + amt as u64);
+        self.inner.consume(amt)
+    }
+}
+
+/// Like `each_trace`, but reads `perf script` output from an
+/// arbitrary reader rather than from stdin or a child process.
+pub fn each_trace_from<F, R>(stdin: &mut dyn BufRead, mut callback: F) -> io::Result<ParseStats>
+where
+    F: FnMut(TraceArgs) -> R,
+    R: Flow,
+{
+    // Reports the sample in `frames`, if any, and leaves `frames`
+    // empty; returns false if the callback asked to stop. `frames` is
+    // either empty (we are between samples) or holds one sample from
+    // perf, header line first:
+    //
+    // rustc 18883 2323302.039150: cycles:
+    //     7f82e6dee178 je_arena_salloc (/some/path.so)
+    //     ...
+    //
+    // so separators may repeat, lead or trail freely.
+    let mut unknown_frames = 0;
+    let mut finish = |frames: &mut Vec<String>| {
+        if frames.is_empty() {
+            return true;
         }
 
-        frames
+        let keep_going = {
+            // First, extract the name of the process, and the
+            // thread id, CPU and timestamp if present.
+            let mut header_words = frames[0].split(char::is_whitespace);
+            let process_name = header_words.next().unwrap_or("<no-process>");
+            let fields = parse_header_fields(header_words);
+
+            // Next, create a secondary vector containing just the
+            // callstack. Put this in order from top to bottom
+            // (reverse of perf), since that's what the matching code
+            // expects. (Arguably we should rewrite the matching
+            // code.)
+            let stack: Vec<String> = frames[1..].iter().rev().map(|f| frame_name(f)).collect();
+            unknown_frames += stack.iter().filter(|f| *f == UNKNOWN_FRAME).count();
+
+            let args = TraceArgs {
+                header: frames,
+                process_name,
+                tid: fields.tid,
+                cpu: fields.cpu,
+                timestamp: fields.timestamp,
+                event: fields.event,
+                cost: fields.cost,
+                stack,
+            };
+            callback(args).keep_going()
+        };
+
+        frames.clear();
+        keep_going
     };
 
     // Note: I used to use regular expressions here, but the perf
@@ -88,34 +323,220 @@ where
     // let blank_re = Regex::new(r"^\s*$").unwrap();
 
     let mut frames = vec![];
-    for line in stdin.lines() {
-        let line = line?;
+    let mut stats = ParseStats::default();
+    let mut bytes = vec![];
+    loop {
+        bytes.clear();
+        if stdin.read_until(b'\n', &mut bytes)? == 0 {
+            break;
+        }
+
+        // strip both `\n` and `\r\n`
+        if bytes.ends_with(b"\n") {
+            bytes.pop();
+            if bytes.ends_with(b"\r") {
+                bytes.pop();
+            }
+        }
+
+        // some demanglings (or locales) produce bytes that aren't
+        // UTF-8; don't let one such line end the whole run
+        let line = match str::from_utf8(&bytes) {
+            Ok(line) => line.to_string(),
+            Err(_) => {
+                stats.lossy_lines += 1;
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+        };
 
         // comment
         if line.starts_with('#') {
             continue;
         }
 
-        // empty line.
+        // some perf versions print a `---` line where unwinding
+        // stopped; it is neither a header nor a frame, and must not
+        // split the sample in two
+        if is_marker(&line) {
+            stats.marker_lines += 1;
+            continue;
+        }
+
+        // empty line, ending the current sample (if any)
         if line.trim().is_empty() {
-            frames = trigger(frames);
+            if !finish(&mut frames) {
+                break;
+            }
             continue;
         }
 
         // header line like `rustc 18883 2323302.039150: cycles:`:
         if !line.starts_with(char::is_whitespace) {
-            frames = trigger(frames);
-
+            if !finish(&mut frames) {
+                break;
+            }
             frames.push(line);
             continue;
         }
 
-        // data like `7f82e6dee178 je_arena_salloc (/some/path.so)`
-        assert!(!frames.is_empty()); // should have the head line in it
-        frames.push(line);
+        // data like `7f82e6dee178 je_arena_salloc (/some/path.so)`;
+        // a frame with no header line before it has no sample to
+        // belong to, so drop it
+        if frames.is_empty() {
+            stats.stray_frames += 1;
+        } else {
+            frames.push(line);
+        }
     }
 
-    trigger(frames);
+    // the last sample need not be followed by a blank line
+    finish(&mut frames);
+
+    stats.unknown_frames = unknown_frames;
+    Ok(stats)
+}
+
+/// True for a line made up of three or more dashes, possibly indented.
+fn is_marker(line: &str) -> bool {
+    let line = line.trim();
+    line.len() >= 3 && line.chars().all(|c| c == '-')
+}
+
+/// Extracts the function name from a frame line like
+/// `7f82e6dee178 je_arena_salloc (/some/path.so)`. Lines without a
+/// symbol (`7f82e6dee178 (/some/path.so)`) yield `[unknown]`, and a
+/// trailing sample period such as `[1234]` is dropped.
+fn frame_name(frame: &str) -> String {
+    let mut words: Vec<&str> = frame
+        .trim()
+        .split(char::is_whitespace)
+        .skip(1)
+        .take_while(|w| !w.starts_with('('))
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    if words.last().is_some_and(|w| is_period(w)) {
+        words.pop();
+    }
+
+    if words.is_empty() {
+        UNKNOWN_FRAME.to_string()
+    } else {
+        words.join(" ")
+    }
+}
+
+/// Names each frame of `trace_args.stack` that has no symbol after
+/// its address, as in `0x7f82e6dee179`, rather than `[unknown]`. With
+/// `normalize`, only the address's offset within its page is kept,
+/// after the name of the file it is in, as in `path.so+0x179`: ASLR
+/// moves each mapping by whole pages, so this names the same code the
+/// same way in every run. A stack that was already rewritten is left
+/// alone, since its frames no longer line up with the header.
+pub fn keep_addresses(trace_args: &mut TraceArgs, normalize: bool) {
+    let frames = &trace_args.header[1..];
+    if frames.len() != trace_args.stack.len() {
+        return;
+    }
+
+    for (name, frame) in trace_args.stack.iter_mut().zip(frames.iter().rev()) {
+        if name == UNKNOWN_FRAME {
+            if let Some(address) = frame_address(frame, normalize) {
+                *name = address;
+            }
+        }
+    }
+}
+
+const PAGE_MASK: u64 = 0xfff;
+
+/// The address of a frame line like `7f82e6dee179 (/some/path.so)`,
+/// written as described in `keep_addresses`.
+fn frame_address(frame: &str, normalize: bool) -> Option<String> {
+    let address = frame.split_whitespace().next()?;
+    let address = u64::from_str_radix(address, 16).ok()?;
+    if !normalize {
+        return Some(format!("0x{:x}", address));
+    }
+
+    let file = frame.rfind('(')
+                    .map(|start| frame[start + 1..].trim_end().trim_end_matches(')'))
+                    .map(|path| path.rsplit('/').next().unwrap_or(path))
+                    .filter(|file| !file.is_empty())
+                    .unwrap_or(UNKNOWN_FRAME);
+    Some(format!("{}+0x{:x}", file, address & PAGE_MASK))
+}
+
+/// True for a bracketed sample period like `[1234]`.
+fn is_period(word: &str) -> bool {
+    word.len() > 2
+        && word.starts_with('[')
+        && word.ends_with(']')
+        && word[1..word.len() - 1].bytes().all(|b| b.is_ascii_digit())
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct HeaderFields<'a> {
+    tid: Option<u32>,
+    cpu: Option<u32>,
+    timestamp: Option<f64>,
+    event: Option<&'a str>,
+    cost: Option<u64>,
+}
+
+/// Given the words of a header line that follow the process name,
+/// e.g. `18883 2323302.039150: cycles:` or
+/// `18883/18890 [003] 2323302.039150: cycles:`, extracts the thread id,
+/// CPU, timestamp, period and event. The thread id is the first word of
+/// the form `tid` or `pid/tid`; the CPU is a word of the form `[cpu]`
+/// before the timestamp; the timestamp is the first word of the form
+/// `secs:`; the period is a number between it and the event (perf
+/// prints one there with `-F +period`); the event is the first word
+/// after the timestamp that is not a number.
+fn parse_header_fields<'a, I>(words: I) -> HeaderFields<'a>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut fields = HeaderFields::default();
+
+    for word in words {
+        if word.is_empty() {
+            continue;
+        }
+
+        if fields.timestamp.is_some() {
+            match word.parse::<u64>() {
+                Ok(cost) => fields.cost = Some(cost),
+                Err(_) => {
+                    fields.event = Some(word.strip_suffix(':').unwrap_or(word));
+                    break;
+                }
+            }
+            continue;
+        }
+
+        if fields.tid.is_none() && fields.cpu.is_none() {
+            let id = word.rsplit('/').next().unwrap();
+            if let Ok(id) = id.parse::<u32>() {
+                fields.tid = Some(id);
+                continue;
+            }
+        }
+
+        if let Some(Ok(cpu)) = word
+            .strip_prefix('[')
+            .and_then(|w| w.strip_suffix(']'))
+            .map(str::parse::<u32>)
+        {
+            fields.cpu = Some(cpu);
+            continue;
+        }
+
+        if let Some(Ok(t)) = word.strip_suffix(':').map(str::parse::<f64>) {
+            fields.timestamp = Some(t);
+            continue;
+        }
+    }
 
-    Ok(())
+    fields
 }
@@ -1,13 +1,14 @@
 use super::*;
 
 use std::io::Cursor;
+use std::ops::ControlFlow;
 
 fn test(data: &str, expected_frame_counts: &[usize]) {
     let mut frame_counts = vec![];
-    let cursor = Cursor::new(data.as_bytes());
-    each_trace(cursor, |frames| {
-        frame_counts.push(frames.len());
-    });
+    let mut cursor = Cursor::new(data.as_bytes());
+    each_trace_from(&mut cursor, |args| {
+        frame_counts.push(args.stack.len());
+    }).unwrap();
     assert_eq!(expected_frame_counts, &frame_counts[..]);
 }
 
@@ -70,3 +71,280 @@ fn test_run_1() {
 
     test(data, &[16, 16]);
 }
+
+#[test]
+fn test_synthetic_dump() {
+    let data = ::fixture::synthetic_dump(100);
+    let mut stacks = vec![];
+    let mut cursor = Cursor::new(data.as_bytes());
+    each_trace_from(&mut cursor, |args| {
+        stacks.push(args.stack);
+    }).unwrap();
+    assert_eq!(stacks.len(), 100);
+    assert!(stacks.iter().all(|s| s[0] == "main" && s.len() > 4));
+}
+
+fn header_fields(header: &str) -> (Option<u32>, Option<f64>) {
+    let mut words = header.split(char::is_whitespace);
+    words.next();
+    let fields = parse_header_fields(words);
+    (fields.tid, fields.timestamp)
+}
+
+#[test]
+fn test_header_fields() {
+    assert_eq!(header_fields("rustc 18883 2323302.039150: cycles:"),
+               (Some(18883), Some(2323302.039150)));
+    assert_eq!(header_fields("rustc 18883/18890 [003] 2323302.5: cycles:"),
+               (Some(18890), Some(2323302.5)));
+    assert_eq!(header_fields(":18830 18830 2552105.017823: cycles: "),
+               (Some(18830), Some(2552105.017823)));
+    assert_eq!(header_fields("rustc cycles:"), (None, None));
+}
+
+#[test]
+fn test_header_event() {
+    let event = |header: &'static str| {
+        let mut words = header.split(char::is_whitespace);
+        words.next();
+        parse_header_fields(words).event
+    };
+    assert_eq!(event("rustc 18883 2323302.039150: cycles: "), Some("cycles"));
+    assert_eq!(event("rustc 18883 [003] 2323302.5:     250000 cycles:u: "), Some("cycles:u"));
+    assert_eq!(event("rustc 18883 2323302.5:"), None);
+    assert_eq!(event("rustc cycles:"), None);
+}
+
+#[test]
+fn test_header_cost() {
+    let cost = |header: &'static str| {
+        let mut words = header.split(char::is_whitespace);
+        words.next();
+        parse_header_fields(words).cost
+    };
+    assert_eq!(cost("rustc 18883 [003] 2323302.5:     250000 cycles:u: "), Some(250000));
+    assert_eq!(cost("rustc 18883 2323302.5: 64 mem-loads: "), Some(64));
+    assert_eq!(cost("rustc 18883 2323302.039150: cycles: "), None);
+}
+
+#[test]
+fn test_symbolless_frames() {
+    let data = "\
+rustc 18883 2323302.039150: cycles:
+\t    7f82e6dee178 je_arena_salloc (/some/path.so)
+\t    7f82e6dee179 (/some/path.so)
+\t    7f82e6dee17a <impl Foo for Bar>::baz [1234] (/some/path.so)
+\t    7f82e6dee17b [unknown] ([kernel.kallsyms])
+\t    7f82e6dee17c
+";
+    let mut stacks = vec![];
+    each_trace_from(&mut Cursor::new(data.as_bytes()), |args| {
+        stacks.push(args.stack);
+    }).unwrap();
+
+    assert_eq!(stacks, vec![vec![
+        "[unknown]",
+        "[unknown]",
+        "<impl Foo for Bar>::baz",
+        "[unknown]",
+        "je_arena_salloc",
+    ]]);
+}
+
+#[test]
+fn test_keep_addresses() {
+    let data = "\
+rustc 18883 2323302.039150: cycles:
+\t    7f82e6dee178 je_arena_salloc (/some/path.so)
+\t    7f82e6dee179 (/some/path.so)
+\t    ffffffff8103e17b [unknown] ([kernel.kallsyms])
+\t    55d0c3a2417c
+\t    2 [unknown] ([unknown])
+";
+    let stacks = |normalize| {
+        let mut stacks = vec![];
+        each_trace_from(&mut Cursor::new(data.as_bytes()), |mut args| {
+            keep_addresses(&mut args, normalize);
+            stacks.push(args.stack);
+        }).unwrap();
+        stacks
+    };
+
+    assert_eq!(stacks(false), vec![vec![
+        "0x2",
+        "0x55d0c3a2417c",
+        "0xffffffff8103e17b",
+        "0x7f82e6dee179",
+        "je_arena_salloc",
+    ]]);
+
+    // the same code, loaded elsewhere in another run, gets the same
+    // name
+    assert_eq!(stacks(true), vec![vec![
+        "[unknown]+0x2",
+        "[unknown]+0x17c",
+        "[kernel.kallsyms]+0x17b",
+        "path.so+0x179",
+        "je_arena_salloc",
+    ]]);
+    assert_eq!(frame_address("7f0011dee179 (/some/path.so)", true),
+               frame_address("7f82e6dee179 (/other/path.so)", true));
+}
+
+const SAMPLE_A: &str = "a 1 1.0: cycles:\n\t1 f (/x)\n\t2 g (/x)\n";
+const SAMPLE_B: &str = "b 2 2.0: cycles:\n\t3 h (/x)\n";
+
+#[test]
+fn test_leading_blank_lines() {
+    test(&format!("\n\n  \n{}\n{}", SAMPLE_A, SAMPLE_B), &[2, 1]);
+}
+
+#[test]
+fn test_consecutive_blank_lines() {
+    test(&format!("{}\n\n\t\n\n{}", SAMPLE_A, SAMPLE_B), &[2, 1]);
+}
+
+#[test]
+fn test_trailing_blank_lines() {
+    test(&format!("{}\n{}\n\n\n", SAMPLE_A, SAMPLE_B), &[2, 1]);
+}
+
+#[test]
+fn test_no_final_blank_line() {
+    // no blank line between samples, nor after the last one (which
+    // also has no final newline)
+    test(&format!("{}{}", SAMPLE_A, SAMPLE_B.trim_end()), &[2, 1]);
+}
+
+#[test]
+fn test_only_blank_lines() {
+    test("", &[]);
+    test("\n\n\n", &[]);
+    test("# comment\n\n# comment\n", &[]);
+}
+
+#[test]
+fn test_crlf() {
+    let data = format!("{}\n{}", SAMPLE_A, SAMPLE_B).replace('\n', "\r\n");
+    let mut stacks = vec![];
+    each_trace_from(&mut Cursor::new(data.as_bytes()), |args| {
+        stacks.push(args.stack);
+    }).unwrap();
+    assert_eq!(stacks, vec![vec!["g", "f"], vec!["h"]]);
+}
+
+#[test]
+fn test_frames_without_header() {
+    test(&format!("\t9 stray (/x)\n\n{}", SAMPLE_A), &[2]);
+}
+
+#[test]
+fn test_cpu_column() {
+    let data = "\
+rustc 18883/18890 [003] 2323302.5: cycles:
+\t1 f (/x)
+
+rustc 18883/18891 [012] 2323302.6: cycles:
+\t1 g (/x)
+
+rustc 18883 2323302.7: cycles:
+\t1 h (/x)
+";
+    let mut cpus = vec![];
+    each_trace_from(&mut Cursor::new(data.as_bytes()), |args| {
+        cpus.push((args.tid, args.cpu));
+    }).unwrap();
+    assert_eq!(cpus, vec![(Some(18890), Some(3)), (Some(18891), Some(12)), (Some(18883), None)]);
+}
+
+#[test]
+fn test_raw_round_trip() {
+    let sample = "\
+rustc 18883/18890 [003] 2323302.5: cycles:
+\t    7f82e6dee178 je_arena_salloc (/some/path.so)
+\t  7f82e6dee179  (/some/path.so)
+\t7f82e6dee17a <impl Foo for Bar>::baz [1234] (/x)
+";
+    let data = format!("# comment\n\n{}\n\n{}", sample, SAMPLE_A);
+    let mut raws = vec![];
+    each_trace_from(&mut Cursor::new(data.as_bytes()), |args| {
+        raws.push(args.raw());
+    }).unwrap();
+    assert_eq!(raws, vec![sample, SAMPLE_A]);
+}
+
+#[test]
+fn test_invalid_utf8() {
+    let mut data = b"a 1 1.0: cycles:\n\t1 f\xff\xfeo (/x)\n\t2 g (/x)\n\n".to_vec();
+    data.extend_from_slice(SAMPLE_B.as_bytes());
+    let mut stacks = vec![];
+    let stats = each_trace_from(&mut Cursor::new(&data[..]), |args| {
+        stacks.push(args.stack);
+    }).unwrap();
+    assert_eq!(stats.lossy_lines, 1);
+    assert_eq!(stacks, vec![vec!["g", "f\u{fffd}\u{fffd}o"], vec!["h"]]);
+}
+
+#[test]
+fn test_dash_marker() {
+    let data = "\
+rustc 18883 2323302.5: cycles:
+\t1 f (/x)
+---
+\t2 g (/x)
+\t    ---
+\t3 [unknown] ([unknown])
+
+";
+    let mut samples = vec![];
+    each_trace_from(&mut Cursor::new(data.as_bytes()), |args| {
+        samples.push((args.header.len(), args.stack));
+    }).unwrap();
+    assert_eq!(samples, vec![(4, vec!["[unknown]".to_string(), "g".to_string(), "f".to_string()])]);
+}
+
+#[test]
+fn test_parse_stats() {
+    let mut data = b"\t9 stray (/x)\n\na 1 1.0: cycles:\n\t1 f\xff (/x)\n---\n".to_vec();
+    data.extend_from_slice(b"\t2 (/x)\n\t3 [unknown] ([unknown])\n\t4 main (/x)\n");
+    let stats = each_trace_from(&mut Cursor::new(&data[..]), |_| ()).unwrap();
+    assert_eq!(stats, ParseStats {
+        lossy_lines: 1,
+        unknown_frames: 2,
+        marker_lines: 1,
+        stray_frames: 1,
+    });
+}
+
+#[test]
+fn test_break() {
+    let mut stacks = vec![];
+    let stats = each_trace_from(&mut Cursor::new(SAMPLE_A.as_bytes()), |args| {
+        stacks.push(args.stack);
+        ControlFlow::Break(())
+    }).unwrap();
+    assert_eq!(stats, ParseStats::default());
+    assert_eq!(stacks.len(), 1);
+}
+
+#[test]
+fn test_progress() {
+    let data = format!("{}\n{}", SAMPLE_A, SAMPLE_B);
+    let read = |progress: Option<&mut Vec<u8>>| {
+        let mut stacks = vec![];
+        let mut cursor = Cursor::new(data.as_bytes());
+        match progress {
+            Some(out) => each_trace_with_progress(&mut cursor, out, Some(data.len() as u64), |args| {
+                stacks.push(args.stack);
+            }),
+            None => each_trace_from(&mut cursor, |args| stacks.push(args.stack)),
+        }.unwrap();
+        stacks
+    };
+
+    // the indicator goes to its own writer and leaves the samples alone
+    let mut out = vec![];
+    assert_eq!(read(Some(&mut out)), read(None));
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with('\r') && out.ends_with("100% (2 samples)\n"), "{:?}", out);
+}
@@ -0,0 +1,73 @@
+//! Counts transitions: pairs of adjacent frames `(caller, callee)`,
+//! anywhere in the stack. This is the edge list of a `CallGraph`
+//! without the nodes or the dot output.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Result, Write};
+use util::percent;
+
+use super::AddFrames;
+
+#[cfg(test)]
+mod test;
+
+pub struct Transitions {
+    transitions: HashMap<(String, String), usize>,
+}
+
+impl Default for Transitions {
+    fn default() -> Transitions {
+        Transitions::new()
+    }
+}
+
+impl Transitions {
+    pub fn new() -> Transitions {
+        Transitions { transitions: HashMap::new() }
+    }
+
+    /// The `n` most frequent transitions as `(count, caller, callee)`,
+    /// most frequent first.
+    pub fn top(&self, n: usize) -> Vec<(usize, &str, &str)> {
+        let mut transitions: Vec<(usize, &str, &str)> =
+            self.transitions.iter()
+                            .map(|((caller, callee), &count)| (count, &caller[..], &callee[..]))
+                            .collect();
+
+        transitions.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+        transitions.truncate(n);
+        transitions
+    }
+
+    pub fn dump(&self, out: &mut dyn Write, total: usize, threshold: usize) -> Result<()> {
+        for (count, caller, callee) in self.top(threshold).into_iter().rev() {
+            writeln!(out, "{:3}% {} -> {}", percent(count, total), caller, callee)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddFrames for Transitions {
+    /// Like the edges of a `CallGraph`, a transition is counted at most
+    /// once per sample, so a recursive sample doesn't count for more.
+    fn add_frames<I>(&mut self, frames: I)
+        where I: Iterator<Item=String>
+    {
+        let mut seen = HashSet::new();
+        let mut caller: Option<String> = None;
+        for callee in frames {
+            if let Some(caller) = caller {
+                let transition = (caller, callee.clone());
+                if !seen.contains(&transition) {
+                    *self.transitions.entry(transition.clone()).or_insert(0) += 1;
+                    seen.insert(transition);
+                }
+            }
+            caller = Some(callee);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.transitions.clear();
+    }
+}
@@ -0,0 +1,46 @@
+use super::*;
+
+fn add(transitions: &mut Transitions, frames: &[&str]) {
+    transitions.add_frames(frames.iter().map(|s| s.to_string()));
+}
+
+#[test]
+fn adjacent_pairs() {
+    let mut transitions = Transitions::new();
+    add(&mut transitions, &["main", "parse", "vec::push", "malloc"]);
+    add(&mut transitions, &["main", "parse", "memcpy"]);
+    add(&mut transitions, &["main", "vec::push", "malloc"]);
+    add(&mut transitions, &["main"]);
+    add(&mut transitions, &[]);
+
+    assert_eq!(transitions.top(10), vec![
+        (2, "main", "parse"),
+        (2, "vec::push", "malloc"),
+        (1, "main", "vec::push"),
+        (1, "parse", "memcpy"),
+        (1, "parse", "vec::push"),
+    ]);
+
+    let mut out = vec![];
+    transitions.dump(&mut out, 4, 2).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               " 50% vec::push -> malloc\n 50% main -> parse\n");
+}
+
+#[test]
+fn once_per_sample() {
+    let mut transitions = Transitions::new();
+    add(&mut transitions, &["main", "f", "g", "f", "g"]);
+    assert_eq!(transitions.top(10), vec![(1, "f", "g"), (1, "g", "f"), (1, "main", "f")]);
+}
+
+#[test]
+fn clear_is_like_new() {
+    let mut transitions = Transitions::new();
+    add(&mut transitions, &["main", "a", "b"]);
+    transitions.clear();
+    assert!(transitions.top(10).is_empty());
+
+    add(&mut transitions, &["main", "c"]);
+    assert_eq!(transitions.top(10), vec![(1, "main", "c")]);
+}
@@ -28,12 +28,33 @@ We want to arrange into a tree:
 
 */
 
-use util::percent;
+use color;
+use std::io::{Result, Write};
+use util::{percent, truncate_name};
 
 use super::AddFrames;
 
+#[cfg(test)]
+mod test;
+
+/// Label of the synthetic node that collects pruned samples (see
+/// `Tree::set_other_bucket`).
+pub const OTHER_LABEL: &str = "(other)";
+
+/// Labels of the two sides of a butterfly (see `Tree::add_butterfly`).
+pub const CALLERS_LABEL: &str = "[callers]";
+pub const CALLEES_LABEL: &str = "[callees]";
+
 pub struct Tree {
     root_node: TreeNode,
+    other_bucket: bool,
+    max_name_width: Option<usize>,
+}
+
+/// How `TreeNode::dump` writes each line.
+struct Style {
+    max_name_width: Option<usize>,
+    color: bool,
 }
 
 pub struct TreeNode {
@@ -50,43 +71,162 @@ pub struct TreeNode {
     children: Vec<TreeNode>,
 }
 
+impl Default for Tree {
+    fn default() -> Tree {
+        Tree::new()
+    }
+}
+
 impl Tree {
     pub fn new() -> Self {
         Tree {
             root_node: TreeNode::new("<root>".to_string()),
+            other_bucket: false,
+            max_name_width: None,
         }
     }
 
+    /// If set, `rollup` moves the samples of the nodes it prunes into
+    /// a single top-level `(other)` node, shown last, rather than into
+    /// the self time of their parents. This shows how much time the
+    /// pruning hid.
+    pub fn set_other_bucket(&mut self, other_bucket: bool) {
+        self.other_bucket = other_bucket;
+    }
+
+    /// Makes `dump` shorten labels longer than `max_name_width`
+    /// characters, keeping their end (see `util::truncate_name`).
+    pub fn set_max_name_width(&mut self, max_name_width: Option<usize>) {
+        self.max_name_width = max_name_width;
+    }
+
     pub fn sort(&mut self) {
         self.root_node.sort();
     }
 
+    /// Total number of samples added to the tree.
+    pub fn root_total(&self) -> usize {
+        self.root_node.hits_total
+    }
+
+    /// Finds the node reached by following `path` from the root, where
+    /// each element is the label of a child of the previous node.
+    /// An empty path yields the (synthetic) root node.
+    pub fn find(&self, path: &[&str]) -> Option<&TreeNode> {
+        let mut node = &self.root_node;
+        for label in path {
+            node = node.children.iter().find(|c| c.label == *label)?;
+        }
+        Some(node)
+    }
+
+    /// Folds every node that `dump` would not show (because it lies
+    /// beyond `max_depth` or is below `min_percent`) into the self
+    /// time of its parent. `max_depth` has the same meaning as in
+    /// `dump`. With `set_other_bucket`, the pruned samples go to the
+    /// `(other)` node instead, and so do top-level nodes below
+    /// `min_percent`.
     pub fn rollup(&mut self, total_samples: usize, max_depth: usize, min_percent: usize) {
+        let mut other = 0;
         for child in &mut self.root_node.children {
-            child.rollup(
+            let bucket = if self.other_bucket { Some(&mut other) } else { None };
+            let kept = child.rollup(
                 0,
                 total_samples,
                 max_depth,
                 min_percent,
+                bucket,
             );
+            if !kept && self.other_bucket {
+                other += child.hits_total;
+                child.hits_total = 0;
+            }
+        }
+
+        if self.other_bucket {
+            self.root_node.children.retain(|c| c.hits_total != 0);
         }
 
         self.sort();
+
+        if other > 0 {
+            let mut node = TreeNode::new(OTHER_LABEL.to_string());
+            node.hits_total = other;
+            node.hits_self = other;
+            self.root_node.children.push(node);
+        }
     }
 
     pub fn only_leaves(&mut self) {
         self.root_node.only_leaves();
     }
 
-    pub fn dump(&self, total_samples: usize, max_depth: usize, min_percent: usize) {
+    /// Adds one sample to a butterfly: a top-level node `label` (for
+    /// the match) whose `[callers]` child holds an inverted tree of
+    /// `callers` (innermost first) and whose `[callees]` child holds
+    /// a tree of `callees`. The sample counts once in `label` and once
+    /// on each side, so each side on its own adds up to the total of
+    /// `label`: a caller at 30% was on the stack, above the match, in
+    /// 30% of the samples, just like in `--tree-callers`. With a
+    /// `weight` other than 1, "once" is `weight` times (see
+    /// `add_weighted_frames`).
+    pub fn add_butterfly<I, J>(&mut self, label: String, callers: I, callees: J, weight: usize)
+        where I: Iterator<Item=String>, J: Iterator<Item=String>
+    {
+        self.root_node.hits_total += weight;
+        let node = self.root_node.child(label);
+        node.hits_total += weight;
+        node.child(CALLERS_LABEL.to_string()).add_frames(callers, weight);
+        node.child(CALLEES_LABEL.to_string()).add_frames(callees, weight);
+    }
+
+    /// Like `add_frames`, but the sample counts `weight` times rather
+    /// than once, so that the totals and self times of the nodes are
+    /// sums of weights (such as the cost of each sample) rather than
+    /// numbers of samples.
+    pub fn add_weighted_frames<I>(&mut self, frames: I, weight: usize)
+        where I: Iterator<Item=String>
+    {
+        self.root_node.add_frames(frames, weight);
+    }
+
+    /// Prints the tree. Depth counts from 0: the top-level nodes are at
+    /// depth 0, their children at depth 1, and so on. Nodes at depths up
+    /// to and including `max_depth` are shown; a node whose children were
+    /// cut off is marked with `[...]`.
+    pub fn dump(
+        &self,
+        out: &mut dyn Write,
+        total_samples: usize,
+        max_depth: usize,
+        min_percent: usize,
+        color: bool,
+    ) -> Result<()> {
         for child in &self.root_node.children {
             child.dump(
+                out,
                 0,
                 total_samples,
                 max_depth,
                 min_percent,
-            );
+                &Style { max_name_width: self.max_name_width, color },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The hottest path from the top of the tree down to a node
+    /// without children: at each level, the child with the highest
+    /// total (the first one added, on a tie). Each element is the
+    /// label of a node and its total.
+    pub fn hottest_path(&self) -> Vec<(String, usize)> {
+        let mut path = vec![];
+        let mut node = &self.root_node;
+        while let Some(child) = node.children.iter().rev().max_by_key(|c| c.hits_total) {
+            path.push((child.label.clone(), child.hits_total));
+            node = child;
         }
+        path
     }
 
     pub fn for_each_leaf<F>(&self, mut f: F)
@@ -97,17 +237,44 @@ impl Tree {
 }
 
 impl TreeNode {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn hits_total(&self) -> usize {
+        self.hits_total
+    }
+
+    pub fn hits_self(&self) -> usize {
+        self.hits_self
+    }
+
+    pub fn children(&self) -> &[TreeNode] {
+        &self.children
+    }
+
     fn new(label: String) -> TreeNode {
         TreeNode {
-            label: label,
+            label,
             hits_total: 0,
             hits_self: 0,
             children: vec![],
         }
     }
 
+    /// The child labeled `label`, created (with no hits) if missing.
+    fn child(&mut self, label: String) -> &mut TreeNode {
+        match self.children.iter().position(|c| c.label == label) {
+            Some(i) => &mut self.children[i],
+            None => {
+                self.children.push(TreeNode::new(label));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+
     fn sort(&mut self) {
-        self.children.sort_by_key(|c| ::std::usize::MAX - c.hits_total);
+        self.children.sort_by_key(|c| usize::MAX - c.hits_total);
         for c in &mut self.children {
             c.sort();
         }
@@ -134,28 +301,42 @@ impl TreeNode {
         self.children = new_children;
     }
 
+    /// Returns false if this node should be pruned. Pruned children
+    /// are folded into our self time or, if `other` is given, removed
+    /// from our total and added to `other`.
     fn rollup(
         &mut self,
         parents: usize,
         total_samples: usize,
         max_depth: usize,
         min_percent: usize,
+        mut other: Option<&mut usize>,
     ) -> bool {
         let total_percent = percent(self.hits_total, total_samples);
         if (total_percent as usize) < min_percent {
             return false;
         }
 
-        if parents > max_depth {
+        if beyond_max_depth(parents, max_depth) {
             return false;
         }
 
         for c in &mut self.children {
-            if !c.rollup(parents + 1, total_samples, max_depth, min_percent) {
-                self.hits_self += c.hits_total;
+            let before = c.hits_total;
+            if !c.rollup(parents + 1, total_samples, max_depth, min_percent, other.as_deref_mut()) {
+                match other {
+                    Some(ref mut other) => {
+                        **other += c.hits_total;
+                        self.hits_total -= c.hits_total;
+                    }
+                    None => self.hits_self += c.hits_total,
+                }
                 c.hits_total = 0;
             } else {
-                assert!(c.hits_total > 0);
+                // Whatever went to `other` from below is gone from `c`,
+                // and so from us too.
+                self.hits_total -= before - c.hits_total;
+                assert!(c.hits_total > 0 || other.is_some());
             }
         }
 
@@ -165,33 +346,42 @@ impl TreeNode {
 
     fn dump(
         &self,
+        out: &mut dyn Write,
         parents: usize,
         total_samples: usize,
         max_depth: usize,
         min_percent: usize,
-    ) {
+        style: &Style,
+    ) -> Result<()> {
         let self_percent = percent(self.hits_self, total_samples);
         let total_percent = percent(self.hits_total, total_samples);
 
         if (total_percent as usize) < min_percent {
-            return;
+            return Ok(());
         }
 
         for _ in 0 .. parents {
-            print!(": ");
+            write!(out, ": ")?;
         }
 
-        print!("| {} ({}% total, {}% self)", self.label, total_percent, self_percent);
-
-        if !self.children.is_empty() && (parents + 1 > max_depth) {
-            println!(" [...]");
-            return;
+        let total_text = format!("{}% total", total_percent);
+        let label = match style.max_name_width {
+            Some(width) => truncate_name(&self.label, width),
+            None => self.label.clone(),
+        };
+        write!(out, "| {} ({}, {}% self)", label,
+               color::percent(style.color, total_percent, &total_text), self_percent)?;
+
+        if !self.children.is_empty() && beyond_max_depth(parents + 1, max_depth) {
+            writeln!(out, " [...]")?;
+            return Ok(());
         }
 
-        println!();
+        writeln!(out)?;
         for c in &self.children {
-            c.dump(parents + 1, total_samples, max_depth, min_percent);
+            c.dump(out, parents + 1, total_samples, max_depth, min_percent, style)?;
         }
+        Ok(())
     }
 
     fn for_each_leaf<F>(&self, f: &mut F)
@@ -207,32 +397,43 @@ impl TreeNode {
         }
     }
 
-    fn add_frames<I>(&mut self, mut frames: I)
+    fn add_frames<I>(&mut self, mut frames: I, weight: usize)
         where I: Iterator<Item=String>
     {
-        self.hits_total += 1;
+        self.hits_total += weight;
 
         if let Some(child_label) = frames.next() {
             for child_node in &mut self.children {
                 if child_node.label == child_label {
-                    return child_node.add_frames(frames);
+                    return child_node.add_frames(frames, weight);
                 }
             }
 
             self.children.push(TreeNode::new(child_label.to_string()));
             self.children.last_mut()
                          .unwrap()
-                         .add_frames(frames);
+                         .add_frames(frames, weight);
         } else {
-            self.hits_self += 1;
+            self.hits_self += weight;
         }
     }
 }
 
+/// True if a node at `depth` is too deep to be shown (see `Tree::dump`).
+fn beyond_max_depth(depth: usize, max_depth: usize) -> bool {
+    depth > max_depth
+}
+
 impl AddFrames for Tree {
     fn add_frames<I>(&mut self, frames: I)
         where I: Iterator<Item=String>
     {
-        self.root_node.add_frames(frames);
+        self.root_node.add_frames(frames, 1);
+    }
+
+    fn clear(&mut self) {
+        self.root_node.children.clear();
+        self.root_node.hits_total = 0;
+        self.root_node.hits_self = 0;
     }
 }
@@ -0,0 +1,259 @@
+use super::*;
+
+fn add(tree: &mut Tree, frames: &[&str]) {
+    tree.add_frames(frames.iter().map(|s| s.to_string()));
+}
+
+/// The example from the comment at the top of this module.
+fn example() -> Tree {
+    let mut tree = Tree::new();
+    add(&mut tree, &["A", "B1", "C"]);
+    add(&mut tree, &["A", "B2", "C"]);
+    add(&mut tree, &["A", "B1", "D"]);
+    tree
+}
+
+#[test]
+fn root_total() {
+    assert_eq!(example().root_total(), 3);
+    assert_eq!(Tree::new().root_total(), 0);
+}
+
+#[test]
+fn find_path() {
+    let tree = example();
+
+    let a = tree.find(&["A"]).unwrap();
+    assert_eq!(a.label(), "A");
+    assert_eq!((a.hits_total(), a.hits_self()), (3, 0));
+    assert_eq!(a.children().len(), 2);
+
+    let b1 = tree.find(&["A", "B1"]).unwrap();
+    assert_eq!((b1.hits_total(), b1.hits_self()), (2, 0));
+
+    let c = tree.find(&["A", "B1", "C"]).unwrap();
+    assert_eq!((c.hits_total(), c.hits_self()), (1, 1));
+    assert!(c.children().is_empty());
+
+    assert!(tree.find(&["A", "B2", "D"]).is_none());
+    assert!(tree.find(&["B1"]).is_none());
+    assert_eq!(tree.find(&[]).unwrap().hits_total(), 3);
+}
+
+fn dump(tree: &Tree, max_depth: usize, color: bool) -> String {
+    let mut out = vec![];
+    tree.dump(&mut out, tree.root_total(), max_depth, 0, color).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn dump_without_color() {
+    let mut tree = example();
+    tree.sort();
+    let out = dump(&tree, usize::MAX, false);
+    assert!(!out.contains('\x1b'), "{:?}", out);
+    assert!(out.starts_with("| A (100% total, 0% self)\n"), "{:?}", out);
+}
+
+#[test]
+fn dump_max_name_width() {
+    let mut tree = Tree::new();
+    add(&mut tree, &["main", "core::ptr::drop_in_place"]);
+    tree.set_max_name_width(Some(14));
+    assert_eq!(dump(&tree, usize::MAX, false),
+               "| main (100% total, 0% self)\n: | …drop_in_place (100% total, 100% self)\n");
+}
+
+#[test]
+fn dump_with_color() {
+    let out = dump(&example(), usize::MAX, true);
+    assert!(out.starts_with("| A (\x1b[31m100% total\x1b[0m, 0% self)\n"), "{:?}", out);
+}
+
+#[test]
+fn dump_max_depth() {
+    let mut tree = example();
+    tree.sort();
+
+    assert_eq!(dump(&tree, 0, false), "\
+| A (100% total, 0% self) [...]
+");
+
+    assert_eq!(dump(&tree, 1, false), "\
+| A (100% total, 0% self)
+: | B1 (66% total, 0% self) [...]
+: | B2 (33% total, 0% self) [...]
+");
+
+    assert_eq!(dump(&tree, 2, false), "\
+| A (100% total, 0% self)
+: | B1 (66% total, 0% self)
+: : | C (33% total, 33% self)
+: : | D (33% total, 33% self)
+: | B2 (33% total, 0% self)
+: : | C (33% total, 33% self)
+");
+}
+
+#[test]
+fn rollup_max_depth() {
+    // `rollup` must keep exactly the nodes that `dump` shows, folding
+    // the rest into their parents' self time.
+    let rolled = |max_depth| {
+        let mut tree = example();
+        tree.rollup(3, max_depth, 0);
+        dump(&tree, max_depth, false)
+    };
+
+    assert_eq!(rolled(0), "\
+| A (100% total, 100% self)
+");
+
+    assert_eq!(rolled(1), "\
+| A (100% total, 0% self)
+: | B1 (66% total, 66% self)
+: | B2 (33% total, 33% self)
+");
+
+    let mut tree = example();
+    tree.sort();
+    assert_eq!(rolled(2), dump(&tree, 2, false));
+}
+
+#[test]
+fn rollup_other_bucket() {
+    // Two more samples below `A`; at 25%, `B2` and `B1 -> D` (1 of 5
+    // samples each) are pruned, but the others are not.
+    let tree = || {
+        let mut tree = example();
+        add(&mut tree, &["A", "B1", "C"]);
+        add(&mut tree, &["A", "B1"]);
+        tree
+    };
+
+    let mut folded = tree();
+    folded.rollup(5, usize::MAX, 25);
+    assert_eq!(folded.find(&["A"]).unwrap().hits_self(), 1);
+    assert_eq!(folded.find(&["A", "B1"]).unwrap().hits_self(), 2);
+    assert!(folded.find(&[OTHER_LABEL]).is_none());
+
+    let mut bucketed = tree();
+    bucketed.set_other_bucket(true);
+    bucketed.rollup(5, usize::MAX, 25);
+    let a = bucketed.find(&["A"]).unwrap();
+    assert_eq!((a.hits_total(), a.hits_self()), (3, 0));
+    assert_eq!(bucketed.find(&["A", "B1"]).unwrap().hits_total(), 3);
+    assert!(bucketed.find(&["A", "B2"]).is_none());
+
+    // `(other)` holds exactly the pruned mass, and is listed last.
+    let other = bucketed.find(&[OTHER_LABEL]).unwrap();
+    assert_eq!(other.hits_total(), 2);
+    assert_eq!(bucketed.find(&[]).unwrap().children().last().unwrap().label(), OTHER_LABEL);
+    assert_eq!(a.hits_total() + other.hits_total(), bucketed.root_total());
+}
+
+#[test]
+fn rollup_other_bucket_top_level() {
+    let mut tree = Tree::new();
+    tree.set_other_bucket(true);
+    for _ in 0..9 {
+        add(&mut tree, &["A", "B"]);
+    }
+    add(&mut tree, &["Z"]);
+    tree.rollup(10, usize::MAX, 20);
+
+    assert_eq!(tree.find(&["A", "B"]).unwrap().hits_self(), 9);
+    assert!(tree.find(&["Z"]).is_none());
+    assert_eq!(tree.find(&[OTHER_LABEL]).unwrap().hits_total(), 1);
+}
+
+#[test]
+fn weighted() {
+    let mut tree = Tree::new();
+    let frames = |frames: &[&str]| frames.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    tree.add_weighted_frames(frames(&["A", "B1", "C"]).into_iter(), 100);
+    tree.add_weighted_frames(frames(&["A", "B2", "C"]).into_iter(), 5);
+    tree.add_weighted_frames(frames(&["A", "B1"]).into_iter(), 20);
+    add(&mut tree, &["A", "B2"]);
+
+    assert_eq!(tree.root_total(), 126);
+    let hits = |path: &[&str]| {
+        let node = tree.find(path).unwrap();
+        (node.hits_total(), node.hits_self())
+    };
+    assert_eq!(hits(&["A"]), (126, 0));
+    assert_eq!(hits(&["A", "B1"]), (120, 20));
+    assert_eq!(hits(&["A", "B1", "C"]), (100, 100));
+    assert_eq!(hits(&["A", "B2"]), (6, 1));
+
+    // percentages are of the summed weights
+    let mut out = vec![];
+    tree.sort();
+    tree.dump(&mut out, 126, usize::MAX, 0, false).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("| A (100% total, 0% self)\n: | B1 (95% total, 15% self)\n"), "{}", out);
+}
+
+#[test]
+fn hottest_path() {
+    let mut tree = Tree::new();
+    for _ in 0..3 {
+        add(&mut tree, &["main", "run", "parse"]);
+    }
+    add(&mut tree, &["main", "run", "typeck"]);
+    add(&mut tree, &["main", "run"]);
+    add(&mut tree, &["main", "exit"]);
+    add(&mut tree, &["start"]);
+    assert_eq!(tree.hottest_path(), [
+        ("main".to_string(), 6),
+        ("run".to_string(), 5),
+        ("parse".to_string(), 3),
+    ]);
+
+    // ties go to the child added first
+    add(&mut tree, &["main", "run", "typeck"]);
+    add(&mut tree, &["main", "run", "typeck"]);
+    assert_eq!(tree.hottest_path()[2], ("parse".to_string(), 3));
+
+    assert!(Tree::new().hottest_path().is_empty());
+}
+
+#[test]
+fn butterfly() {
+    let mut tree = Tree::new();
+    let add = |tree: &mut Tree, callers: &[&str], callees: &[&str]| {
+        tree.add_butterfly("M".to_string(),
+                           callers.iter().map(|s| s.to_string()),
+                           callees.iter().map(|s| s.to_string()),
+                           1);
+    };
+    add(&mut tree, &["B", "A"], &["X"]);
+    add(&mut tree, &["C", "A"], &["X", "Y"]);
+    add(&mut tree, &["B", "A"], &[]);
+
+    assert_eq!(tree.root_total(), 3);
+    let hits = |path: &[&str]| {
+        let node = tree.find(path).unwrap();
+        (node.hits_total(), node.hits_self())
+    };
+    assert_eq!(hits(&["M"]), (3, 0));
+    assert_eq!(hits(&["M", CALLERS_LABEL]), (3, 0));
+    assert_eq!(hits(&["M", CALLERS_LABEL, "B"]), (2, 0));
+    assert_eq!(hits(&["M", CALLERS_LABEL, "B", "A"]), (2, 2));
+    assert_eq!(hits(&["M", CALLEES_LABEL]), (3, 1));
+    assert_eq!(hits(&["M", CALLEES_LABEL, "X"]), (2, 1));
+    assert_eq!(hits(&["M", CALLEES_LABEL, "X", "Y"]), (1, 1));
+}
+
+#[test]
+fn clear_is_like_new() {
+    let mut tree = example();
+    tree.clear();
+    assert_eq!(tree.root_total(), 0);
+    assert!(tree.find(&["A"]).is_none());
+
+    let mut fresh = Tree::new();
+    add(&mut tree, &["A", "B2", "C"]);
+    add(&mut fresh, &["A", "B2", "C"]);
+    assert_eq!(dump(&tree, usize::MAX, false), dump(&fresh, usize::MAX, false));
+}
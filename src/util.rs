@@ -1,6 +1,44 @@
+use std::env;
+use std::io::{self, IsTerminal};
+
 pub fn percent(num: usize, denom: usize) -> u32 {
     let num: f64 = num as f64;
     let denom: f64 = denom as f64;
     let percent: f64 = num * 100.0 / denom;
     percent as u32
 }
+
+/// Estimated time covered by `samples` samples taken at `frequency`
+/// samples per second.
+pub fn seconds(samples: usize, frequency: u64) -> f64 {
+    samples as f64 / frequency as f64
+}
+
+/// Like `seconds`, formatted for display, e.g. `1.25s`.
+pub fn seconds_str(samples: usize, frequency: u64) -> String {
+    format!("{:.2}s", seconds(samples, frequency))
+}
+
+/// Shortens `name` to at most `width` characters by replacing its
+/// start with `…`. The end is kept, since that is where a Rust path
+/// has the name of the function itself.
+pub fn truncate_name(name: &str, width: usize) -> String {
+    let len = name.chars().count();
+    if len <= width {
+        return name.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+    let tail: String = name.chars().skip(len - (width - 1)).collect();
+    format!("…{}", tail)
+}
+
+/// The width of the terminal, if stdout is one and `COLUMNS` says how
+/// wide it is.
+pub fn terminal_width() -> Option<usize> {
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    env::var("COLUMNS").ok()?.trim().parse().ok().filter(|&width| width > 0)
+}